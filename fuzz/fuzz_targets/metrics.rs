@@ -0,0 +1,30 @@
+//! Compares two arbitrary strings with every algorithm and computes their alignment.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use word_similarity::metrics::levenshtein_alignment;
+use word_similarity::SimilarityAlgorithm;
+
+fuzz_target!(|data: &[u8]| {
+    // The two words are separated by the first zero byte
+    let split = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+    let a = String::from_utf8_lossy(&data[..split]);
+    let b = String::from_utf8_lossy(data.get(split + 1..).unwrap_or_default());
+
+    for algorithm in SimilarityAlgorithm::ALL {
+        let similarity = algorithm.similarity(&a, &b);
+        // Allow for the float error of the cosine similarity
+        assert!(
+            (-1e-9..=1.0 + 1e-9).contains(&similarity),
+            "{} similarity of {:?} and {:?} is {}",
+            algorithm,
+            a,
+            b,
+            similarity
+        );
+    }
+
+    let operations = levenshtein_alignment(&a, &b);
+    assert!(operations.len() >= a.chars().count().max(b.chars().count()));
+});
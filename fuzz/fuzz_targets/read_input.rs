@@ -0,0 +1,51 @@
+//! Feeds arbitrary bytes through the input parser, every preprocessing step and the
+//! similarity matrix of every algorithm.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use word_similarity::preprocess::Preprocessing;
+use word_similarity::{
+    calculate_similarity_matrix, collect_pairs, read_input, ComparisonFilter, InputOptions, Scorer,
+    SimilarityAlgorithm,
+};
+
+/// Number of words compared, to keep the quadratic matrix fast.
+const MAX_COMPARED_WORDS: usize = 32;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte selects the preprocessing steps, the rest is the input file
+    let (flags, input) = match data.split_first() {
+        Some((flags, input)) => (*flags, input),
+        None => return,
+    };
+    let preprocessing = Preprocessing {
+        case_sensitive: flags & 1 != 0,
+        ascii_fold: flags & 2 != 0,
+        strip_accents: flags & 4 != 0,
+        strip_punctuation: flags & 8 != 0,
+        ignore_numbers: flags & 16 != 0,
+        collapse_whitespace: flags & 32 != 0,
+        reverse: flags & 64 != 0,
+        ..Preprocessing::default()
+    };
+    let options = InputOptions {
+        preprocessing,
+        allow_single_word: flags & 128 != 0,
+        ..InputOptions::default()
+    };
+
+    // Invalid input must be reported as an error, never as a panic
+    let words = match read_input(input, &options) {
+        Ok((words, _, _)) => words,
+        Err(_) => return,
+    };
+    let words = &words[..words.len().min(MAX_COMPARED_WORDS)];
+
+    for algorithm in SimilarityAlgorithm::ALL {
+        let matrix = calculate_similarity_matrix(words, &Scorer::new(algorithm), &ComparisonFilter::default(), None);
+        for pair in collect_pairs(&matrix, &(0.0..=f64::INFINITY)) {
+            assert!(pair.index_a() < pair.index_b() && pair.index_b() < words.len());
+        }
+    }
+});
@@ -0,0 +1,282 @@
+//! An on-disk cache of computed similarities, for repeated runs on overlapping input.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::metrics::{IdfWeights, SubstitutionCosts};
+use crate::{fnv1a, fnv1a_extend, Scorer};
+
+/// The first bytes of a cache file, followed by the version of the format.
+const MAGIC: &[u8; 8] = b"WSCACHE1";
+
+/// Size of one record: the key followed by the similarity, both little-endian.
+const RECORD_SIZE: usize = 16;
+
+/// A hasher for keys that already are hashes, which passes them through unchanged.
+#[derive(Default)]
+struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // Only used for keys other than u64, which the cache does not have
+        for byte in bytes {
+            self.0 = (self.0 << 8) | u64::from(*byte);
+        }
+    }
+
+    fn write_u64(&mut self, key: u64) {
+        self.0 = key;
+    }
+}
+
+type KeyMap = HashMap<u64, f64, BuildHasherDefault<KeyHasher>>;
+
+/// Similarities keyed by the hash of the scorer settings and the two processed words.
+///
+/// The file starts with `MAGIC` and holds one record per similarity. New similarities are
+/// collected while computing and appended to the file by `save`, so one file can be shared by
+/// runs with different algorithms. The keys are 64-bit hashes, so a collision returns the
+/// similarity of another pair; with a million pairs the chance of any collision is about one
+/// in 36 million.
+///
+/// Looking up a similarity is slower than computing the Levenshtein distance of two short
+/// words, so the cache pays off for long words and the slower algorithms, like cosine.
+#[derive(Debug)]
+pub struct SimilarityCache {
+    /// Hash of the scorer settings, so other algorithms and settings get other keys.
+    namespace: u64,
+    /// Whether the words of a pair are hashed in order, for scorers that are not symmetric.
+    ordered: bool,
+    known: KeyMap,
+    added: Mutex<Vec<(u64, f64)>>,
+    hits: AtomicU64,
+}
+
+impl SimilarityCache {
+    /// Load the cache file for the scorer, or start an empty cache if it does not exist yet.
+    ///
+    /// The key covers the algorithm, `max_edits`, `length_penalty`, the record fields,
+    /// `clamp_identical` and the contents of the substitution costs and IDF weights, so
+    /// editing those files does not serve stale similarities.
+    pub fn open(path: &Path, scorer: &Scorer) -> io::Result<Self> {
+        let known = match fs::read(path) {
+            Ok(bytes) => parse_records(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => KeyMap::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(SimilarityCache::with_entries(scorer, known))
+    }
+
+    fn with_entries(scorer: &Scorer, known: KeyMap) -> Self {
+        let settings = format!(
+            "{}|{:?}|{:?}|{:?}|{:?}|{:?}|{}",
+            scorer.algorithm,
+            scorer.max_edits,
+            scorer.length_penalty,
+            scorer.substitution_costs.as_ref().map(SubstitutionCosts::fingerprint),
+            scorer.idf_weights.as_ref().map(IdfWeights::fingerprint),
+            scorer.record_fields,
+            scorer.clamp_identical
+        );
+        SimilarityCache {
+            namespace: fnv1a(settings.as_bytes()),
+            // Substitution costs may differ per direction, all other settings are symmetric
+            ordered: scorer.substitution_costs.is_some(),
+            known,
+            added: Mutex::new(Vec::new()),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// The key of the similarity of two processed words.
+    pub fn key(&self, a: &str, b: &str) -> u64 {
+        let (a, b) = if !self.ordered && b < a { (b, a) } else { (a, b) };
+        // 0xff never occurs in UTF-8, so it separates the words unambiguously
+        let hash = fnv1a_extend(self.namespace, a.as_bytes());
+        let hash = fnv1a_extend(hash, &[0xff]);
+        fnv1a_extend(hash, b.as_bytes())
+    }
+
+    /// Look up a similarity loaded from the cache file, counting it as a hit if found.
+    pub fn get(&self, key: u64) -> Option<f64> {
+        let similarity = self.known.get(&key).copied();
+        if similarity.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        similarity
+    }
+
+    /// Record the similarities computed for a row, to be saved at the end of the run.
+    pub fn add(&self, entries: Vec<(u64, f64)>) {
+        if !entries.is_empty() {
+            self.added.lock().unwrap().extend(entries);
+        }
+    }
+
+    /// Number of similarities found in the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of similarities computed and not yet saved.
+    pub fn added(&self) -> usize {
+        self.added.lock().unwrap().len()
+    }
+
+    /// Append the computed similarities to the cache file, creating it if needed.
+    ///
+    /// A record cut off by an interrupted save is removed first, so the new records start at
+    /// a record boundary.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut added = self.added.lock().unwrap();
+        // Both orders of a pair share a key unless the scorer is ordered
+        added.sort_unstable_by_key(|(key, _)| *key);
+        added.dedup_by_key(|(key, _)| *key);
+
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false).open(path)?;
+        let len = file.metadata()?.len();
+        let records_end = complete_records_end(len)?;
+        if records_end != len {
+            file.set_len(records_end)?;
+        }
+        file.seek(SeekFrom::Start(records_end))?;
+        let mut writer = BufWriter::new(file);
+        if records_end == 0 {
+            writer.write_all(MAGIC)?;
+        }
+        for (key, similarity) in added.iter() {
+            writer.write_all(&key.to_le_bytes())?;
+            writer.write_all(&similarity.to_le_bytes())?;
+        }
+        // Flush explicitly, since dropping the writer would swallow any error
+        writer.flush()?;
+        added.clear();
+        Ok(())
+    }
+}
+
+/// The length of the header and the complete records of a cache file of `len` bytes, which
+/// is 0 for an empty file.
+fn complete_records_end(len: u64) -> io::Result<u64> {
+    let header = MAGIC.len() as u64;
+    match len {
+        0 => Ok(0),
+        len if len < header => Err(invalid_cache()),
+        len => Ok(len - (len - header) % RECORD_SIZE as u64),
+    }
+}
+
+/// Parse the records of a cache file after checking its header.
+fn parse_records(bytes: &[u8]) -> io::Result<KeyMap> {
+    let records = bytes.strip_prefix(MAGIC).ok_or_else(invalid_cache)?;
+
+    // A record cut off by an interrupted save is ignored by chunks_exact, and cut off by the
+    // next save
+    let records = records.chunks_exact(RECORD_SIZE);
+    let mut known = KeyMap::with_capacity_and_hasher(records.len(), Default::default());
+    for record in records {
+        let (key, similarity) = record.split_at(8);
+        let key = u64::from_le_bytes(key.try_into().unwrap());
+        let similarity = f64::from_le_bytes(similarity.try_into().unwrap());
+        known.insert(key, similarity);
+    }
+    Ok(known)
+}
+
+fn invalid_cache() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Not a similarity cache file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimilarityAlgorithm;
+
+    #[test]
+    fn keys_depend_on_words_and_scorer() {
+        let levenshtein = SimilarityCache::with_entries(&Scorer::default(), KeyMap::default());
+        let jaro = SimilarityCache::with_entries(&Scorer::new(SimilarityAlgorithm::Jaro), KeyMap::default());
+        assert_ne!(levenshtein.key("ab", "c"), levenshtein.key("a", "bc"));
+        assert_eq!(levenshtein.key("a", "b"), levenshtein.key("b", "a"));
+        assert_ne!(levenshtein.key("a", "b"), jaro.key("a", "b"));
+    }
+
+    #[test]
+    fn keys_depend_on_the_contents_of_the_tables() {
+        let key = |costs: &str, idf: &str| {
+            let scorer = Scorer {
+                substitution_costs: Some(SubstitutionCosts::parse(costs).unwrap()),
+                idf_weights: Some(IdfWeights::parse(idf).unwrap()),
+                ..Scorer::default()
+            };
+            SimilarityCache::with_entries(&scorer, KeyMap::default()).key("a", "b")
+        };
+        let original = key("0 o 0.2\n1 l 0.3\n", "10\ner\t5\n");
+        assert_eq!(key("# comment\n1 l 0.3\n0 o 0.2\n", "10\ner\t5\n"), original);
+        assert_ne!(key("0 o 0.25\n1 l 0.3\n", "10\ner\t5\n"), original);
+        assert_ne!(key("0 o 0.2\n1 l 0.3\n", "10\ner\t6\n"), original);
+        assert_ne!(key("0 o 0.2\n1 l 0.3\n", "20\ner\t5\n"), original);
+    }
+
+    #[test]
+    fn saves_and_loads_similarities() {
+        let path = std::env::temp_dir().join(format!("word_similarity_cache_{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let scorer = Scorer::default();
+
+        let cache = SimilarityCache::open(&path, &scorer).unwrap();
+        let key = cache.key("hello", "hallo");
+        assert_eq!(cache.get(key), None);
+        cache.add(vec![(key, 0.8)]);
+        cache.save(&path).unwrap();
+
+        let cache = SimilarityCache::open(&path, &scorer).unwrap();
+        assert_eq!(cache.get(key), Some(0.8));
+        assert_eq!((cache.hits(), cache.added()), (1, 0));
+
+        std::fs::write(&path, b"not a cache").unwrap();
+        assert!(SimilarityCache::open(&path, &scorer).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saves_after_a_record_cut_off() {
+        let path = std::env::temp_dir().join(format!("word_similarity_cache_cut_{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let scorer = Scorer::default();
+
+        let cache = SimilarityCache::open(&path, &scorer).unwrap();
+        let (first, second) = (cache.key("hello", "hallo"), cache.key("world", "word"));
+        cache.add(vec![(first, 0.8)]);
+        cache.save(&path).unwrap();
+
+        // An interrupted save leaves part of a record behind
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[1, 2, 3, 4, 5]);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let cache = SimilarityCache::open(&path, &scorer).unwrap();
+        assert_eq!(cache.get(first), Some(0.8));
+        cache.add(vec![(second, 0.75)]);
+        cache.save(&path).unwrap();
+
+        let cache = SimilarityCache::open(&path, &scorer).unwrap();
+        assert_eq!(cache.get(first), Some(0.8));
+        assert_eq!(cache.get(second), Some(0.75));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), (MAGIC.len() + 2 * RECORD_SIZE) as u64);
+
+        std::fs::write(&path, b"WSC").unwrap();
+        assert!(cache.save(&path).is_err());
+        assert!(SimilarityCache::open(&path, &scorer).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
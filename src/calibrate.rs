@@ -0,0 +1,161 @@
+//! Suggestion of a minimum match from the distribution of the similarities of sampled pairs.
+
+use rayon::prelude::*;
+
+use crate::verify::SplitMix64;
+use crate::{Scorer, Word};
+
+/// Number of bins of the histogram, one per percent.
+const BINS: usize = 100;
+
+/// Half the width of the moving average that smooths the histogram, in bins.
+const SMOOTHING: usize = 5;
+
+/// The smallest depth of a valley on the log scale of the histogram, which means the second
+/// group is at least twice as frequent as the valley.
+const MIN_DEPTH: f64 = std::f64::consts::LN_2;
+
+/// A minimum match at the valley between the unrelated pairs and the likely matches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThresholdSuggestion {
+    /// The suggested minimum match, as a fraction.
+    pub threshold: f64,
+    /// The most frequent similarity below the valley, usually of the unrelated pairs.
+    pub lower_peak: f64,
+    /// The most frequent similarity above the valley, of the likely matches.
+    pub upper_peak: f64,
+    /// Number of sampled similarities at or above the threshold.
+    pub above: usize,
+    /// Number of sampled similarities.
+    pub sampled: usize,
+}
+
+/// Compute the similarities of a random sample of the word pairs, or of all pairs if there
+/// are no more than `sample_size` of them.
+pub fn sample_similarities(words: &[Word], scorer: &Scorer, sample_size: usize) -> Vec<f64> {
+    let count = words.len();
+    let total = count * count.saturating_sub(1) / 2;
+    let pairs: Vec<(usize, usize)> = if total <= sample_size {
+        (0..count).flat_map(|a| (a + 1..count).map(move |b| (a, b))).collect()
+    } else {
+        // Pairs are drawn with replacement, which hardly matters for a sample of many pairs
+        let mut random = SplitMix64::from_time();
+        (0..sample_size)
+            .map(|_| {
+                let a = (random.next() % count as u64) as usize;
+                let b = (random.next() % (count - 1) as u64) as usize;
+                (a, if b >= a { b + 1 } else { b })
+            })
+            .collect()
+    };
+
+    pairs
+        .into_par_iter()
+        .map(|(a, b)| scorer.similarity(words[a].processed(), words[b].processed()))
+        .collect()
+}
+
+/// Find the natural gap in a bimodal distribution of similarities and suggest the minimum
+/// match in the middle of it.
+///
+/// The similarities are counted in a histogram of one bin per percent, which is smoothed on a
+/// log scale so a small group of matches still stands out against the many unrelated pairs.
+/// The valley is the deepest dip above the highest peak that is followed by a rise. Returns
+/// `None` if there is no such dip, so the scores do not separate into two groups.
+pub fn suggest_threshold(similarities: &[f64]) -> Option<ThresholdSuggestion> {
+    let mut counts = [0usize; BINS];
+    for &similarity in similarities.iter().filter(|similarity| !similarity.is_nan()) {
+        let bin = ((similarity.clamp(0.0, 1.0) * BINS as f64) as usize).min(BINS - 1);
+        counts[bin] += 1;
+    }
+
+    // Smooth the counts before taking the log, so the empty bins between the few similarities
+    // that short words can have do not look like valleys
+    let smoothed: Vec<f64> = (0..BINS)
+        .map(|bin| {
+            let window = &counts[bin.saturating_sub(SMOOTHING)..(bin + SMOOTHING + 1).min(BINS)];
+            (window.iter().sum::<usize>() as f64 / window.len() as f64).ln_1p()
+        })
+        .collect();
+
+    let lower_peak = highest_bin(&smoothed, 0)?;
+    // The depth of a bin is how far it lies below the lower of the peaks on either side
+    let depths: Vec<f64> = (lower_peak + 1..BINS - 1)
+        .map(|bin| {
+            let right = smoothed[bin + 1..].iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            smoothed[lower_peak].min(right) - smoothed[bin]
+        })
+        .collect();
+    let depth = depths.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if depth < MIN_DEPTH {
+        return None;
+    }
+
+    // A flat valley spans several bins, so take the middle of the first run of deepest bins
+    let first = depths.iter().position(|&d| d >= depth - 1e-9)?;
+    let run = depths[first..].iter().take_while(|&&d| d >= depth - 1e-9).count();
+    let valley_start = lower_peak + 1 + first;
+    let threshold = (2 * valley_start + run) as f64 / (2 * BINS) as f64;
+    let upper_peak = highest_bin(&smoothed, valley_start + run)?;
+
+    Some(ThresholdSuggestion {
+        threshold,
+        lower_peak: bin_center(lower_peak),
+        upper_peak: bin_center(upper_peak),
+        above: similarities.iter().filter(|&&similarity| similarity >= threshold).count(),
+        sampled: similarities.len(),
+    })
+}
+
+/// The first of the highest bins from `start` on, or `None` if all of them are empty.
+fn highest_bin(smoothed: &[f64], start: usize) -> Option<usize> {
+    let mut best: Option<usize> = None;
+    for bin in start..smoothed.len() {
+        if smoothed[bin] > best.map_or(0.0, |best| smoothed[best]) {
+            best = Some(bin);
+        }
+    }
+    best
+}
+
+fn bin_center(bin: usize) -> f64 {
+    (bin as f64 + 0.5) / BINS as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Similarities spread evenly over `count` values from `low` to `high`.
+    fn spread(low: f64, high: f64, count: usize) -> Vec<f64> {
+        (0..count).map(|i| low + (high - low) * i as f64 / (count - 1) as f64).collect()
+    }
+
+    #[test]
+    fn suggests_the_middle_of_the_gap() {
+        let mut similarities = spread(0.1, 0.5, 5000);
+        similarities.extend(spread(0.85, 1.0, 50));
+
+        let suggestion = suggest_threshold(&similarities).unwrap();
+        assert!((0.6..=0.75).contains(&suggestion.threshold), "{:?}", suggestion);
+        assert!(suggestion.lower_peak < 0.5 && suggestion.upper_peak > 0.85);
+        assert_eq!((suggestion.above, suggestion.sampled), (50, 5050));
+    }
+
+    #[test]
+    fn finds_no_gap_in_a_single_group() {
+        assert_eq!(suggest_threshold(&spread(0.1, 0.9, 5000)), None);
+        assert_eq!(suggest_threshold(&[]), None);
+    }
+
+    #[test]
+    fn samples_all_pairs_of_small_inputs() {
+        let words: Vec<Word> = ["hello", "hallo", "help"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let similarities = sample_similarities(&words, &Scorer::default(), 100);
+        assert_eq!(similarities.len(), 3);
+        assert_eq!(sample_similarities(&words, &Scorer::default(), 2).len(), 2);
+    }
+}
@@ -0,0 +1,228 @@
+//! Clustering of similar words and selection of a canonical word per cluster.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{word_column, OutputOptions, SimilarityPair, Word};
+
+/// How the canonical word of a cluster is chosen. Ties go to the word that comes first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CanonicalStrategy {
+    /// The word with the fewest characters.
+    #[default]
+    Shortest,
+    /// The word with the most characters.
+    Longest,
+    /// The word that comes first in the input file.
+    First,
+    /// The word that occurs most often in the input file.
+    MostFrequent,
+}
+
+impl CanonicalStrategy {
+    /// All strategies, in the order they are listed in the help text.
+    pub const ALL: [CanonicalStrategy; 4] = [
+        CanonicalStrategy::Shortest,
+        CanonicalStrategy::Longest,
+        CanonicalStrategy::First,
+        CanonicalStrategy::MostFrequent,
+    ];
+
+    /// The name used for the strategy on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            CanonicalStrategy::Shortest => "shortest",
+            CanonicalStrategy::Longest => "longest",
+            CanonicalStrategy::First => "first",
+            CanonicalStrategy::MostFrequent => "most-frequent",
+        }
+    }
+}
+
+impl fmt::Display for CanonicalStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CanonicalStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CanonicalStrategy::ALL
+            .iter()
+            .copied()
+            .find(|strategy| strategy.name() == s)
+            .ok_or_else(|| format!("Unknown canonical strategy: {}", s))
+    }
+}
+
+/// A cluster of similar words, represented by its canonical word.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cluster {
+    /// Index of the canonical word.
+    pub canonical: usize,
+    /// Indices of the other words in the cluster, in input order.
+    pub members: Vec<usize>,
+}
+
+/// Disjoint sets of word indices with path halving and union by size.
+struct UnionFind {
+    parents: Vec<usize>,
+    sizes: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(count: usize) -> Self {
+        UnionFind {
+            parents: (0..count).collect(),
+            sizes: vec![1; count],
+        }
+    }
+
+    fn find(&mut self, mut index: usize) -> usize {
+        while self.parents[index] != index {
+            self.parents[index] = self.parents[self.parents[index]];
+            index = self.parents[index];
+        }
+        index
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        if self.sizes[a] < self.sizes[b] {
+            std::mem::swap(&mut a, &mut b);
+        }
+        self.parents[b] = a;
+        self.sizes[a] += self.sizes[b];
+    }
+}
+
+/// Group the words connected by the pairs into clusters, in the order of their first word.
+///
+/// Words without any pair form no cluster, since they have nothing to absorb.
+pub fn cluster_words(word_count: usize, pairs: &[SimilarityPair]) -> Vec<Vec<usize>> {
+    let mut sets = UnionFind::new(word_count);
+    for pair in pairs {
+        sets.union(pair.index_a(), pair.index_b());
+    }
+
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    let mut cluster_of_root = HashMap::new();
+    for index in 0..word_count {
+        let root = sets.find(index);
+        let cluster = *cluster_of_root.entry(root).or_insert_with(|| {
+            clusters.push(Vec::new());
+            clusters.len() - 1
+        });
+        clusters[cluster].push(index);
+    }
+    clusters.retain(|cluster| cluster.len() > 1);
+    clusters
+}
+
+/// Cluster the words connected by the pairs and choose the canonical word of every cluster.
+pub fn canonicalize(words: &[Word], pairs: &[SimilarityPair], strategy: CanonicalStrategy) -> Vec<Cluster> {
+    let mut frequencies: HashMap<&str, usize> = HashMap::new();
+    if strategy == CanonicalStrategy::MostFrequent {
+        for word in words {
+            *frequencies.entry(word.original.as_str()).or_insert(0) += 1;
+        }
+    }
+
+    cluster_words(words.len(), pairs)
+        .into_iter()
+        .map(|indices| {
+            // Members are in input order, so the first best word wins ties
+            let length = |index: usize| words[index].original.chars().count();
+            let mut canonical = indices[0];
+            for &index in &indices[1..] {
+                let better = match strategy {
+                    CanonicalStrategy::Shortest => length(index) < length(canonical),
+                    CanonicalStrategy::Longest => length(index) > length(canonical),
+                    CanonicalStrategy::First => false,
+                    CanonicalStrategy::MostFrequent => {
+                        frequencies[words[index].original.as_str()]
+                            > frequencies[words[canonical].original.as_str()]
+                    }
+                };
+                if better {
+                    canonical = index;
+                }
+            }
+            let members = indices.into_iter().filter(|&index| index != canonical).collect();
+            Cluster { canonical, members }
+        })
+        .collect()
+}
+
+/// Write one line per cluster with the canonical word followed by the words it absorbs.
+pub fn write_clusters_file(
+    path: &Path,
+    clusters: &[Cluster],
+    words: &[Word],
+    options: &OutputOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::create(path)?;
+    let mut writer = match options.write_buffer_size {
+        Some(capacity) => BufWriter::with_capacity(capacity, file),
+        None => BufWriter::new(file),
+    };
+
+    let newline = if options.crlf { "\r\n" } else { "\n" };
+    for cluster in clusters {
+        write!(writer, "{}\tAbsorbs:", word_column(cluster.canonical, words, options))?;
+        for (position, &member) in cluster.members.iter().enumerate() {
+            let separator = if position == 0 { " " } else { "\t" };
+            write!(writer, "{}{}", separator, word_column(member, words, options))?;
+        }
+        write!(writer, "{}", newline)?;
+    }
+
+    // Flush explicitly, since dropping the writer would swallow any error
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(index_a: usize, index_b: usize) -> SimilarityPair {
+        SimilarityPair::new(0.9, index_a, index_b)
+    }
+
+    fn words(originals: &[&str]) -> Vec<Word> {
+        originals.iter().map(|word| Word::unprocessed(word.to_string())).collect()
+    }
+
+    #[test]
+    fn clusters_connected_words() {
+        let clusters = cluster_words(6, &[pair(4, 1), pair(0, 3), pair(3, 5)]);
+        assert_eq!(clusters, vec![vec![0, 3, 5], vec![1, 4]]);
+    }
+
+    #[test]
+    fn chooses_canonical_word_by_strategy() {
+        let words = words(&["hallo", "helo", "hello", "helo", "hellooo"]);
+        let pairs = [pair(0, 1), pair(1, 2), pair(2, 3), pair(3, 4)];
+        let canonical = |strategy| canonicalize(&words, &pairs, strategy)[0].canonical;
+
+        assert_eq!(canonical(CanonicalStrategy::Shortest), 1);
+        assert_eq!(canonical(CanonicalStrategy::Longest), 4);
+        assert_eq!(canonical(CanonicalStrategy::First), 0);
+        assert_eq!(canonical(CanonicalStrategy::MostFrequent), 1);
+        assert_eq!(
+            canonicalize(&words, &pairs, CanonicalStrategy::Shortest)[0].members,
+            vec![0, 2, 3, 4]
+        );
+    }
+}
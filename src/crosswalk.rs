@@ -0,0 +1,99 @@
+//! A crosswalk mapping every word of a new batch to its best match among the input words, for
+//! migrating records from one list to another.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::{format_similarity, row_label, OutputOptions, SimilarityPair, Word};
+
+/// The placeholder written in the match columns of a batch word without a match.
+pub const UNMATCHED: &str = "NULL";
+
+/// Find the best input word of every batch word, the words from `batch_start` on, among the
+/// pairs of batch words with input words. Ties go to the pair that comes first.
+///
+/// Returns the index of the input word and the similarity per batch word, or `None` if none
+/// of its pairs is with an input word.
+pub fn best_matches(pairs: &[SimilarityPair], word_count: usize, batch_start: usize) -> Vec<Option<(usize, f64)>> {
+    let mut best: Vec<Option<(usize, f64)>> = vec![None; word_count - batch_start];
+    for pair in pairs {
+        let (batch, input) = match (pair.index_a() >= batch_start, pair.index_b() >= batch_start) {
+            (true, false) => (pair.index_a(), pair.index_b()),
+            (false, true) => (pair.index_b(), pair.index_a()),
+            _ => continue,
+        };
+        let slot = &mut best[batch - batch_start];
+        if slot.is_none_or(|(_, similarity)| pair.similarity > similarity) {
+            *slot = Some((input, pair.similarity));
+        }
+    }
+    best
+}
+
+/// Render the crosswalk as tab-separated text with a header, one row per batch word in the
+/// order of the batch file, with `UNMATCHED` in the match columns of the unmatched words.
+pub fn render_crosswalk(pairs: &[SimilarityPair], words: &[Word], batch_start: usize, options: &OutputOptions) -> String {
+    let mut text = String::from("batch_row\tbatch_word\tinput_row\tinput_word\tsimilarity\n");
+    for (offset, best) in best_matches(pairs, words.len(), batch_start).into_iter().enumerate() {
+        let index = batch_start + offset;
+        let (input_row, input_word, similarity) = match best {
+            Some((input, similarity)) => (
+                row_label(input, options),
+                words[input].original.clone(),
+                format_similarity(similarity, options),
+            ),
+            None => (UNMATCHED.to_string(), UNMATCHED.to_string(), UNMATCHED.to_string()),
+        };
+        text.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            row_label(index, options),
+            words[index].original,
+            input_row,
+            input_word,
+            similarity
+        ));
+    }
+    text
+}
+
+/// Write the crosswalk of the batch words to a file, see `render_crosswalk`.
+pub fn write_crosswalk_file(
+    path: &Path,
+    pairs: &[SimilarityPair],
+    words: &[Word],
+    batch_start: usize,
+    options: &OutputOptions,
+) -> io::Result<()> {
+    fs::write(path, render_crosswalk(pairs, words, batch_start, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_every_batch_word_to_its_best_input_word() {
+        let words: Vec<Word> = ["hello", "world", "helo", "wrld", "xyz", "xyzz"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let pairs = [
+            SimilarityPair::new(0.8, 0, 2),
+            SimilarityPair::new(0.9, 4, 5),
+            SimilarityPair::new(0.75, 3, 1),
+            SimilarityPair::new(0.75, 3, 0),
+        ];
+        assert_eq!(best_matches(&pairs, words.len(), 2), [Some((0, 0.8)), Some((1, 0.75)), None, None]);
+
+        let options = OutputOptions {
+            batch_start: Some(2),
+            ..OutputOptions::default()
+        };
+        let text = render_crosswalk(&pairs, &words, 2, &options);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "Batch row 1\thelo\tRow 1\thello\t80.00%");
+        assert_eq!(lines[3], "Batch row 3\txyz\tNULL\tNULL\tNULL");
+    }
+}
@@ -0,0 +1,163 @@
+//! Reading of square similarity matrices computed by other tools, as labeled CSV files.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::InputError;
+use crate::NOT_COMPARED;
+
+/// A square similarity matrix with a label for every row and column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabeledMatrix {
+    pub labels: Vec<String>,
+    /// The similarities as fractions, with `NOT_COMPARED` for empty cells.
+    pub matrix: Vec<Vec<f64>>,
+}
+
+/// Read a labeled square matrix from a CSV file, see `parse_csv_matrix`.
+pub fn read_csv_matrix(path: &Path, percentages: bool) -> Result<LabeledMatrix, InputError> {
+    let bytes = fs::read(path)?;
+    let text = String::from_utf8(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+    parse_csv_matrix(&text, percentages)
+}
+
+/// Parse a labeled square matrix, as written by spreadsheets and data frame libraries:
+///
+/// ```text
+/// ,hello,hallo,world
+/// hello,1,0.8,0.2
+/// hallo,0.8,1,0.2
+/// world,0.2,0.2,1
+/// ```
+///
+/// The first line lists the labels after a corner cell, and every other line starts with the
+/// label of its row, which must be the label of the column at the same position. Fields may be
+/// quoted, with `""` for a quote inside quotes. The similarities are fractions from 0 to 1, or
+/// percentages from 0 to 100 if `percentages` is set. Empty cells were not compared.
+pub fn parse_csv_matrix(text: &str, percentages: bool) -> Result<LabeledMatrix, InputError> {
+    let invalid = |line: usize, message: String| InputError::InvalidMatrix { line, message };
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+
+    let labels = match lines.next() {
+        Some((index, line)) => {
+            let mut header = split_csv_line(line).map_err(|message| invalid(index + 1, message))?;
+            header.remove(0);
+            header
+        }
+        None => return Err(invalid(1, "The file is empty".to_string())),
+    };
+    if labels.is_empty() {
+        return Err(invalid(1, "The header lists no labels".to_string()));
+    }
+
+    let scale = if percentages { 100.0 } else { 1.0 };
+    let mut matrix = Vec::with_capacity(labels.len());
+    for (index, line) in lines {
+        let line_number = index + 1;
+        let fields = split_csv_line(line).map_err(|message| invalid(line_number, message))?;
+        let row = matrix.len();
+        if row == labels.len() {
+            return Err(invalid(line_number, format!("More rows than the {} labels of the header", labels.len())));
+        }
+        if fields.len() != labels.len() + 1 {
+            let message = format!("Expected a label and {} values, found {} fields", labels.len(), fields.len());
+            return Err(invalid(line_number, message));
+        }
+        if fields[0] != labels[row] {
+            let message = format!("Row {} is labeled {:?}, but its column is labeled {:?}", row + 1, fields[0], labels[row]);
+            return Err(invalid(line_number, message));
+        }
+
+        let mut values = Vec::with_capacity(labels.len());
+        for field in &fields[1..] {
+            let field = field.trim();
+            if field.is_empty() {
+                values.push(NOT_COMPARED);
+                continue;
+            }
+            match field.parse::<f64>() {
+                Ok(value) if (0.0..=scale).contains(&value) => values.push(value / scale),
+                _ => return Err(invalid(line_number, format!("Invalid similarity: {}", field))),
+            }
+        }
+        matrix.push(values);
+    }
+    if matrix.len() != labels.len() {
+        let message = format!("Expected {} rows, one per label, found {}", labels.len(), matrix.len());
+        return Err(invalid(text.lines().count(), message));
+    }
+
+    Ok(LabeledMatrix { labels, matrix })
+}
+
+/// Split a CSV line into its fields, unquoting the quoted ones.
+fn split_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => {
+                quoted = false;
+                if !matches!(chars.peek(), None | Some(',')) {
+                    return Err("Unexpected text after a closing quote".to_string());
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quoted field".to_string());
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_labeled_matrices() {
+        let text = "\u{feff},hello,\"Smith, J.\"\r\nhello,1,\n\"Smith, J.\",0.5,1\n";
+        let parsed = parse_csv_matrix(text, false).unwrap();
+        assert_eq!(parsed.labels, ["hello", "Smith, J."]);
+        assert_eq!(parsed.matrix[0][0], 1.0);
+        assert!(parsed.matrix[0][1].is_nan());
+        assert_eq!(parsed.matrix[1], [0.5, 1.0]);
+
+        let parsed = parse_csv_matrix("x,a,b\na,100,80\nb,80,100\n", true).unwrap();
+        assert_eq!(parsed.matrix, [[1.0, 0.8], [0.8, 1.0]]);
+        assert_eq!(split_csv_line(r#""say ""hi""",b"#).unwrap(), [r#"say "hi""#, "b"]);
+    }
+
+    #[test]
+    fn rejects_invalid_matrices() {
+        for (text, line) in [
+            ("", 1),
+            (",\n", 1),
+            (",a,b\na,1,0.5\n", 2),
+            (",a,b\na,1,0.5\nc,0.5,1\n", 3),
+            (",a,b\na,1\nb,0.5,1\n", 2),
+            (",a,b\na,1,1.5\nb,0.5,1\n", 2),
+            (",a,b\na,1,x\nb,0.5,1\n", 2),
+            (",a\na,1\na,1\n", 3),
+            (",\"a\nb,1\n", 1),
+        ] {
+            match parse_csv_matrix(text, false) {
+                Err(InputError::InvalidMatrix { line: actual, .. }) => assert_eq!(actual, line, "{:?}", text),
+                other => panic!("{:?} gave {:?}", text, other),
+            }
+        }
+    }
+}
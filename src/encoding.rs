@@ -0,0 +1,44 @@
+//! Decoding of input files in the single-byte encodings of legacy word lists.
+
+use crate::InputEncoding;
+
+/// The characters of the bytes 0x80 to 0x9F in Windows-1252, where it differs from Latin-1.
+///
+/// The five bytes that Windows-1252 leaves undefined keep their C1 control character, as in
+/// the WHATWG Encoding Standard, so every byte decodes to a character.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+/// Decode the bytes of a line of the input.
+///
+/// In the single-byte encodings every byte is a character, so decoding never fails. UTF-8 is
+/// decoded lossily, replacing invalid bytes with U+FFFD, since strict UTF-8 is read directly.
+pub fn decode(bytes: &[u8], encoding: InputEncoding) -> String {
+    match encoding {
+        InputEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+        InputEncoding::Latin1 => bytes.iter().map(|&byte| char::from(byte)).collect(),
+        InputEncoding::Windows1252 => bytes
+            .iter()
+            .map(|&byte| match byte {
+                0x80..=0x9f => WINDOWS_1252_HIGH[usize::from(byte - 0x80)],
+                _ => char::from(byte),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_single_byte_encodings() {
+        let bytes = b"caf\xe9 \x80\x96\x81";
+        assert_eq!(decode(bytes, InputEncoding::Latin1), "café \u{80}\u{96}\u{81}");
+        assert_eq!(decode(bytes, InputEncoding::Windows1252), "café €–\u{81}");
+        assert_eq!(decode(bytes, InputEncoding::Utf8), "caf\u{fffd} \u{fffd}\u{fffd}\u{fffd}");
+        assert_eq!(decode("café".as_bytes(), InputEncoding::Utf8), "café");
+    }
+}
@@ -0,0 +1,107 @@
+//! Errors returned when reading the input files and writing the output file.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// An error reading the input file or one of the files accompanying it.
+#[derive(Debug)]
+pub enum InputError {
+    /// The file could not be opened or read.
+    Io(io::Error),
+    /// The file contains an empty line; lines are numbered from 1.
+    EmptyLine { line: usize },
+    /// A word is empty after preprocessing, with `EmptyPolicy::Error`; lines are numbered from 1.
+    EmptyAfterPreprocessing { line: usize },
+    /// The file contains fewer words than the minimum.
+    TooFewWords { count: usize, min: usize, max: usize },
+    /// The file contains more words than the maximum.
+    TooManyWords { count: usize, min: usize, max: usize },
+    /// A record has fewer fields than the highest weighted field; lines are numbered from 1.
+    MissingFields { line: usize, fields: usize, required: usize },
+    /// A line of JSON input is not an array of strings; lines are numbered from 1.
+    InvalidJson { line: usize, message: String },
+    /// A CSV similarity matrix is not square or has an invalid cell; lines are numbered from 1.
+    InvalidMatrix { line: usize, message: String },
+    /// The IDs file does not contain exactly one ID per word.
+    IdCountMismatch { ids: usize, words: usize },
+}
+
+impl InputError {
+    /// Whether the file was read, but its contents are invalid.
+    pub fn is_invalid_contents(&self) -> bool {
+        !matches!(self, InputError::Io(_))
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputError::Io(e) => e.fmt(f),
+            InputError::EmptyLine { line } => {
+                write!(f, "Empty lines are not allowed in the input file (line {})", line)
+            }
+            InputError::EmptyAfterPreprocessing { line } => {
+                write!(f, "The word on line {} is empty after preprocessing", line)
+            }
+            InputError::TooFewWords { count, min, max }
+            | InputError::TooManyWords { count, min, max } => write!(
+                f,
+                "Invalid number of words: {}. The input file must contain between {} and {} words.",
+                count, min, max
+            ),
+            InputError::MissingFields { line, fields, required } => write!(
+                f,
+                "The record on line {} has {} fields, but at least {} are needed",
+                line, fields, required
+            ),
+            InputError::InvalidJson { line, message } => {
+                write!(f, "Invalid JSON array on line {}: {}", line, message)
+            }
+            InputError::InvalidMatrix { line, message } => {
+                write!(f, "Invalid matrix on line {}: {}", line, message)
+            }
+            InputError::IdCountMismatch { ids, words } => write!(
+                f,
+                "The IDs file contains {} IDs, but the input file contains {} words.",
+                ids, words
+            ),
+        }
+    }
+}
+
+impl Error for InputError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InputError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for InputError {
+    fn from(e: io::Error) -> Self {
+        InputError::Io(e)
+    }
+}
+
+/// The output file was truncated because the next line would exceed the size cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputTruncated {
+    pub max_bytes: u64,
+    /// Number of pairs written before the output was truncated.
+    pub pairs_written: usize,
+    pub pairs_total: usize,
+}
+
+impl fmt::Display for OutputTruncated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The output was truncated at {} bytes after {} of {} pairs",
+            self.max_bytes, self.pairs_written, self.pairs_total
+        )
+    }
+}
+
+impl Error for OutputTruncated {}
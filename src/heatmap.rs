@@ -0,0 +1,241 @@
+//! Rendering of the similarity matrix as a heatmap PNG, for exploring small inputs.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Largest number of words rendered as a heatmap. Larger matrices get cells under a pixel.
+pub const MAX_HEATMAP_WORDS: usize = 500;
+
+/// Side of the square in which the cells are drawn, as long as a cell fits in a pixel.
+const MATRIX_SIZE: usize = 600;
+
+/// Largest side of a cell, so a handful of words does not give huge blocks.
+const MAX_CELL_SIZE: usize = 24;
+
+/// Scale of the digit font, whose digits are 3 by 5 dots.
+const FONT_SCALE: usize = 2;
+
+const DIGIT_WIDTH: usize = 3 * FONT_SCALE;
+const DIGIT_HEIGHT: usize = 5 * FONT_SCALE;
+const DIGIT_ADVANCE: usize = DIGIT_WIDTH + FONT_SCALE;
+const MARGIN: usize = 8;
+const SCALE_WIDTH: usize = 16;
+
+const BACKGROUND: [u8; 3] = [255, 255, 255];
+const TEXT: [u8; 3] = [0, 0, 0];
+const NOT_COMPARED_COLOR: [u8; 3] = [200, 200, 200];
+
+/// Colors from low to high similarity, evenly spaced. These are stops of the viridis color
+/// map, which stays readable in grayscale and for color-blind readers.
+const COLOR_STOPS: [[u8; 3]; 5] = [
+    [68, 1, 84],
+    [59, 82, 139],
+    [33, 145, 140],
+    [94, 201, 98],
+    [253, 231, 37],
+];
+
+/// The digits 0 to 9, one row of 3 dots per entry, most significant bit on the left.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// An RGB image with 8 bits per channel.
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![BACKGROUND; width * height],
+        }
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: [u8; 3]) {
+        for row in y..(y + height).min(self.height) {
+            let start = row * self.width;
+            self.pixels[start + x.min(self.width)..start + (x + width).min(self.width)].fill(color);
+        }
+    }
+
+    /// Draw a number with its top left corner at the given position.
+    fn draw_number(&mut self, x: usize, y: usize, number: usize) {
+        for (position, digit) in number.to_string().bytes().enumerate() {
+            let glyph = DIGITS[usize::from(digit - b'0')];
+            for (dot_y, dots) in glyph.iter().enumerate() {
+                for dot_x in 0..3 {
+                    if dots & (0b100 >> dot_x) != 0 {
+                        self.fill(
+                            x + position * DIGIT_ADVANCE + dot_x * FONT_SCALE,
+                            y + dot_y * FONT_SCALE,
+                            FONT_SCALE,
+                            FONT_SCALE,
+                            TEXT,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Width of a number drawn with `Image::draw_number`.
+fn number_width(number: usize) -> usize {
+    number.to_string().len() * DIGIT_ADVANCE - FONT_SCALE
+}
+
+/// The color of a similarity, interpolated between the color stops.
+fn similarity_color(similarity: f64) -> [u8; 3] {
+    if similarity.is_nan() {
+        return NOT_COMPARED_COLOR;
+    }
+    let position = similarity.clamp(0.0, 1.0) * (COLOR_STOPS.len() - 1) as f64;
+    let lower = (position.floor() as usize).min(COLOR_STOPS.len() - 2);
+    let fraction = position - lower as f64;
+    let (from, to) = (COLOR_STOPS[lower], COLOR_STOPS[lower + 1]);
+    let mut color = [0; 3];
+    for channel in 0..3 {
+        let value = f64::from(from[channel]) + (f64::from(to[channel]) - f64::from(from[channel])) * fraction;
+        color[channel] = value.round() as u8;
+    }
+    color
+}
+
+/// Label every row and column whose number is a multiple of the smallest step of 1, 2 or 5
+/// times a power of ten at which the labels do not overlap.
+fn label_step(cell_size: usize, label_size: usize) -> usize {
+    let mut power = 1;
+    loop {
+        for factor in [1, 2, 5] {
+            if factor * power * cell_size >= label_size + FONT_SCALE * 2 {
+                return factor * power;
+            }
+        }
+        power *= 10;
+    }
+}
+
+/// Draw the matrix with the row numbers on the left and top edges and a color scale from
+/// 0% at the bottom to 100% at the top on the right.
+fn render(matrix: &[Vec<f64>]) -> Image {
+    let count = matrix.len().max(1);
+    let cell_size = (MATRIX_SIZE / count).clamp(1, MAX_CELL_SIZE);
+    let matrix_size = cell_size * matrix.len();
+    let label_width = number_width(count).max(number_width(100));
+
+    let left = MARGIN + label_width + FONT_SCALE * 2;
+    let top = MARGIN + DIGIT_HEIGHT + FONT_SCALE * 2;
+    // The last column label may stick out past the matrix
+    let scale_left = left + matrix_size + number_width(count) + MARGIN;
+    let width = scale_left + SCALE_WIDTH + FONT_SCALE * 2 + number_width(100) + MARGIN;
+    let height = top + matrix_size.max(DIGIT_HEIGHT * 2) + MARGIN;
+    let mut image = Image::new(width, height);
+
+    for (row, values) in matrix.iter().enumerate() {
+        for (column, &similarity) in values.iter().enumerate() {
+            let x = left + column * cell_size;
+            let y = top + row * cell_size;
+            image.fill(x, y, cell_size, cell_size, similarity_color(similarity));
+        }
+    }
+
+    // Rows are numbered from 1 like in the output file, and the first row is always labeled
+    let rows_step = label_step(cell_size, DIGIT_HEIGHT);
+    let columns_step = label_step(cell_size, number_width(count));
+    for index in 0..matrix.len() {
+        let number = index + 1;
+        if index == 0 || number % rows_step == 0 {
+            let x = left - FONT_SCALE * 2 - number_width(number);
+            image.draw_number(x, top + index * cell_size, number);
+        }
+        if index == 0 || number % columns_step == 0 {
+            image.draw_number(left + index * cell_size, MARGIN, number);
+        }
+    }
+
+    for y in 0..matrix_size {
+        let similarity = 1.0 - y as f64 / (matrix_size.max(2) - 1) as f64;
+        image.fill(scale_left, top + y, SCALE_WIDTH, 1, similarity_color(similarity));
+    }
+    let labels_left = scale_left + SCALE_WIDTH + FONT_SCALE * 2;
+    image.draw_number(labels_left, top, 100);
+    image.draw_number(labels_left, top + matrix_size.max(DIGIT_HEIGHT * 2) - DIGIT_HEIGHT, 0);
+
+    image
+}
+
+/// Render the similarity matrix as a heatmap and write it to a PNG file.
+///
+/// The cells scale with the number of words to fill about 600 pixels. Pairs that were not
+/// compared are gray.
+pub fn write_heatmap_file(path: &Path, matrix: &[Vec<f64>]) -> io::Result<()> {
+    let image = render(matrix);
+    let mut writer = BufWriter::new(File::create(path)?);
+    encode_png(&image, &mut writer)?;
+    // Flush explicitly, since dropping the writer would swallow any error
+    writer.flush()
+}
+
+/// Encode the image as a PNG with 8-bit RGB pixels.
+fn encode_png<W: Write>(image: &Image, writer: W) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(writer, image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let data: Vec<u8> = image.pixels.iter().flatten().copied().collect();
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_span_the_scale() {
+        assert_eq!(similarity_color(0.0), COLOR_STOPS[0]);
+        assert_eq!(similarity_color(0.5), COLOR_STOPS[2]);
+        assert_eq!(similarity_color(1.0), COLOR_STOPS[4]);
+        assert_eq!(similarity_color(f64::NAN), NOT_COMPARED_COLOR);
+    }
+
+    #[test]
+    fn encodes_matrix_as_png() {
+        let matrix = vec![vec![1.0, 0.25, f64::NAN], vec![0.25, 1.0, 0.5], vec![f64::NAN, 0.5, 1.0]];
+        let image = render(&matrix);
+        let mut png = Vec::new();
+        encode_png(&image, &mut png).unwrap();
+
+        let mut reader = png::Decoder::new(io::Cursor::new(png)).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width as usize, info.height as usize), (image.width, image.height));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        let pixels: Vec<u8> = image.pixels.iter().flatten().copied().collect();
+        assert_eq!(data, pixels);
+
+        // The first cell is on the diagonal, the last cell of the first row was not compared
+        let cell = MAX_CELL_SIZE;
+        let left = MARGIN + number_width(100) + FONT_SCALE * 2;
+        let top = MARGIN + DIGIT_HEIGHT + FONT_SCALE * 2;
+        let pixel = |x: usize, y: usize| image.pixels[y * image.width + x];
+        assert_eq!(pixel(left, top + cell - 1), COLOR_STOPS[4]);
+        assert_eq!(pixel(left + 2 * cell, top), NOT_COMPARED_COLOR);
+    }
+}
@@ -0,0 +1,133 @@
+//! Parsing of input lines holding a JSON array of tokens, as written by NLP tokenizers.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Parse a line holding a JSON array of strings, like `["new", "york"]`, into its strings.
+///
+/// Only arrays of strings are accepted. The strings must not contain line breaks, since the
+/// words are written one per line.
+pub fn parse_string_array(line: &str) -> Result<Vec<String>, String> {
+    let mut chars = line.chars().peekable();
+    skip_whitespace(&mut chars);
+    if chars.next() != Some('[') {
+        return Err("Expected an array".to_string());
+    }
+
+    let mut strings = Vec::new();
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&']') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            strings.push(parse_string(&mut chars)?);
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some(']') => break,
+                _ => return Err("Expected ',' or ']' after a string".to_string()),
+            }
+        }
+    }
+
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return Err("Unexpected text after the array".to_string());
+    }
+    Ok(strings)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+        chars.next();
+    }
+}
+
+/// Parse a JSON string, starting at its opening quote.
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    if chars.next() != Some('"') {
+        return Err("Expected a string".to_string());
+    }
+    let mut string = String::new();
+    loop {
+        let c = match chars.next() {
+            Some('"') => return Ok(string),
+            Some('\\') => match chars.next() {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('/') => '/',
+                Some('b') => '\u{8}',
+                Some('f') => '\u{c}',
+                Some('t') => '\t',
+                Some('n' | 'r') => return Err("Strings must not contain line breaks".to_string()),
+                Some('u') => parse_unicode_escape(chars)?,
+                _ => return Err("Invalid escape sequence".to_string()),
+            },
+            Some(c) if u32::from(c) < 0x20 => return Err("Unescaped control character".to_string()),
+            Some(c) => c,
+            None => return Err("Unterminated string".to_string()),
+        };
+        string.push(c);
+    }
+}
+
+/// Parse the hex digits of a `\u` escape, and the low surrogate that follows a high one.
+fn parse_unicode_escape(chars: &mut Peekable<Chars>) -> Result<char, String> {
+    fn hex(chars: &mut Peekable<Chars>) -> Result<u32, String> {
+        let digits: String = chars.by_ref().take(4).collect();
+        match u32::from_str_radix(&digits, 16) {
+            Ok(value) if digits.len() == 4 => Ok(value),
+            _ => Err("Invalid \\u escape".to_string()),
+        }
+    }
+    let high = hex(chars)?;
+    let code = if (0xD800..0xDC00).contains(&high) {
+        if chars.next() != Some('\\') || chars.next() != Some('u') {
+            return Err("Unpaired surrogate".to_string());
+        }
+        let low = hex(chars)?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err("Unpaired surrogate".to_string());
+        }
+        0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+    } else {
+        high
+    };
+    match char::from_u32(code) {
+        Some('\n' | '\r') => Err("Strings must not contain line breaks".to_string()),
+        Some(c) => Ok(c),
+        None => Err("Unpaired surrogate".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_arrays_of_strings() {
+        assert_eq!(parse_string_array(r#"["new","york"]"#).unwrap(), ["new", "york"]);
+        assert_eq!(parse_string_array(r#" [ "a\"b" , "é😀\/" ] "#).unwrap(), ["a\"b", "é😀/"]);
+        assert_eq!(parse_string_array(r#"["\u00e9\ud83d\ude00"]"#).unwrap(), ["é😀"]);
+        assert!(parse_string_array("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_arrays() {
+        for line in [
+            r#""new york""#,
+            r#"["new" "york"]"#,
+            r#"["new",]"#,
+            r#"["new", 1]"#,
+            r#"["new"] x"#,
+            r#"["new"#,
+            r#"["a\nb"]"#,
+            r#"["\u000a"]"#,
+            r#"["\ud83d"]"#,
+            r#"["\x"]"#,
+        ] {
+            assert!(parse_string_array(line).is_err(), "{}", line);
+        }
+    }
+}
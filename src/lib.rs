@@ -0,0 +1,3089 @@
+//! Calculates similarity percentages between word pairs.
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::prelude::*;
+use regex::Regex;
+
+pub mod cache;
+pub mod calibrate;
+pub mod checksum;
+pub mod cluster;
+pub mod crosswalk;
+pub mod csv_matrix;
+pub mod encoding;
+pub mod error;
+pub mod heatmap;
+pub mod json;
+pub mod metrics;
+pub mod mmap;
+pub mod preprocess;
+pub mod records;
+pub mod report;
+pub mod stats;
+pub mod validate;
+pub mod verify;
+
+pub use error::{InputError, OutputTruncated};
+pub use metrics::{Scorer, SimilarityAlgorithm};
+pub use preprocess::Preprocessing;
+
+use cache::SimilarityCache;
+use records::RecordFields;
+
+/// The format of the lines of the input file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Every line is a word.
+    #[default]
+    Text,
+    /// Every line is a JSON array of tokens, which are joined with spaces into a word.
+    Json,
+}
+
+impl InputFormat {
+    /// All formats, in the order they are listed in the help text.
+    pub const ALL: [InputFormat; 2] = [InputFormat::Text, InputFormat::Json];
+
+    /// The name used for the format on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            InputFormat::Text => "text",
+            InputFormat::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InputFormat::ALL
+            .iter()
+            .copied()
+            .find(|format| format.name() == s)
+            .ok_or_else(|| format!("Unknown input format: {}", s))
+    }
+}
+
+/// The character encoding of the input file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputEncoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1, where every byte is the Unicode character of the same number.
+    Latin1,
+    /// The Windows code page 1252, Latin-1 with printable characters such as `€` and `–`
+    /// instead of the control characters from 0x80 to 0x9F.
+    Windows1252,
+}
+
+impl InputEncoding {
+    /// All encodings, in the order they are listed in the help text.
+    pub const ALL: [InputEncoding; 3] = [InputEncoding::Utf8, InputEncoding::Latin1, InputEncoding::Windows1252];
+
+    /// The name used for the encoding on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            InputEncoding::Utf8 => "utf-8",
+            InputEncoding::Latin1 => "latin1",
+            InputEncoding::Windows1252 => "windows-1252",
+        }
+    }
+}
+
+impl fmt::Display for InputEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for InputEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        InputEncoding::ALL
+            .iter()
+            .copied()
+            .find(|encoding| encoding.name() == s)
+            .ok_or_else(|| format!("Unknown input encoding: {}", s))
+    }
+}
+
+/// What to do with words whose comparison key is empty after preprocessing, like `!!!` with
+/// punctuation stripped. Against each other they would score 100%, and 0% against all others.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmptyPolicy {
+    /// Leave the words out and count them.
+    #[default]
+    Drop,
+    /// Reject the input with an `InputError::EmptyAfterPreprocessing` error.
+    Error,
+    /// Compare the words like any other.
+    Keep,
+}
+
+impl EmptyPolicy {
+    /// All policies, in the order they are listed in the help text.
+    pub const ALL: [EmptyPolicy; 3] = [EmptyPolicy::Drop, EmptyPolicy::Error, EmptyPolicy::Keep];
+
+    /// The name used for the policy on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            EmptyPolicy::Drop => "drop",
+            EmptyPolicy::Error => "error",
+            EmptyPolicy::Keep => "keep",
+        }
+    }
+}
+
+impl fmt::Display for EmptyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EmptyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EmptyPolicy::ALL
+            .iter()
+            .copied()
+            .find(|policy| policy.name() == s)
+            .ok_or_else(|| format!("Unknown empty policy: {}", s))
+    }
+}
+
+/// Options controlling how the input file is read.
+#[derive(Clone, Debug, Default)]
+pub struct InputOptions {
+    pub format: InputFormat,
+    /// Decode the input files from this encoding into UTF-8 before reading the words.
+    pub encoding: InputEncoding,
+    pub preprocessing: Preprocessing,
+    /// What to do with the words that preprocessing leaves empty.
+    pub empty_policy: EmptyPolicy,
+    /// Accept an input file with a single word instead of rejecting it.
+    pub allow_single_word: bool,
+    /// Capacity of the input buffer in bytes, or the standard library default if not set.
+    pub read_buffer_size: Option<usize>,
+    /// Extract every match of the pattern in a line as a separate word, instead of using the
+    /// whole line. Lines without a match, including empty lines, are skipped.
+    pub token_regex: Option<Regex>,
+    /// Minimum milliseconds between redraws of the progress bar, from 1 to 1000, or the
+    /// indicatif default of 15 redraws per second if not set.
+    pub progress_interval: Option<u64>,
+    /// Do not draw the progress bar while reading, for callers reporting progress themselves.
+    pub hide_progress: bool,
+    /// Map the input file into memory instead of reading it through a buffer.
+    pub mmap: bool,
+    /// Replace invalid UTF-8 with U+FFFD instead of rejecting the input.
+    pub lossy: bool,
+    /// Read every line as a record of delimited fields, which are preprocessed one by one
+    /// and must include every weighted field.
+    pub record_fields: Option<RecordFields>,
+    /// Only read the lines in this range, numbered from 1, keeping their numbers in the file.
+    pub line_range: Option<RangeInclusive<usize>>,
+}
+
+/// The format of the output file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One tab-separated line per pair.
+    #[default]
+    Text,
+    /// A GraphViz graph with a node per word and an edge per pair.
+    Dot,
+    /// One JSON object per line and pair, without an enclosing array.
+    Jsonl,
+    /// One line per word listing its matches, for importing into network analysis tools.
+    Adjacency,
+}
+
+impl OutputFormat {
+    /// All formats, in the order they are listed in the help text.
+    pub const ALL: [OutputFormat; 4] = [
+        OutputFormat::Text,
+        OutputFormat::Dot,
+        OutputFormat::Jsonl,
+        OutputFormat::Adjacency,
+    ];
+
+    /// The name used for the format on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Dot => "dot",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Adjacency => "adjacency",
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OutputFormat::ALL
+            .iter()
+            .copied()
+            .find(|format| format.name() == s)
+            .ok_or_else(|| format!("Unknown output format: {}", s))
+    }
+}
+
+/// Options controlling how the pairs are written to the output file.
+#[derive(Clone, Debug)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    /// Pad the columns to fixed widths instead of separating them with tabs.
+    pub align: bool,
+    /// Additional algorithms whose scores are written as extra columns.
+    pub extra_algorithms: Vec<SimilarityAlgorithm>,
+    /// External IDs of the words, used instead of the row numbers.
+    pub ids: Option<Vec<String>>,
+    /// Write similarities as integer permille (0 to 1000) instead of percentages.
+    pub permille: bool,
+    /// Index of the first word of a new batch, see `ComparisonFilter::batch_start`. Batch
+    /// words are labeled by their row in the batch file, and every pair is tagged with
+    /// whether it matches the batch against the canonical words or against itself.
+    pub batch_start: Option<usize>,
+    /// Capacity of the output buffer in bytes, or the standard library default if not set.
+    pub write_buffer_size: Option<usize>,
+    /// Write the pairs in blocks per first word, with the word as a header followed by its
+    /// matches, instead of as a flat list. Only applies to the text format.
+    pub group_by_word: bool,
+    /// Add the Levenshtein edit operations turning the first word into the second word.
+    pub show_alignment: bool,
+    /// Stop writing with an `OutputTruncated` error before the file would exceed this size.
+    pub max_output_bytes: Option<u64>,
+    /// Append to the output file instead of replacing it.
+    pub append: bool,
+    /// The input line of every word, added to the row labels when words are extracted as tokens.
+    pub source_lines: Option<Vec<usize>>,
+    /// Also score the original words, before preprocessing, with this scorer and add the
+    /// result next to the similarity, to show how much the preprocessing changed it.
+    pub raw_scorer: Option<Scorer>,
+    /// Add the FNV-1a hash of the processed key of both words, to join pairs across runs in
+    /// which the row numbers differ.
+    pub with_hash: bool,
+    /// Name of the metric written next to the similarity of every pair, see
+    /// `Scorer::metric_name`, so the output of different runs can be told apart.
+    pub metric: Option<String>,
+    /// Name of the fallback metric, if pairs below the minimum match got a second chance
+    /// with it; every pair is then tagged with whether the fallback matched it.
+    pub fallback_metric: Option<String>,
+    /// Add the Levenshtein distance of the processed words, the number of edits behind the
+    /// normalized Levenshtein similarity.
+    pub with_edits: bool,
+    /// Prefix every pair with its 1-based rank among the pairs of its first word, which are
+    /// written best first, like `2.` for the second-best match of the word.
+    pub with_rank: bool,
+    /// End the lines with CRLF instead of LF, for tools on Windows that expect it.
+    pub crlf: bool,
+    /// The pairs are the partners selected for their first word, as with `top_k`, so the
+    /// adjacency format lists every pair only under its first word instead of under both.
+    pub partners_per_word: bool,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            format: OutputFormat::Text,
+            align: false,
+            extra_algorithms: Vec::new(),
+            ids: None,
+            permille: false,
+            batch_start: None,
+            write_buffer_size: None,
+            group_by_word: false,
+            show_alignment: false,
+            max_output_bytes: None,
+            append: false,
+            source_lines: None,
+            raw_scorer: None,
+            with_hash: false,
+            metric: None,
+            fallback_metric: None,
+            with_edits: false,
+            with_rank: false,
+            crlf: false,
+            partners_per_word: false,
+        }
+    }
+}
+
+/// A word from the input file together with the key it is compared by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Word {
+    /// The word as it appears in the input file, used for display.
+    pub original: String,
+    /// The preprocessed comparison key, or `None` if it is the same as the original.
+    processed: Option<String>,
+}
+
+impl Word {
+    /// Create a word from its original text and its preprocessed comparison key.
+    pub fn new(original: String, processed: String) -> Self {
+        // Don't keep a second copy of keys that preprocessing left unchanged
+        let processed = if processed == original { None } else { Some(processed) };
+        Word { original, processed }
+    }
+
+    /// Create a word that is compared as it is, without allocating a separate key.
+    pub fn unprocessed(original: String) -> Self {
+        Word { original, processed: None }
+    }
+
+    /// The key the word is compared by.
+    pub fn processed(&self) -> &str {
+        self.processed.as_deref().unwrap_or(&self.original)
+    }
+}
+
+/// Read the input file and return a vector of words.
+pub fn read_input_file(
+    input_path: &Path,
+    options: &InputOptions,
+) -> Result<Vec<Word>, InputError> {
+    read_file_words(input_path, options, MAX_WORDS).map(|(words, _, _)| words)
+}
+
+/// Read the input file like `read_input_file`, also returning the line number of every word
+/// and the number of words dropped for being empty after preprocessing.
+///
+/// The line numbers start at 1 and are only different from the word numbers with a token
+/// regex, a line range or dropped words.
+pub fn read_input_file_with_lines(
+    input_path: &Path,
+    options: &InputOptions,
+) -> Result<(Vec<Word>, Vec<usize>, usize), InputError> {
+    read_file_words(input_path, options, MAX_WORDS)
+}
+
+/// The maximum number of words in an input file.
+const MAX_WORDS: usize = 500_000;
+
+/// The minimum number of words in an input file, unless a single word is allowed.
+const MIN_WORDS: usize = 2;
+
+/// Read the words, their line numbers and the number of dropped empty words from any reader,
+/// like `read_input_file_with_lines`.
+///
+/// This is the parser behind the input files, for input that does not come from a file.
+pub fn read_input<R: BufRead>(
+    reader: R,
+    options: &InputOptions,
+) -> Result<(Vec<Word>, Vec<usize>, usize), InputError> {
+    read_reader_words(reader, options, MAX_WORDS)
+}
+
+/// Open the input file and read its words, accepting at most `max_words` words.
+fn read_file_words(
+    input_path: &Path,
+    options: &InputOptions,
+    max_words: usize,
+) -> Result<(Vec<Word>, Vec<usize>, usize), InputError> {
+    let file = File::open(input_path)?;
+    if options.mmap {
+        let map = mmap::map_file(&file)?;
+        let lines = mmap::byte_lines(&map).map(|line| decode_line(line, options));
+        return read_words(lines, options, max_words);
+    }
+    let reader = match options.read_buffer_size {
+        Some(capacity) => BufReader::with_capacity(capacity, file),
+        None => BufReader::new(file),
+    };
+    read_reader_words(reader, options, max_words)
+}
+
+/// Read the words from a reader, decoding another encoding or replacing invalid UTF-8 if the
+/// options ask for it.
+fn read_reader_words<R: BufRead>(
+    mut reader: R,
+    options: &InputOptions,
+    max_words: usize,
+) -> Result<(Vec<Word>, Vec<usize>, usize), InputError> {
+    if !options.lossy && options.encoding == InputEncoding::Utf8 {
+        return read_words(reader.lines().map(|line| line.map(Cow::Owned)), options, max_words);
+    }
+
+    // Like BufRead::lines, but on bytes, since invalid UTF-8 would end the lines. The encodings
+    // all encode a line feed as the single byte 0x0A, so the lines are split before decoding
+    let lines = std::iter::from_fn(move || {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.ends_with(b"\n") {
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                }
+                Some(Ok(Cow::Owned(encoding::decode(&line, options.encoding))))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    });
+    read_words(lines, options, max_words)
+}
+
+/// Decode a line of a mapped file, borrowing it unless it had to be decoded from another
+/// encoding or invalid UTF-8 had to be replaced.
+fn decode_line<'a>(line: &'a [u8], options: &InputOptions) -> io::Result<Cow<'a, str>> {
+    if options.encoding != InputEncoding::Utf8 {
+        return Ok(Cow::Owned(encoding::decode(line, options.encoding)));
+    }
+    if options.lossy {
+        return Ok(String::from_utf8_lossy(line));
+    }
+    std::str::from_utf8(line)
+        .map(Cow::Borrowed)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))
+}
+
+/// Read the words, their line numbers and the number of dropped empty words, accepting at
+/// most `max_words` words.
+fn read_words<'a, I>(
+    input_lines: I,
+    options: &InputOptions,
+    max_words: usize,
+) -> Result<(Vec<Word>, Vec<usize>, usize), InputError>
+where
+    I: Iterator<Item = io::Result<Cow<'a, str>>>,
+{
+    let preprocessing = &options.preprocessing;
+    let mut words = Vec::new();
+    let progress_bar = ProgressBar::new(0);
+    progress_bar.set_style(ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
+        .progress_chars("#>-"));
+    if options.hide_progress {
+        progress_bar.set_draw_target(ProgressDrawTarget::hidden());
+    } else if let Some(interval) = options.progress_interval {
+        progress_bar.set_draw_target(ProgressDrawTarget::stderr_with_hz(1000 / interval.max(1)));
+    }
+
+    let mut lines = Vec::new();
+    let mut dropped = 0;
+    let make_word = |text: String| {
+        if preprocessing.disabled {
+            Word::unprocessed(text)
+        } else {
+            let processed = match &options.record_fields {
+                Some(records) => records.preprocess(&text, preprocessing),
+                None => preprocessing.apply(&text),
+            };
+            Word::new(text, processed)
+        }
+    };
+    // Whether to keep a word, counting the dropped ones
+    let mut keep_word = |word: &Word, line: usize| -> Result<bool, InputError> {
+        if !word.processed().is_empty() {
+            return Ok(true);
+        }
+        match options.empty_policy {
+            EmptyPolicy::Drop => {
+                dropped += 1;
+                Ok(false)
+            }
+            EmptyPolicy::Error => Err(InputError::EmptyAfterPreprocessing { line }),
+            EmptyPolicy::Keep => Ok(true),
+        }
+    };
+
+    for (index, line) in input_lines.enumerate() {
+        if let Some(range) = &options.line_range {
+            if index + 1 > *range.end() {
+                break;
+            }
+            if index + 1 < *range.start() {
+                continue;
+            }
+        }
+        let mut line = line?;
+
+        // Files exported by Windows tools often start with a byte order mark
+        if index == 0 {
+            if let Some(rest) = line.strip_prefix('\u{feff}') {
+                line = Cow::Owned(rest.to_string());
+            }
+        }
+
+        if options.format == InputFormat::Json {
+            let tokens = json::parse_string_array(&line)
+                .map_err(|message| InputError::InvalidJson { line: index + 1, message })?;
+            line = Cow::Owned(tokens.join(" "));
+        }
+
+        // Extract the tokens as words if requested, otherwise use the whole line
+        if let Some(regex) = &options.token_regex {
+            for token in regex.find_iter(&line).filter(|token| !token.as_str().is_empty()) {
+                let word = make_word(token.as_str().to_string());
+                if keep_word(&word, index + 1)? {
+                    words.push(word);
+                    lines.push(index + 1);
+                }
+            }
+            progress_bar.set_length(words.len() as u64);
+            continue;
+        }
+
+        // Check for empty lines
+        if line.is_empty() {
+            return Err(InputError::EmptyLine { line: index + 1 });
+        }
+
+        if let Some(records) = &options.record_fields {
+            let fields = records.split(&line).len();
+            if fields < records.required_fields() {
+                return Err(InputError::MissingFields {
+                    line: index + 1,
+                    fields,
+                    required: records.required_fields(),
+                });
+            }
+        }
+
+        // Preprocess line and store original and processed word
+        let word = make_word(line.into_owned());
+        if keep_word(&word, index + 1)? {
+            words.push(word);
+            lines.push(index + 1);
+        }
+
+        progress_bar.set_length(words.len() as u64);
+    }
+
+    progress_bar.finish();
+
+    // Check for too few or too many words
+    let word_count = words.len();
+    let min_words = if options.allow_single_word { 1 } else { MIN_WORDS };
+    if word_count < min_words {
+        return Err(InputError::TooFewWords {
+            count: word_count,
+            min: min_words,
+            max: max_words,
+        });
+    }
+    if word_count > max_words {
+        return Err(InputError::TooManyWords {
+            count: word_count,
+            min: min_words,
+            max: max_words,
+        });
+    }
+
+    Ok((words, lines, dropped))
+}
+
+/// Mark the words whose key equals the key of a source word, returning the marks and the
+/// source words that do not match any word.
+///
+/// Every duplicate of a source word in the input is a source.
+pub fn match_sources<'a>(words: &[Word], sources: &'a [Word]) -> (Vec<bool>, Vec<&'a Word>) {
+    let keys: HashSet<&str> = words.iter().map(Word::processed).collect();
+    let source_keys: HashSet<&str> = sources.iter().map(Word::processed).collect();
+    let flags = words.iter().map(|word| source_keys.contains(word.processed())).collect();
+    let unmatched = sources.iter().filter(|source| !keys.contains(source.processed())).collect();
+    (flags, unmatched)
+}
+
+/// Put the source word of every pair first, as the word the pair was found for.
+pub fn orient_to_sources(pairs: &mut [SimilarityPair], sources: &[bool]) {
+    for pair in pairs {
+        if !sources[pair.index_a()] && sources[pair.index_b()] {
+            *pair = pair.with_indices(pair.index_b(), pair.index_a());
+        }
+    }
+}
+
+/// Progress callback receiving the number of finished rows and the total number of rows.
+pub type ProgressCallback<'a> = &'a mut (dyn FnMut(usize, usize) + Send);
+
+/// Read a file with one ID per line, matching the words of the input file in order.
+pub fn read_ids_file(path: &Path, word_count: usize) -> Result<Vec<String>, InputError> {
+    let file = File::open(path)?;
+    let ids = BufReader::new(file).lines().collect::<Result<Vec<String>, _>>()?;
+
+    if ids.len() != word_count {
+        return Err(InputError::IdCountMismatch {
+            ids: ids.len(),
+            words: word_count,
+        });
+    }
+
+    Ok(ids)
+}
+
+/// Serializes the calls to a progress callback from the rows computed in parallel.
+struct RowProgress<'a> {
+    callback: Option<Mutex<ProgressCallback<'a>>>,
+    done: AtomicUsize,
+    total: usize,
+}
+
+impl<'a> RowProgress<'a> {
+    fn new(callback: Option<ProgressCallback<'a>>, total: usize) -> Self {
+        RowProgress {
+            callback: callback.map(Mutex::new),
+            done: AtomicUsize::new(0),
+            total,
+        }
+    }
+
+    /// Report that one more row is finished.
+    fn row_done(&self) {
+        if let Some(callback) = &self.callback {
+            let mut callback = callback.lock().unwrap();
+            (*callback)(self.done.fetch_add(1, Ordering::Relaxed) + 1, self.total);
+        }
+    }
+}
+
+/// Matrix value of the pairs that were skipped by the comparison filter.
+///
+/// NaN never compares as at or above a minimum match, so skipped pairs are never collected.
+pub const NOT_COMPARED: f64 = f64::NAN;
+
+/// Decides which pairs of words are compared at all.
+#[derive(Clone, Debug, Default)]
+pub struct ComparisonFilter {
+    /// Index of the first word of a new batch appended after the canonical words. If set,
+    /// only pairs with at least one word from the batch are compared.
+    pub batch_start: Option<usize>,
+    /// Stop comparing a word to the remaining words once it has enough good matches.
+    pub match_budget: Option<MatchBudget>,
+    /// Only compare words that are at most this many rows apart, for sorted input.
+    pub window: Option<usize>,
+    /// Measure the window in these positions of the words instead of in rows, such as their
+    /// positions in sorted order from `sorted_positions`.
+    pub window_positions: Option<Vec<usize>>,
+    /// Counts of the comparisons performed and skipped with this filter.
+    pub counts: ComparisonCounts,
+    /// Look up the similarities in this cache before computing them, and record the ones
+    /// that had to be computed.
+    pub cache: Option<Arc<SimilarityCache>>,
+    /// Minimum match per length of the shorter word, checked on top of the match range when
+    /// the pairs are collected.
+    pub length_thresholds: Option<LengthThresholds>,
+    /// Record the time spent on every row, for finding the words that are slow to compare.
+    pub row_times: Option<Arc<RowTimes>>,
+    /// Break the ties between pairs of equal similarity kept by `calculate_top_pairs` in a
+    /// random order given by this seed, instead of by their indices; see `shuffle_ties`.
+    pub tie_seed: Option<u64>,
+    /// Skip the pairs of words that only differ in a trailing number, like "Widget" and
+    /// "Widget 2", which are usually distinct variants rather than duplicates.
+    pub distinct_numeric_suffix: bool,
+    /// Skip the pairs whose processed words do not start with the same this many characters.
+    pub min_prefix: Option<usize>,
+    /// Give the pairs below the minimum match a second chance with this algorithm when the
+    /// pairs are collected from the matrix.
+    pub fallback: Option<Fallback>,
+    /// Whether every word is a source, see `match_sources`. If set, only pairs with at least
+    /// one source are compared, and with `top_k` only the sources get partners.
+    pub sources: Option<Vec<bool>>,
+}
+
+/// Counters of the comparisons performed and skipped, updated once per row.
+#[derive(Debug, Default)]
+pub struct ComparisonCounts {
+    performed: AtomicU64,
+    pruned: AtomicU64,
+}
+
+impl ComparisonCounts {
+    /// Number of pairs of different words that were scored, counting both orders.
+    pub fn performed(&self) -> u64 {
+        self.performed.load(Ordering::Relaxed)
+    }
+
+    /// Number of pairs that were skipped by the filter or the match budget.
+    pub fn pruned(&self) -> u64 {
+        self.pruned.load(Ordering::Relaxed)
+    }
+
+    fn add_row(&self, performed: usize, pruned: usize) {
+        self.performed.fetch_add(performed as u64, Ordering::Relaxed);
+        self.pruned.fetch_add(pruned as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clone for ComparisonCounts {
+    fn clone(&self) -> Self {
+        ComparisonCounts {
+            performed: AtomicU64::new(self.performed()),
+            pruned: AtomicU64::new(self.pruned()),
+        }
+    }
+}
+
+/// Wall time spent on every row of the matrix, indexed by row.
+///
+/// Every row is timed by the thread computing it and stored in its own slot, so recording
+/// the times does not serialize the rows.
+#[derive(Debug)]
+pub struct RowTimes {
+    nanos: Vec<AtomicU64>,
+}
+
+impl RowTimes {
+    /// Create the slots for a matrix with this many rows, all zero until the rows are done.
+    pub fn new(rows: usize) -> Self {
+        RowTimes {
+            nanos: (0..rows).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn record(&self, row: usize, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos[row].store(nanos, Ordering::Relaxed);
+    }
+
+    /// The `count` slowest rows with their times, slowest first; ties go to the lower row.
+    pub fn slowest(&self, count: usize) -> Vec<(usize, Duration)> {
+        let mut rows: Vec<(usize, u64)> = self
+            .nanos
+            .iter()
+            .map(|nanos| nanos.load(Ordering::Relaxed))
+            .enumerate()
+            .collect();
+        rows.sort_by(|(row_a, a), (row_b, b)| b.cmp(a).then(row_a.cmp(row_b)));
+        rows.truncate(count);
+        rows.into_iter()
+            .map(|(row, nanos)| (row, Duration::from_nanos(nanos)))
+            .collect()
+    }
+}
+
+/// An early termination rule: once a word has `matches` partners scoring at or above
+/// `good_enough`, its remaining comparisons are skipped.
+///
+/// This is approximate, since a skipped word may have been a better match than the ones found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MatchBudget {
+    pub good_enough: f64,
+    pub matches: usize,
+}
+
+/// Minimum matches per length of the shorter word of a pair, parsed from a specification
+/// like `1-4:95,5-10:85,11-:80` with the lengths in characters and the percentages.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LengthThresholds {
+    /// Inclusive length ranges, with no upper bound if `None`, and their minimum match.
+    buckets: Vec<(usize, Option<usize>, f64)>,
+    /// Minimum match for the lengths outside every range.
+    fallback: f64,
+}
+
+impl LengthThresholds {
+    /// Parse the specification, using the fallback minimum match for the lengths it does not
+    /// cover. The ranges must not overlap.
+    pub fn parse(spec: &str, fallback: f64) -> Result<Self, String> {
+        let mut buckets = Vec::new();
+        for bucket in spec.split(',').map(str::trim) {
+            let (lengths, percentage) = bucket
+                .split_once(':')
+                .ok_or_else(|| format!("Expected LENGTHS:PERCENTAGE, got '{}'", bucket))?;
+            let length = |text: &str| {
+                text.trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid length in '{}'", bucket))
+            };
+            let (min, max) = match lengths.split_once('-') {
+                Some((min, max)) if max.trim().is_empty() => (length(min)?, None),
+                Some((min, max)) => (length(min)?, Some(length(max)?)),
+                None => (length(lengths)?, Some(length(lengths)?)),
+            };
+            if max.is_some_and(|max| max < min) {
+                return Err(format!("Empty length range in '{}'", bucket));
+            }
+            let threshold = match percentage.trim().parse::<f64>() {
+                Ok(percentage) if (0.0..=100.0).contains(&percentage) => percentage / 100.0,
+                _ => return Err(format!("Invalid percentage in '{}'", bucket)),
+            };
+            buckets.push((min, max, threshold));
+        }
+
+        buckets.sort_by_key(|&(min, _, _)| min);
+        for pair in buckets.windows(2) {
+            if pair[0].1.is_none_or(|max| max >= pair[1].0) {
+                return Err(format!("The length ranges overlap at length {}", pair[1].0));
+            }
+        }
+        Ok(LengthThresholds { buckets, fallback })
+    }
+
+    /// The minimum match for a pair whose shorter word has this many characters.
+    pub fn threshold(&self, length: usize) -> f64 {
+        self.buckets
+            .iter()
+            .find(|&&(min, max, _)| length >= min && max.is_none_or(|max| length <= max))
+            .map_or(self.fallback, |&(_, _, threshold)| threshold)
+    }
+
+    /// The lowest minimum match of any length, which every pair has to reach.
+    pub fn lowest(&self) -> f64 {
+        self.buckets
+            .iter()
+            .map(|&(_, _, threshold)| threshold)
+            .fold(self.fallback, f64::min)
+    }
+}
+
+impl ComparisonFilter {
+    /// Check whether a similarity reaches the length threshold of the pair, if there are any.
+    pub fn meets_length_threshold(&self, words: &[Word], pair: &SimilarityPair) -> bool {
+        match &self.length_thresholds {
+            Some(thresholds) => {
+                let length = |index: usize| words[index].processed().chars().count();
+                let shorter = length(pair.index_a()).min(length(pair.index_b()));
+                pair.similarity >= thresholds.threshold(shorter)
+            }
+            None => true,
+        }
+    }
+
+    /// Check whether the words at the two indices should be compared.
+    pub fn should_compare(&self, index_a: usize, index_b: usize) -> bool {
+        if let Some(batch_start) = self.batch_start {
+            if index_a < batch_start && index_b < batch_start {
+                return false;
+            }
+        }
+        if let Some(window) = self.window {
+            let distance = match &self.window_positions {
+                Some(positions) => positions[index_a].abs_diff(positions[index_b]),
+                None => index_a.abs_diff(index_b),
+            };
+            if distance > window {
+                return false;
+            }
+        }
+        if let Some(sources) = &self.sources {
+            if !sources[index_a] && !sources[index_b] {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check whether two different words pass the checks on their processed keys, before
+    /// they are scored.
+    pub fn passes_word_gates(&self, a: &str, b: &str) -> bool {
+        if self.distinct_numeric_suffix && differs_only_in_numeric_suffix(a, b) {
+            return false;
+        }
+        if let Some(length) = self.min_prefix {
+            let (mut a, mut b) = (a.chars(), b.chars());
+            // Words shorter than the prefix cannot share it
+            for _ in 0..length {
+                match (a.next(), b.next()) {
+                    (Some(x), Some(y)) if x == y => {}
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+
+    /// Check whether a row of `calculate_top_pairs` selects partners for its word.
+    pub(crate) fn selects_partners(&self, index: usize) -> bool {
+        self.sources.as_ref().is_none_or(|sources| sources[index])
+    }
+}
+
+/// The position of every word when the words are sorted by their processed key, for the
+/// Sorted Neighborhood Method of record linkage: near-duplicates tend to sort close to each
+/// other, so a window over the sorted words finds most of them. Equal keys keep their order.
+pub fn sorted_positions(words: &[Word]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..words.len()).collect();
+    order.sort_by(|&a, &b| words[a].processed().cmp(words[b].processed()));
+    let mut positions = vec![0; words.len()];
+    for (position, index) in order.into_iter().enumerate() {
+        positions[index] = position;
+    }
+    positions
+}
+
+/// Check whether two words are the same except for a trailing number, like "A" and "A 2" or
+/// "A1" and "A2". Identical words do not count, and neither do words that are only a number.
+pub fn differs_only_in_numeric_suffix(a: &str, b: &str) -> bool {
+    fn stem(word: &str) -> &str {
+        word.trim_end_matches(|c: char| c.is_ascii_digit()).trim_end()
+    }
+    let ends_in_digit = |word: &str| word.ends_with(|c: char| c.is_ascii_digit());
+
+    a != b && (ends_in_digit(a) || ends_in_digit(b)) && !stem(a).is_empty() && stem(a) == stem(b)
+}
+
+/// Calculate the similarities of one word to all words, including itself.
+///
+/// When the match budget of the filter is used up, the rest of the row is not compared.
+fn similarity_row(
+    words: &[Word],
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    index: usize,
+) -> Vec<f64> {
+    let row_start = filter.row_times.is_some().then(Instant::now);
+    let word1 = &words[index];
+    let mut row = vec![NOT_COMPARED; words.len()];
+    let mut good_matches = 0;
+    let mut performed = 0;
+    let mut computed = Vec::new();
+    for (index2, word2) in words.iter().enumerate() {
+        if !filter.should_compare(index, index2) {
+            continue;
+        }
+        if index2 != index && !filter.passes_word_gates(word1.processed(), word2.processed()) {
+            continue;
+        }
+        let similarity = if index2 != index {
+            cached_similarity(scorer, filter, word1, word2, &mut computed)
+        } else {
+            // Comparing a word with itself is not worth a lookup
+            scorer.similarity(word1.processed(), word2.processed())
+        };
+        row[index2] = similarity;
+        if index2 != index {
+            performed += 1;
+        }
+
+        if let Some(budget) = filter.match_budget {
+            if index2 != index && similarity >= budget.good_enough {
+                good_matches += 1;
+                if good_matches >= budget.matches {
+                    break;
+                }
+            }
+        }
+    }
+    filter.counts.add_row(performed, words.len() - 1 - performed);
+    if let Some(cache) = &filter.cache {
+        cache.add(computed);
+    }
+    if let (Some(row_times), Some(row_start)) = (&filter.row_times, row_start) {
+        row_times.record(index, row_start.elapsed());
+    }
+    row
+}
+
+/// Score two words, looking the similarity up in the cache of the filter if it has one and
+/// recording it in `computed` if it had to be computed.
+fn cached_similarity(
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    word1: &Word,
+    word2: &Word,
+    computed: &mut Vec<(u64, f64)>,
+) -> f64 {
+    match &filter.cache {
+        Some(cache) => {
+            let key = cache.key(word1.processed(), word2.processed());
+            cache.get(key).unwrap_or_else(|| {
+                let similarity = scorer.similarity(word1.processed(), word2.processed());
+                computed.push((key, similarity));
+                similarity
+            })
+        }
+        None => scorer.similarity(word1.processed(), word2.processed()),
+    }
+}
+
+/// Calculate the similarity matrix using the given scorer.
+///
+/// Pairs rejected by the filter are not scored and get the value `NOT_COMPARED`. If a
+/// progress callback is given, it is called once for every finished row. The calls are
+/// serialized, so the callback does not need to be thread-safe itself.
+pub fn calculate_similarity_matrix(
+    words: &[Word],
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    progress: Option<ProgressCallback>,
+) -> Vec<Vec<f64>> {
+    let progress = RowProgress::new(progress, words.len());
+
+    (0..words.len())
+        .into_par_iter()
+        .map(|index| {
+            let row = similarity_row(words, scorer, filter, index);
+            progress.row_done();
+            row
+        })
+        .collect::<Vec<Vec<f64>>>()
+}
+
+/// Calculate the pairs within the match range for a filter with a window, comparing every
+/// word only with the words at most `window` rows after it, or positions with
+/// `window_positions`, without building the matrix.
+///
+/// This takes time and memory linear in the number of words for a fixed window, where the
+/// matrix is quadratic in both. Every pair is scored once, with the earlier word in the
+/// input first, and the match budget of the filter counts the matches of a word with the
+/// words after it in the window order. The pairs are collected like `collect_pairs` and
+/// `fallback_pairs` do, best first, and the comparison counts count every pair once. The
+/// progress callback is called like in `calculate_similarity_matrix`.
+///
+/// # Panics
+///
+/// If the filter has no window.
+pub fn calculate_window_pairs(
+    words: &[Word],
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    match_range: &RangeInclusive<f64>,
+    progress: Option<ProgressCallback>,
+) -> Vec<SimilarityPair> {
+    let window = filter.window.expect("calculate_window_pairs needs a window");
+    let progress = RowProgress::new(progress, words.len());
+
+    // The words in the order the window slides over
+    let order: Vec<usize> = match &filter.window_positions {
+        Some(positions) => {
+            let mut order = vec![0; words.len()];
+            for (index, &position) in positions.iter().enumerate() {
+                order[position] = index;
+            }
+            order
+        }
+        None => (0..words.len()).collect(),
+    };
+
+    let mut pairs: Vec<SimilarityPair> = (0..words.len())
+        .into_par_iter()
+        .flat_map_iter(|position| {
+            let row_start = filter.row_times.is_some().then(Instant::now);
+            let index = order[position];
+            let end = position.saturating_add(window).min(words.len() - 1);
+            let mut row_pairs = Vec::new();
+            let mut good_matches = 0;
+            let mut performed = 0;
+            let mut computed = Vec::new();
+            for &index2 in &order[position + 1..=end] {
+                let (a, b) = (index.min(index2), index.max(index2));
+                let (word_a, word_b) = (&words[a], &words[b]);
+                if !filter.should_compare(a, b) || !filter.passes_word_gates(word_a.processed(), word_b.processed()) {
+                    continue;
+                }
+                let similarity = cached_similarity(scorer, filter, word_a, word_b, &mut computed);
+                performed += 1;
+
+                if match_range.contains(&similarity) {
+                    row_pairs.push(SimilarityPair::new(similarity, a, b));
+                } else if let Some(fallback) = filter.fallback.as_ref().filter(|_| similarity < *match_range.start()) {
+                    let similarity = fallback.scorer.similarity(word_a.processed(), word_b.processed());
+                    if (fallback.min_match..=*match_range.end()).contains(&similarity) {
+                        row_pairs.push(SimilarityPair::fallback(similarity, a, b));
+                    }
+                }
+
+                if let Some(budget) = filter.match_budget {
+                    if similarity >= budget.good_enough {
+                        good_matches += 1;
+                        if good_matches >= budget.matches {
+                            break;
+                        }
+                    }
+                }
+            }
+            filter.counts.add_row(performed, words.len() - 1 - position - performed);
+            if let Some(cache) = &filter.cache {
+                cache.add(computed);
+            }
+            if let (Some(row_times), Some(row_start)) = (&filter.row_times, row_start) {
+                row_times.record(index, row_start.elapsed());
+            }
+            progress.row_done();
+            row_pairs
+        })
+        .collect();
+
+    // Ties go to the lower indices like in `collect_pairs`, with the pairs of the first
+    // algorithm ahead of the fallback pairs
+    pairs.sort_by(|a, b| {
+        b.similarity
+            .total_cmp(&a.similarity)
+            .then(a.is_fallback().cmp(&b.is_fallback()))
+            .then((a.index_a(), a.index_b()).cmp(&(b.index_a(), b.index_b())))
+    });
+    pairs
+}
+
+/// A pair ordered by how good a match it is, for keeping the best pairs in a heap.
+///
+/// Higher similarities are better; ties go to the lower tie key, which is 0 unless the ties
+/// are shuffled, and then to the lower indices, like in `collect_pairs`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RankedPair {
+    pub(crate) pair: SimilarityPair,
+    tie: u64,
+}
+
+impl RankedPair {
+    pub(crate) fn new(pair: SimilarityPair, tie_seed: Option<u64>) -> Self {
+        RankedPair {
+            pair,
+            tie: tie_seed.map_or(0, |seed| tie_key(seed, &pair)),
+        }
+    }
+}
+
+impl Ord for RankedPair {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.pair
+            .similarity
+            .total_cmp(&other.pair.similarity)
+            .then_with(|| other.tie.cmp(&self.tie))
+            .then_with(|| other.pair.index_a().cmp(&self.pair.index_a()))
+            .then_with(|| other.pair.index_b().cmp(&self.pair.index_b()))
+    }
+}
+
+impl PartialOrd for RankedPair {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedPair {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for RankedPair {}
+
+/// A heap keeping only the `capacity` best pairs pushed into it.
+struct BoundedHeap {
+    // Reversed, so the worst pair kept is on top
+    heap: BinaryHeap<Reverse<RankedPair>>,
+    capacity: usize,
+    tie_seed: Option<u64>,
+}
+
+impl BoundedHeap {
+    fn new(capacity: usize, tie_seed: Option<u64>) -> Self {
+        BoundedHeap {
+            heap: BinaryHeap::with_capacity(capacity.min(1024) + 1),
+            capacity,
+            tie_seed,
+        }
+    }
+
+    fn push(&mut self, pair: SimilarityPair) {
+        self.push_ranked(RankedPair::new(pair, self.tie_seed));
+    }
+
+    fn push_ranked(&mut self, ranked: RankedPair) {
+        if self.heap.len() < self.capacity {
+            self.heap.push(Reverse(ranked));
+        } else if let Some(Reverse(worst)) = self.heap.peek() {
+            if ranked > *worst {
+                self.heap.pop();
+                self.heap.push(Reverse(ranked));
+            }
+        }
+    }
+
+    fn merge(mut self, other: BoundedHeap) -> Self {
+        for Reverse(ranked) in other.heap {
+            self.push_ranked(ranked);
+        }
+        self
+    }
+
+    /// The pairs kept, best first.
+    fn into_sorted_vec(self) -> Vec<SimilarityPair> {
+        // Sorting the reversed pairs ascending puts the best pair first
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(ranked)| ranked.pair)
+            .collect()
+    }
+}
+
+/// The key ordering a pair among the pairs of equal similarity when the ties are shuffled.
+///
+/// It only depends on the seed and the indices, so the order is the same however the pairs
+/// were found, both in the heaps of `calculate_top_pairs` and in `shuffle_ties`.
+fn tie_key(seed: u64, pair: &SimilarityPair) -> u64 {
+    let indices = ((pair.index_a() as u64) << 32) | pair.index_b() as u64;
+    mix64(mix64(seed) ^ indices)
+}
+
+/// The SplitMix64 finalizer, which spreads every bit of the input over the whole output.
+fn mix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Order the pairs by descending similarity and the pairs of equal similarity in a random
+/// order given by the seed, instead of by their indices.
+///
+/// The same seed gives the same order, so taking the first pairs is a reproducible random
+/// sample of the ties.
+pub fn shuffle_ties(pairs: &mut [SimilarityPair], seed: u64) {
+    pairs.sort_by_cached_key(|pair| (Reverse(TotalOrder(pair.similarity)), tie_key(seed, pair)));
+}
+
+/// An `f64` ordered by `total_cmp`, for use in sort keys.
+#[derive(Clone, Copy, Debug)]
+struct TotalOrder(f64);
+
+impl Ord for TotalOrder {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl PartialOrd for TotalOrder {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for TotalOrder {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for TotalOrder {}
+
+/// Calculate only the best pairs within the match range, without building the matrix.
+///
+/// With `top_k`, every word keeps only its `top_k` best partners, looking at all other words;
+/// `index_a` is then the word the partner was selected for, so a pair can appear in both
+/// directions. Without it, every pair is considered once with `index_a` smaller than `index_b`.
+/// With `limit`, only the `limit` best pairs overall are kept.
+///
+/// Every worker thread keeps its best pairs in a bounded heap while the rows are computed, and
+/// the heaps are merged at the end. Memory therefore stays proportional to the number of pairs
+/// kept instead of the number of words squared. The pairs are returned sorted like
+/// `collect_pairs` does. Progress is reported like in `calculate_similarity_matrix`.
+pub fn calculate_top_pairs(
+    words: &[Word],
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    match_range: &RangeInclusive<f64>,
+    top_k: Option<usize>,
+    limit: Option<usize>,
+    progress: Option<ProgressCallback>,
+) -> Vec<SimilarityPair> {
+    let progress = RowProgress::new(progress, words.len());
+    let capacity = limit.unwrap_or(usize::MAX);
+
+    (0..words.len())
+        .into_par_iter()
+        .fold(
+            || BoundedHeap::new(capacity, filter.tie_seed),
+            |mut heap, index_a| {
+                // The rows of words that are not sources are only candidates with top_k
+                if top_k.is_some() && !filter.selects_partners(index_a) {
+                    progress.row_done();
+                    return heap;
+                }
+                let row = similarity_row(words, scorer, filter, index_a);
+                let candidates = row.iter().enumerate().filter(|(index_b, value)| {
+                    let partner = match top_k {
+                        Some(_) => *index_b != index_a,
+                        None => *index_b > index_a,
+                    };
+                    partner && match_range.contains(*value)
+                });
+                let candidates = candidates
+                    .map(|(index_b, value)| SimilarityPair::new(*value, index_a, index_b))
+                    .filter(|pair| filter.meets_length_threshold(words, pair));
+
+                match top_k {
+                    Some(k) => {
+                        let mut row_heap = BoundedHeap::new(k, filter.tie_seed);
+                        candidates.for_each(|pair| row_heap.push(pair));
+                        heap = heap.merge(row_heap);
+                    }
+                    None => candidates.for_each(|pair| heap.push(pair)),
+                }
+
+                progress.row_done();
+                heap
+            },
+        )
+        .reduce(|| BoundedHeap::new(capacity, filter.tie_seed), BoundedHeap::merge)
+        .into_sorted_vec()
+}
+
+/// Count the word pairs at or above each of the given thresholds.
+pub fn count_pairs(matrix: &[Vec<f64>], thresholds: &[f64]) -> Vec<usize> {
+    let mut counts = vec![0; thresholds.len()];
+
+    for (i, row) in matrix.iter().enumerate() {
+        // Only count each pair once, like collect_pairs does
+        for value in row.iter().skip(i + 1) {
+            for (count, threshold) in counts.iter_mut().zip(thresholds) {
+                if *value >= *threshold {
+                    *count += 1;
+                }
+            }
+        }
+    }
+
+    counts
+}
+
+/// A pair of words at or above the minimum match, referring to the words by their index.
+///
+/// The words are only looked up when the pair is written, and the indices are stored as `u32`,
+/// so a pair takes 16 bytes instead of 24. With N words there can be up to N * (N - 1) / 2
+/// pairs: at a low minimum match, 100,000 words can still produce about 5 billion pairs, or
+/// 80 GB, on top of the matrix. Use `calculate_top_pairs` to keep the pairs bounded instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimilarityPair {
+    pub similarity: f64,
+    index_a: u32,
+    /// The index of the second word, with `FALLBACK_FLAG` set for fallback pairs.
+    index_b: u32,
+}
+
+/// The bit of `SimilarityPair::index_b` marking a pair found by the fallback algorithm,
+/// which the limit of `MAX_WORDS` words leaves free.
+const FALLBACK_FLAG: u32 = 1 << 31;
+
+impl SimilarityPair {
+    /// Create a pair of the words at the given indices.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an index does not fit in 31 bits, which the limit of `MAX_WORDS` words rules
+    /// out for word lists read with `read_input_file`.
+    pub fn new(similarity: f64, index_a: usize, index_b: usize) -> Self {
+        let index = |index: usize| match u32::try_from(index) {
+            Ok(index) if index < FALLBACK_FLAG => index,
+            _ => panic!("word index does not fit in 31 bits"),
+        };
+        SimilarityPair {
+            similarity,
+            index_a: index(index_a),
+            index_b: index(index_b),
+        }
+    }
+
+    /// Create a pair that failed the minimum match of the algorithm and passed with the
+    /// similarity of the fallback algorithm, see `fallback_pairs`.
+    pub fn fallback(similarity: f64, index_a: usize, index_b: usize) -> Self {
+        let mut pair = SimilarityPair::new(similarity, index_a, index_b);
+        pair.index_b |= FALLBACK_FLAG;
+        pair
+    }
+
+    /// The same pair for the words at other indices, still marked if it is a fallback pair.
+    pub fn with_indices(&self, index_a: usize, index_b: usize) -> Self {
+        if self.is_fallback() {
+            SimilarityPair::fallback(self.similarity, index_a, index_b)
+        } else {
+            SimilarityPair::new(self.similarity, index_a, index_b)
+        }
+    }
+
+    /// Index of the first word.
+    pub fn index_a(&self) -> usize {
+        self.index_a as usize
+    }
+
+    /// Index of the second word.
+    pub fn index_b(&self) -> usize {
+        (self.index_b & !FALLBACK_FLAG) as usize
+    }
+
+    /// Whether the similarity is that of the fallback algorithm.
+    pub fn is_fallback(&self) -> bool {
+        self.index_b & FALLBACK_FLAG != 0
+    }
+}
+
+/// Collect the pairs within the match range, sorted in descending order of similarity.
+///
+/// Every pair is only collected once, with `index_a` smaller than `index_b`. Use a range
+/// like `min_match..=f64::INFINITY` to collect all pairs at or above the minimum match.
+pub fn collect_pairs(matrix: &[Vec<f64>], match_range: &RangeInclusive<f64>) -> Vec<SimilarityPair> {
+    let mut all_pairs = Vec::new();
+
+    for (i, row) in matrix.iter().enumerate() {
+        for (j, value) in row.iter().enumerate() {
+            if i >= j {
+                continue; // Skip if the index of the first word is greater than or equal to the index of the second word
+            }
+            if match_range.contains(value) {
+                all_pairs.push(SimilarityPair::new(*value, i, j));
+            }
+        }
+    }
+
+    // Sort pairs in descending order of similarity
+    all_pairs.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+
+    all_pairs
+}
+
+/// A second, more lenient algorithm for the pairs below the minimum match of the first one.
+#[derive(Clone, Debug)]
+pub struct Fallback {
+    pub scorer: Scorer,
+    /// Minimum match of the fallback algorithm.
+    pub min_match: f64,
+}
+
+/// Score the compared pairs below the match range again with the fallback algorithm, and
+/// collect the ones that reach its minimum match as fallback pairs, sorted like
+/// `collect_pairs` does.
+///
+/// A fallback pair still has to stay within the upper end of the match range.
+pub fn fallback_pairs(
+    matrix: &[Vec<f64>],
+    words: &[Word],
+    fallback: &Fallback,
+    match_range: &RangeInclusive<f64>,
+) -> Vec<SimilarityPair> {
+    let threshold = fallback.min_match..=*match_range.end();
+    let mut pairs: Vec<SimilarityPair> = matrix
+        .par_iter()
+        .enumerate()
+        .flat_map_iter(|(i, row)| {
+            let threshold = &threshold;
+            row.iter().enumerate().skip(i + 1).filter_map(move |(j, &value)| {
+                // Pairs that were not compared stay that way
+                if value.is_nan() || value >= *match_range.start() {
+                    return None;
+                }
+                let similarity = fallback.scorer.similarity(words[i].processed(), words[j].processed());
+                threshold.contains(&similarity).then(|| SimilarityPair::fallback(similarity, i, j))
+            })
+        })
+        .collect();
+    pairs.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    pairs
+}
+
+/// Keep at most `max_matches` pairs per word, for limiting the output of words that match
+/// many others.
+///
+/// The pairs are taken in the order given, which is best first for the pairs returned by
+/// `collect_pairs` and `calculate_top_pairs`. A pair is kept only if neither of its words has
+/// reached the cap yet, so a pair that would push either word over its cap is dropped, and the
+/// other word keeps its room for later pairs.
+pub fn cap_matches_per_word(
+    pairs: Vec<SimilarityPair>,
+    word_count: usize,
+    max_matches: usize,
+) -> Vec<SimilarityPair> {
+    let mut matches = vec![0; word_count];
+    pairs
+        .into_iter()
+        .filter(|pair| {
+            let (a, b) = (pair.index_a(), pair.index_b());
+            if matches[a] >= max_matches || matches[b] >= max_matches {
+                return false;
+            }
+            matches[a] += 1;
+            matches[b] += 1;
+            true
+        })
+        .collect()
+}
+
+/// Words grouped by their aggressively normalized key, see `group_exact_duplicates`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExactGroups {
+    /// Index of the first word of every group, in input order.
+    pub representatives: Vec<usize>,
+    /// A pair at 100% for every two words in the same group, with `index_a` smaller than
+    /// `index_b`.
+    pub pairs: Vec<SimilarityPair>,
+}
+
+impl ExactGroups {
+    /// Combine the exact pairs with the pairs computed for the representatives.
+    ///
+    /// The indices of the fuzzy pairs refer to the representatives and are mapped back to the
+    /// words. The exact pairs within the match range come first, followed by the fuzzy pairs
+    /// in descending order of similarity.
+    pub fn merge(
+        &self,
+        fuzzy: Vec<SimilarityPair>,
+        match_range: &RangeInclusive<f64>,
+    ) -> Vec<SimilarityPair> {
+        let exact = self.pairs.iter().copied();
+        let exact = exact.filter(|pair| match_range.contains(&pair.similarity));
+        let fuzzy = fuzzy.into_iter().map(|pair| {
+            pair.with_indices(self.representatives[pair.index_a()], self.representatives[pair.index_b()])
+        });
+        let mut pairs: Vec<SimilarityPair> = exact.chain(fuzzy).collect();
+        // Stable, so exact pairs stay ahead of fuzzy pairs that also score 100%
+        pairs.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        pairs
+    }
+}
+
+/// Group the words whose keys are equal after lowercasing, stripping accents and collapsing
+/// whitespace, on top of the preprocessing already applied.
+///
+/// Only the representatives need to be compared fuzzily, since the other words of a group
+/// are exact duplicates of them.
+pub fn group_exact_duplicates(words: &[Word]) -> ExactGroups {
+    let normalization = Preprocessing {
+        strip_accents: true,
+        collapse_whitespace: true,
+        ..Preprocessing::default()
+    };
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of_key = HashMap::new();
+    for (index, word) in words.iter().enumerate() {
+        let key = normalization.apply(word.processed());
+        let group = *group_of_key.entry(key).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(index);
+    }
+
+    let mut exact = ExactGroups::default();
+    for group in &groups {
+        exact.representatives.push(group[0]);
+        for (position, &index_a) in group.iter().enumerate() {
+            for &index_b in &group[position + 1..] {
+                exact.pairs.push(SimilarityPair::new(1.0, index_a, index_b));
+            }
+        }
+    }
+    exact
+}
+
+/// Number of pairs written between two explicit flushes of the output file.
+const WRITE_BATCH_SIZE: usize = 100_000;
+
+/// Write the pairs, as returned by `collect_pairs`, to the output file.
+///
+/// The pairs are written in batches, flushing the file after each batch. If a progress
+/// callback is given, it is called after every batch with the pairs written so far.
+pub fn write_output_file(
+    path: &Path,
+    all_pairs: &[SimilarityPair],
+    words: &[Word],
+    options: &OutputOptions,
+    mut progress: Option<ProgressCallback>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = if options.append {
+        OpenOptions::new().append(true).create(true).open(path)?
+    } else {
+        File::create(path)?
+    };
+    let mut writer = match options.write_buffer_size {
+        Some(capacity) => BufWriter::with_capacity(capacity, file),
+        None => BufWriter::new(file),
+    };
+
+    let pair_count = all_pairs.len();
+
+    // Grouping keeps the descending order within every group, since the sort is stable
+    let grouped;
+    let group_by_word = options.group_by_word && options.format == OutputFormat::Text;
+    let all_pairs = if group_by_word {
+        let mut pairs = all_pairs.to_vec();
+        pairs.sort_by_key(|pair| pair.index_a());
+        grouped = pairs;
+        &grouped[..]
+    } else {
+        all_pairs
+    };
+
+    // Aligned output needs the column widths before the first line is written
+    let widths = if options.align && options.format == OutputFormat::Text {
+        Some(column_widths(all_pairs, words, options))
+    } else {
+        None
+    };
+
+    // Ranks count the pairs of every first word in output order, so ties keep their order
+    let ranks = options.with_rank.then(|| pair_ranks(all_pairs, words.len()));
+    let rank_width = match (&ranks, widths) {
+        (Some(ranks), Some(_)) => ranks.iter().max().map_or(0, |rank| rank.to_string().len()),
+        _ => 0,
+    };
+
+    // Every chunk of output is formatted first, so the size cap never cuts a line in half
+    let mut output = CappedOutput {
+        writer: &mut writer,
+        chunk: Vec::new(),
+        bytes: 0,
+        max_bytes: options.max_output_bytes,
+        crlf: options.crlf,
+    };
+
+    if options.format == OutputFormat::Adjacency {
+        write_adjacency_lists(&mut output, all_pairs, words, options)?;
+        writer.flush()?;
+        if let Some(progress) = progress.as_mut() {
+            progress(pair_count, pair_count);
+        }
+        return Ok(());
+    }
+
+    if options.format == OutputFormat::Dot {
+        write_dot_header(&mut output.chunk, words, options)?;
+        output.commit(0, pair_count)?;
+    }
+
+    // Write sorted pairs to the output file
+    let mut written = 0;
+    let mut current_group = None;
+    for batch in all_pairs.chunks(WRITE_BATCH_SIZE) {
+        for (position, pair) in batch.iter().enumerate() {
+            let line = &mut output.chunk;
+            let rank = ranks.as_ref().map(|ranks| ranks[written + position]);
+            let rank_label = rank.map_or_else(String::new, |rank| format!("{:>width$}. ", rank, width = rank_width));
+            match options.format {
+                OutputFormat::Text if group_by_word => {
+                    if current_group != Some(pair.index_a()) {
+                        if current_group.is_some() {
+                            writeln!(line)?;
+                        }
+                        writeln!(line, "{}", word_column(pair.index_a(), words, options))?;
+                        current_group = Some(pair.index_a());
+                    }
+                    write_grouped_pair(line, pair, words, options, widths, &rank_label)?
+                }
+                OutputFormat::Text => write_pair(line, pair, words, options, widths, &rank_label)?,
+                OutputFormat::Dot => write_dot_edge(line, pair, options)?,
+                OutputFormat::Jsonl => write_json_pair(line, pair, words, options, rank)?,
+                OutputFormat::Adjacency => unreachable!("adjacency lists are written per word"),
+            }
+            output.commit(written + position, pair_count)?;
+        }
+        output.writer.flush()?;
+
+        written += batch.len();
+        if let Some(progress) = progress.as_mut() {
+            progress(written, pair_count);
+        }
+    }
+
+    if options.format == OutputFormat::Dot {
+        writeln!(output.chunk, "}}")?;
+        output.commit(pair_count, pair_count)?;
+    }
+
+    // Flush explicitly, since dropping the writer would swallow any error
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Write a line per word with matches, listing its partners best first, like
+/// `hello: hallo(0.8000), helo(0.8000)`, or with the similarities in integer permille.
+///
+/// The words are named by their ID if IDs are given, quoted if they contain the separators
+/// of the format. Every pair is listed under both of its words, unless the pairs are the
+/// partners selected per word.
+fn write_adjacency_lists<W: Write>(
+    output: &mut CappedOutput<W>,
+    pairs: &[SimilarityPair],
+    words: &[Word],
+    options: &OutputOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut neighbors: Vec<Vec<(usize, f64)>> = vec![Vec::new(); words.len()];
+    for pair in pairs {
+        neighbors[pair.index_a()].push((pair.index_b(), pair.similarity));
+        if !options.partners_per_word {
+            neighbors[pair.index_b()].push((pair.index_a(), pair.similarity));
+        }
+    }
+
+    let name = |index: usize| match &options.ids {
+        Some(ids) => adjacency_name(&ids[index]),
+        None => adjacency_name(&words[index].original),
+    };
+    let mut listed = 0;
+    for (index, partners) in neighbors.iter().enumerate().filter(|(_, partners)| !partners.is_empty()) {
+        write!(output.chunk, "{}:", name(index))?;
+        for (position, (partner, similarity)) in partners.iter().enumerate() {
+            let separator = if position == 0 { " " } else { ", " };
+            let similarity = if options.permille {
+                ((similarity * 1000.0).round() as u32).to_string()
+            } else {
+                format!("{:.4}", similarity)
+            };
+            write!(output.chunk, "{}{}({})", separator, name(*partner), similarity)?;
+        }
+        writeln!(output.chunk)?;
+        output.commit(listed, pairs.len())?;
+        // A pair is written with the first of its words that gets a line
+        listed += partners
+            .iter()
+            .filter(|(partner, _)| options.partners_per_word || *partner > index)
+            .count();
+    }
+    Ok(())
+}
+
+/// Quote a word of an adjacency list in double quotes if it contains a comma, colon,
+/// parenthesis, double quote or backslash, escaping the last two with a backslash.
+fn adjacency_name(name: &str) -> Cow<'_, str> {
+    if name.contains([',', ':', '(', ')', '"', '\\']) {
+        // The escapes are the same as in GraphViz IDs
+        Cow::Owned(format!("\"{}\"", escape_dot(name)))
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// The 1-based rank of every pair among the pairs with the same first word, in their order.
+fn pair_ranks(pairs: &[SimilarityPair], word_count: usize) -> Vec<usize> {
+    let mut counts = vec![0; word_count];
+    pairs
+        .iter()
+        .map(|pair| {
+            counts[pair.index_a()] += 1;
+            counts[pair.index_a()]
+        })
+        .collect()
+}
+
+/// Compute the widths in characters of the two word columns in aligned output.
+fn column_widths(
+    pairs: &[SimilarityPair],
+    words: &[Word],
+    options: &OutputOptions,
+) -> (usize, usize) {
+    pairs.iter().fold((0, 0), |(width1, width2), pair| {
+        (
+            width1.max(word_column(pair.index_a(), words, options).chars().count()),
+            width2.max(word_column(pair.index_b(), words, options).chars().count()),
+        )
+    })
+}
+
+/// Format the label of a word: its ID if IDs are given, otherwise its 1-based row number.
+pub fn row_label(index: usize, options: &OutputOptions) -> String {
+    match (&options.ids, options.batch_start) {
+        (Some(ids), _) => ids[index].clone(),
+        (None, Some(batch_start)) if index >= batch_start => {
+            format!("Batch row {}", index - batch_start + 1)
+        }
+        (None, _) => match &options.source_lines {
+            Some(lines) => format!("Row {} (line {})", index + 1, lines[index]),
+            None => format!("Row {}", index + 1),
+        },
+    }
+}
+
+/// Describe whether a pair matches a batch word against a canonical word or another batch word.
+fn batch_match_kind(pair: &SimilarityPair, batch_start: usize) -> &'static str {
+    if pair.index_a() >= batch_start && pair.index_b() >= batch_start {
+        "batch-internal"
+    } else {
+        "batch-canonical"
+    }
+}
+
+/// Format a similarity as a percentage with two decimals, or as integer permille.
+pub fn format_similarity(similarity: f64, options: &OutputOptions) -> String {
+    if options.permille {
+        format!("{}‰", (similarity * 1000.0).round() as u32)
+    } else {
+        format!("{:.2}%", similarity * 100.0)
+    }
+}
+
+/// Format the column identifying a word by its label and original text.
+pub fn word_column(index: usize, words: &[Word], options: &OutputOptions) -> String {
+    format!("{}: {}", row_label(index, options), words[index].original)
+}
+
+/// Hash bytes with the 64-bit FNV-1a hash, which is stable across runs and platforms.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    fnv1a_extend(OFFSET_BASIS, bytes)
+}
+
+/// Continue an FNV-1a hash with more bytes, to hash several values without concatenating them.
+pub fn fnv1a_extend(hash: u64, bytes: &[u8]) -> u64 {
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes
+        .iter()
+        .fold(hash, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(PRIME))
+}
+
+/// The path of a shard file: the output path with the shard number appended, e.g. `out.txt.0`.
+pub fn shard_path(path: &Path, shard: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", shard));
+    PathBuf::from(name)
+}
+
+/// Write the pairs to `shards` files, assigning every pair by the hash of its two words.
+///
+/// The shards are written in parallel, and each one is a complete file in the chosen format
+/// with its pairs in the same order as in `all_pairs`. The progress callback is called once
+/// for every finished shard.
+pub fn write_output_shards(
+    path: &Path,
+    all_pairs: &[SimilarityPair],
+    words: &[Word],
+    options: &OutputOptions,
+    shards: usize,
+    progress: Option<ProgressCallback>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut shard_pairs = vec![Vec::new(); shards];
+    for pair in all_pairs {
+        let mut key = words[pair.index_a()].original.as_bytes().to_vec();
+        key.push(0xff); // Not valid in UTF-8, so the words cannot run into each other
+        key.extend_from_slice(words[pair.index_b()].original.as_bytes());
+        shard_pairs[(fnv1a(&key) % shards as u64) as usize].push(*pair);
+    }
+
+    /// A shard error that can be sent back from the worker threads.
+    enum ShardFailure {
+        Truncated(OutputTruncated),
+        Other(String),
+    }
+
+    let progress = RowProgress::new(progress, shards);
+    let results: Vec<Result<(), ShardFailure>> = shard_pairs
+        .par_iter()
+        .enumerate()
+        .map(|(shard, pairs)| {
+            let result = write_output_file(&shard_path(path, shard), pairs, words, options, None);
+            progress.row_done();
+            result.map_err(|e| match e.downcast::<OutputTruncated>() {
+                Ok(truncated) => ShardFailure::Truncated(*truncated),
+                Err(e) => ShardFailure::Other(format!("Shard {}: {}", shard, e)),
+            })
+        })
+        .collect();
+
+    match results.into_iter().find_map(Result::err) {
+        Some(ShardFailure::Truncated(truncated)) => Err(Box::new(truncated)),
+        Some(ShardFailure::Other(message)) => Err(message.into()),
+        None => Ok(()),
+    }
+}
+
+/// A writer that only writes complete chunks of output as long as they fit in the size cap.
+struct CappedOutput<'a, W: Write> {
+    writer: &'a mut W,
+    /// The chunk being formatted, written by `commit`.
+    chunk: Vec<u8>,
+    bytes: u64,
+    max_bytes: Option<u64>,
+    /// Turn the LF line endings of every chunk into CRLF before it is written.
+    crlf: bool,
+}
+
+impl<'a, W: Write> CappedOutput<'a, W> {
+    /// Write the formatted chunk, or fail with `OutputTruncated` if it would exceed the cap.
+    fn commit(
+        &mut self,
+        pairs_written: usize,
+        pairs_total: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Words never contain line breaks, so every LF in the chunk ends a line
+        if self.crlf {
+            let mut chunk = Vec::with_capacity(self.chunk.len() + 2);
+            for &byte in &self.chunk {
+                if byte == b'\n' {
+                    chunk.push(b'\r');
+                }
+                chunk.push(byte);
+            }
+            self.chunk = chunk;
+        }
+        let bytes = self.bytes + self.chunk.len() as u64;
+        if let Some(max_bytes) = self.max_bytes {
+            if bytes > max_bytes {
+                self.writer.flush()?;
+                return Err(Box::new(OutputTruncated {
+                    max_bytes,
+                    pairs_written,
+                    pairs_total,
+                }));
+            }
+        }
+        self.writer.write_all(&self.chunk)?;
+        self.chunk.clear();
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+/// Write a single pair after its rank label, padding the word columns if column widths are given.
+fn write_pair<W: Write>(
+    writer: &mut W,
+    pair: &SimilarityPair,
+    words: &[Word],
+    options: &OutputOptions,
+    widths: Option<(usize, usize)>,
+    rank_label: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match widths {
+        Some((width1, width2)) => writeln!(
+            writer,
+            "{}{:<width1$}  {:<width2$}  Similarity: {:>7}{}{}{}{}{}{}{}{}",
+            rank_label,
+            word_column(pair.index_a(), words, options),
+            word_column(pair.index_b(), words, options),
+            format_similarity(pair.similarity, options),
+            metric(pair, options, "  "),
+            fallback_tag(pair, options, "  "),
+            edits(pair, words, options, "  "),
+            raw_score(pair, words, options, "  "),
+            extra_scores(pair, words, options, "  "),
+            match_kind(pair, options, "  "),
+            alignment(pair, words, options, "  "),
+            hashes(pair, words, options, "  "),
+            width1 = width1,
+            width2 = width2
+        )?,
+        None => writeln!(
+            writer,
+            "{}{}\t{}\tSimilarity: {}{}{}{}{}{}{}{}{}",
+            rank_label,
+            word_column(pair.index_a(), words, options),
+            word_column(pair.index_b(), words, options),
+            format_similarity(pair.similarity, options),
+            metric(pair, options, "\t"),
+            fallback_tag(pair, options, "\t"),
+            edits(pair, words, options, "\t"),
+            raw_score(pair, words, options, "\t"),
+            extra_scores(pair, words, options, "\t"),
+            match_kind(pair, options, "\t"),
+            alignment(pair, words, options, "\t"),
+            hashes(pair, words, options, "\t")
+        )?,
+    }
+
+    Ok(())
+}
+
+/// Write a pair as an indented match below the header of its first word, after its rank label.
+fn write_grouped_pair<W: Write>(
+    writer: &mut W,
+    pair: &SimilarityPair,
+    words: &[Word],
+    options: &OutputOptions,
+    widths: Option<(usize, usize)>,
+    rank_label: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match widths {
+        Some((_, width2)) => writeln!(
+            writer,
+            "    {}{:<width2$}  Similarity: {:>7}{}{}{}{}{}{}{}{}",
+            rank_label,
+            word_column(pair.index_b(), words, options),
+            format_similarity(pair.similarity, options),
+            metric(pair, options, "  "),
+            fallback_tag(pair, options, "  "),
+            edits(pair, words, options, "  "),
+            raw_score(pair, words, options, "  "),
+            extra_scores(pair, words, options, "  "),
+            match_kind(pair, options, "  "),
+            alignment(pair, words, options, "  "),
+            hashes(pair, words, options, "  "),
+            width2 = width2
+        )?,
+        None => writeln!(
+            writer,
+            "    {}{}\tSimilarity: {}{}{}{}{}{}{}{}{}",
+            rank_label,
+            word_column(pair.index_b(), words, options),
+            format_similarity(pair.similarity, options),
+            metric(pair, options, "\t"),
+            fallback_tag(pair, options, "\t"),
+            edits(pair, words, options, "\t"),
+            raw_score(pair, words, options, "\t"),
+            extra_scores(pair, words, options, "\t"),
+            match_kind(pair, options, "\t"),
+            alignment(pair, words, options, "\t"),
+            hashes(pair, words, options, "\t")
+        )?,
+    }
+
+    Ok(())
+}
+
+/// Write the start of a GraphViz graph, with a node labeled with the original text for every word.
+fn write_dot_header<W: Write>(
+    writer: &mut W,
+    words: &[Word],
+    options: &OutputOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    writeln!(writer, "graph similarity {{")?;
+    for (index, word) in words.iter().enumerate() {
+        writeln!(
+            writer,
+            "    {} [label=\"{}\"];",
+            dot_node(index, options),
+            escape_dot(&word.original)
+        )?;
+    }
+    Ok(())
+}
+
+/// Write a pair as a GraphViz edge weighted and labeled with its similarity.
+fn write_dot_edge<W: Write>(
+    writer: &mut W,
+    pair: &SimilarityPair,
+    options: &OutputOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    writeln!(
+        writer,
+        "    {} -- {} [label=\"{}\", weight={:.4}];",
+        dot_node(pair.index_a(), options),
+        dot_node(pair.index_b(), options),
+        format_similarity(pair.similarity, options),
+        pair.similarity
+    )?;
+    Ok(())
+}
+
+/// Write a pair as a JSON object on a single line.
+///
+/// Rows are numbered from 1 within their own file, like the `Row N` labels of the text format.
+/// The rank of the pair, if given, comes first.
+fn write_json_pair<W: Write>(
+    writer: &mut W,
+    pair: &SimilarityPair,
+    words: &[Word],
+    options: &OutputOptions,
+    rank: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut fields = Vec::new();
+    if let Some(rank) = rank {
+        fields.push(format!("\"rank\":{}", rank));
+    }
+    for (suffix, index) in [("a", pair.index_a()), ("b", pair.index_b())] {
+        fields.push(format!("\"row_{}\":{}", suffix, json_row(index, options)));
+        if let Some(lines) = &options.source_lines {
+            fields.push(format!("\"line_{}\":{}", suffix, lines[index]));
+        }
+        if let Some(ids) = &options.ids {
+            fields.push(format!("\"id_{}\":{}", suffix, json_string(&ids[index])));
+        }
+        fields.push(format!("\"word_{}\":{}", suffix, json_string(&words[index].original)));
+        if options.with_hash {
+            fields.push(format!("\"hash_{}\":\"{}\"", suffix, word_hash(&words[index])));
+        }
+    }
+    fields.push(format!("\"similarity\":{}", json_similarity(pair.similarity, options)));
+    if let Some(metric) = &options.metric {
+        fields.push(format!("\"metric\":{}", json_string(pair_metric(pair, metric, options))));
+    }
+    if options.fallback_metric.is_some() {
+        fields.push(format!("\"fallback\":{}", pair.is_fallback()));
+    }
+    if options.with_edits {
+        fields.push(format!("\"edits\":{}", edit_count(pair, words)));
+    }
+    if let Some(scorer) = &options.raw_scorer {
+        let raw = scorer.similarity(&words[pair.index_a()].original, &words[pair.index_b()].original);
+        fields.push(format!("\"raw_similarity\":{}", json_similarity(raw, options)));
+    }
+
+    let processed_word1 = words[pair.index_a()].processed();
+    let processed_word2 = words[pair.index_b()].processed();
+    for algorithm in &options.extra_algorithms {
+        let similarity = algorithm.similarity(processed_word1, processed_word2);
+        let similarity = json_similarity(similarity, options);
+        fields.push(format!("{}:{}", json_string(algorithm.name()), similarity));
+    }
+    if let Some(batch_start) = options.batch_start {
+        fields.push(format!("\"match\":\"{}\"", batch_match_kind(pair, batch_start)));
+    }
+
+    writeln!(writer, "{{{}}}", fields.join(","))?;
+    Ok(())
+}
+
+/// The row number of a word in its own file, starting at 1.
+fn json_row(index: usize, options: &OutputOptions) -> usize {
+    match options.batch_start {
+        Some(batch_start) if index >= batch_start => index - batch_start + 1,
+        _ => index + 1,
+    }
+}
+
+/// Format a similarity as a JSON number: the fraction, or integer permille if requested.
+fn json_similarity(similarity: f64, options: &OutputOptions) -> String {
+    if options.permille {
+        ((similarity * 1000.0).round() as u32).to_string()
+    } else {
+        similarity.to_string()
+    }
+}
+
+/// Quote and escape a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if u32::from(c) < 0x20 => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The GraphViz node ID of a word: its quoted ID if IDs are given, otherwise its row number.
+fn dot_node(index: usize, options: &OutputOptions) -> String {
+    match &options.ids {
+        Some(ids) => format!("\"{}\"", escape_dot(&ids[index])),
+        None => (index + 1).to_string(),
+    }
+}
+
+/// Escape a string for use inside a double-quoted GraphViz ID.
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Format the batch match kind column of a pair, preceded by the separator, in batch mode.
+fn match_kind(pair: &SimilarityPair, options: &OutputOptions, separator: &str) -> String {
+    match options.batch_start {
+        Some(batch_start) => {
+            format!("{}Match: {}", separator, batch_match_kind(pair, batch_start))
+        }
+        None => String::new(),
+    }
+}
+
+/// Format the alignment column of a pair, preceded by the separator, if requested.
+///
+/// The alignment is computed on the processed words, since those are what is compared.
+fn alignment(
+    pair: &SimilarityPair,
+    words: &[Word],
+    options: &OutputOptions,
+    separator: &str,
+) -> String {
+    if !options.show_alignment {
+        return String::new();
+    }
+    let operations = metrics::levenshtein_alignment(
+        words[pair.index_a()].processed(),
+        words[pair.index_b()].processed(),
+    );
+    let operations: Vec<String> = operations.iter().map(ToString::to_string).collect();
+    format!("{}Alignment: {}", separator, operations.join(" "))
+}
+
+/// Format the metric column preceded by the separator, if requested.
+fn metric(pair: &SimilarityPair, options: &OutputOptions, separator: &str) -> String {
+    match &options.metric {
+        Some(metric) => format!("{}Metric: {}", separator, pair_metric(pair, metric, options)),
+        None => String::new(),
+    }
+}
+
+/// The name of the metric behind the similarity of a pair, given the name of the first one.
+fn pair_metric<'a>(pair: &SimilarityPair, metric: &'a str, options: &'a OutputOptions) -> &'a str {
+    match &options.fallback_metric {
+        Some(fallback) if pair.is_fallback() => fallback,
+        _ => metric,
+    }
+}
+
+/// Format the column telling whether the fallback algorithm matched a pair, preceded by the
+/// separator, if a fallback is used.
+fn fallback_tag(pair: &SimilarityPair, options: &OutputOptions, separator: &str) -> String {
+    match options.fallback_metric {
+        Some(_) => format!("{}Fallback: {}", separator, if pair.is_fallback() { "yes" } else { "no" }),
+        None => String::new(),
+    }
+}
+
+/// Format the Levenshtein distance of the processed words of a pair, preceded by the
+/// separator, if requested.
+fn edits(pair: &SimilarityPair, words: &[Word], options: &OutputOptions, separator: &str) -> String {
+    if !options.with_edits {
+        return String::new();
+    }
+    format!("{}Edits: {}", separator, edit_count(pair, words))
+}
+
+/// The number of edits turning the processed first word of a pair into the second word.
+fn edit_count(pair: &SimilarityPair, words: &[Word]) -> usize {
+    strsim::levenshtein(words[pair.index_a()].processed(), words[pair.index_b()].processed())
+}
+
+/// Format the similarity of the original words of a pair, preceded by the separator, if requested.
+fn raw_score(pair: &SimilarityPair, words: &[Word], options: &OutputOptions, separator: &str) -> String {
+    match &options.raw_scorer {
+        Some(scorer) => {
+            let raw = scorer.similarity(&words[pair.index_a()].original, &words[pair.index_b()].original);
+            format!("{}Raw: {}", separator, format_similarity(raw, options))
+        }
+        None => String::new(),
+    }
+}
+
+/// Format the hash columns of both words of a pair, each preceded by the separator, if requested.
+fn hashes(pair: &SimilarityPair, words: &[Word], options: &OutputOptions, separator: &str) -> String {
+    if !options.with_hash {
+        return String::new();
+    }
+    format!(
+        "{}Hash A: {}{}Hash B: {}",
+        separator,
+        word_hash(&words[pair.index_a()]),
+        separator,
+        word_hash(&words[pair.index_b()])
+    )
+}
+
+/// The FNV-1a hash of the processed key of a word, as 16 hex digits.
+///
+/// The key is hashed rather than the original word, so words that are compared as equal get
+/// the same hash.
+fn word_hash(word: &Word) -> String {
+    format!("{:016x}", fnv1a(word.processed().as_bytes()))
+}
+
+/// Format the scores of the extra algorithms for a pair, each preceded by the separator.
+fn extra_scores(
+    pair: &SimilarityPair,
+    words: &[Word],
+    options: &OutputOptions,
+    separator: &str,
+) -> String {
+    let processed_word1 = words[pair.index_a()].processed();
+    let processed_word2 = words[pair.index_b()].processed();
+
+    options
+        .extra_algorithms
+        .iter()
+        .map(|algorithm| {
+            format!(
+                "{}{}: {}",
+                separator,
+                algorithm,
+                format_similarity(algorithm.similarity(processed_word1, processed_word2), options)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    #[test]
+    fn reads_two_words() {
+        let words = read_input_file(&fixture("two_words.txt"), &InputOptions::default()).unwrap();
+        let originals: Vec<&str> = words.iter().map(|word| word.original.as_str()).collect();
+        assert_eq!(originals, ["hello", "world"]);
+    }
+
+    #[test]
+    fn joins_json_tokens_into_words() {
+        let options = InputOptions {
+            format: InputFormat::Json,
+            ..InputOptions::default()
+        };
+        let (words, _, _) = read_input(&b"[\"New\", \"York\"]\n[\"york\"]\n"[..], &options).unwrap();
+        assert_eq!(words[0], Word::new("New York".to_string(), "new york".to_string()));
+        assert_eq!(words[1].original, "york");
+
+        let error = read_input(&b"[\"a\"]\n[\"b\" 2]\n"[..], &options).unwrap_err();
+        assert!(matches!(error, InputError::InvalidJson { line: 2, .. }), "{:?}", error);
+        let error = read_input(&b"[\"a\"]\n[]\n"[..], &options).unwrap_err();
+        assert!(matches!(error, InputError::EmptyLine { line: 2 }));
+    }
+
+    #[test]
+    fn strips_byte_order_mark() {
+        let words = read_input_file(&fixture("bom.txt"), &InputOptions::default()).unwrap();
+        assert_eq!(words[0].original, "hello");
+        assert_eq!(SimilarityAlgorithm::Levenshtein.similarity(words[0].processed(), "hello"), 1.0);
+
+        // Only a mark at the start of the file is stripped
+        let (words, _, _) = read_input("a\n\u{feff}b\n".as_bytes(), &InputOptions::default()).unwrap();
+        assert_eq!(words[1].original, "\u{feff}b");
+    }
+
+    #[test]
+    fn reads_a_slice_of_the_lines() {
+        let read = |range: RangeInclusive<usize>| {
+            let options = InputOptions {
+                line_range: Some(range),
+                ..InputOptions::default()
+            };
+            read_input("a\n\nb\nc\nd\n".as_bytes(), &options)
+        };
+
+        // The empty line before the slice does not count, and the words keep their line numbers
+        let (words, lines, _) = read(3..=4).unwrap();
+        assert_eq!(words, [Word::unprocessed("b".to_string()), Word::unprocessed("c".to_string())]);
+        assert_eq!(lines, [3, 4]);
+
+        assert!(matches!(read(2..=3), Err(InputError::EmptyLine { line: 2 })));
+        assert!(matches!(read(5..=9), Err(InputError::TooFewWords { count: 1, .. })));
+    }
+
+    #[test]
+    fn maps_input_and_replaces_invalid_utf8_if_lossy() {
+        let path = fixture("latin1.txt");
+        for mmap in [false, true] {
+            let options = InputOptions {
+                mmap,
+                ..InputOptions::default()
+            };
+            let error = read_input_file(&path, &options).unwrap_err();
+            assert!(matches!(error, InputError::Io(ref e) if e.kind() == std::io::ErrorKind::InvalidData));
+
+            let options = InputOptions {
+                mmap,
+                lossy: true,
+                ..InputOptions::default()
+            };
+            let words = read_input_file(&path, &options).unwrap();
+            let originals: Vec<&str> = words.iter().map(|word| word.original.as_str()).collect();
+            assert_eq!(originals, ["caf\u{fffd}", "hello"]);
+        }
+
+        let options = InputOptions {
+            mmap: true,
+            ..InputOptions::default()
+        };
+        let words = read_input_file(&fixture("bom.txt"), &options).unwrap();
+        assert_eq!(words[0].original, "hello");
+    }
+
+    #[test]
+    fn decodes_legacy_encodings() {
+        for mmap in [false, true] {
+            let options = InputOptions {
+                mmap,
+                encoding: InputEncoding::Latin1,
+                ..InputOptions::default()
+            };
+            let words = read_input_file(&fixture("latin1.txt"), &options).unwrap();
+            let originals: Vec<&str> = words.iter().map(|word| word.original.as_str()).collect();
+            assert_eq!(originals, ["café", "hello"]);
+        }
+
+        let options = InputOptions {
+            encoding: InputEncoding::Windows1252,
+            ..InputOptions::default()
+        };
+        let (words, _, _) = read_input(&b"\x80 5\r\nna\xefve\n"[..], &options).unwrap();
+        assert_eq!(words[0].original, "€ 5");
+        assert_eq!(words[1].original, "naïve");
+    }
+
+    #[test]
+    fn rejects_empty_line() {
+        let error = read_input_file(&fixture("empty_line.txt"), &InputOptions::default()).unwrap_err();
+        assert!(matches!(error, InputError::EmptyLine { line: 2 }));
+        assert_eq!(error.to_string(), "Empty lines are not allowed in the input file (line 2)");
+    }
+
+    #[test]
+    fn rejects_single_word_unless_allowed() {
+        let path = fixture("single_word.txt");
+        let error = read_input_file(&path, &InputOptions::default()).unwrap_err();
+        assert!(matches!(error, InputError::TooFewWords { count: 1, min: 2, .. }));
+        assert!(error.to_string().starts_with("Invalid number of words: 1."));
+
+        let options = InputOptions {
+            allow_single_word: true,
+            ..InputOptions::default()
+        };
+        assert_eq!(read_input_file(&path, &options).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_too_many_words() {
+        let path = fixture("unicode.txt");
+        let error = read_file_words(&path, &InputOptions::default(), 2).unwrap_err();
+        assert!(matches!(error, InputError::TooManyWords { count: 3, max: 2, .. }));
+        assert_eq!(
+            error.to_string(),
+            "Invalid number of words: 3. The input file must contain between 2 and 2 words."
+        );
+        assert_eq!(read_file_words(&path, &InputOptions::default(), 3).unwrap().0.len(), 3);
+    }
+
+    #[test]
+    fn reports_missing_file_as_io_error() {
+        let error = read_input_file(&fixture("missing.txt"), &InputOptions::default()).unwrap_err();
+        assert!(matches!(error, InputError::Io(_)));
+        assert!(!error.is_invalid_contents());
+    }
+
+    #[test]
+    fn reads_from_any_reader_and_rejects_invalid_utf8() {
+        let (words, lines, _) = read_input(&b"hello\nworld\n"[..], &InputOptions::default()).unwrap();
+        assert_eq!(words.len(), 2);
+        assert_eq!(lines, [1, 2]);
+
+        let error = read_input(&b"hello\n\xffworld\n"[..], &InputOptions::default()).unwrap_err();
+        assert!(matches!(error, InputError::Io(_)));
+    }
+
+    #[test]
+    fn extracts_tokens_with_their_lines() {
+        let options = InputOptions {
+            token_regex: Some(Regex::new(r"\w+").unwrap()),
+            ..InputOptions::default()
+        };
+        let (words, lines, _) = read_input_file_with_lines(&fixture("empty_line.txt"), &options).unwrap();
+        let originals: Vec<&str> = words.iter().map(|word| word.original.as_str()).collect();
+        assert_eq!(originals, ["hello", "world"]);
+        assert_eq!(lines, [1, 3]);
+    }
+
+    #[test]
+    fn applies_the_empty_policy() {
+        let read = |empty_policy: EmptyPolicy| {
+            let options = InputOptions {
+                preprocessing: Preprocessing {
+                    strip_punctuation: true,
+                    ..Preprocessing::default()
+                },
+                empty_policy,
+                ..InputOptions::default()
+            };
+            read_input("hello\n!!!\nworld\n?\n".as_bytes(), &options)
+        };
+
+        let (words, lines, dropped) = read(EmptyPolicy::Drop).unwrap();
+        assert_eq!((words.len(), lines, dropped), (2, vec![1, 3], 2));
+        assert!(matches!(read(EmptyPolicy::Error), Err(InputError::EmptyAfterPreprocessing { line: 2 })));
+        let (words, _, dropped) = read(EmptyPolicy::Keep).unwrap();
+        assert_eq!((words[1].processed(), dropped), ("", 0));
+    }
+
+    #[test]
+    fn lowercases_unicode_and_keeps_original() {
+        let words = read_input_file(&fixture("unicode.txt"), &InputOptions::default()).unwrap();
+        let processed: Vec<&str> = words.iter().map(Word::processed).collect();
+        assert_eq!(processed, ["école", "straße", "σοφία"]);
+        assert_eq!(words[0].original, "ÉCOLE");
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let words = vec![
+            Word::new("Say \"hi\"".to_string(), "say \"hi\"".to_string()),
+            Word::new("say\thi\\".to_string(), "say\thi\\".to_string()),
+        ];
+        let pair = SimilarityPair::new(0.5, 0, 1);
+        let options = OutputOptions {
+            format: OutputFormat::Jsonl,
+            extra_algorithms: vec![SimilarityAlgorithm::Jaro],
+            ..OutputOptions::default()
+        };
+
+        let mut line = Vec::new();
+        write_json_pair(&mut line, &pair, &words, &options, None).unwrap();
+        let jaro = SimilarityAlgorithm::Jaro.similarity(words[0].processed(), words[1].processed());
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            format!(
+                "{{\"row_a\":1,\"word_a\":\"Say \\\"hi\\\"\",\"row_b\":2,\"word_b\":\"say\\thi\\\\\",\"similarity\":0.5,\"jaro\":{}}}\n",
+                jaro
+            )
+        );
+    }
+
+    #[test]
+    fn writes_raw_similarity_of_original_words() {
+        let words = vec![
+            Word::new("ABC".to_string(), "abc".to_string()),
+            Word::new("abc".to_string(), "abc".to_string()),
+        ];
+        let options = OutputOptions {
+            raw_scorer: Some(Scorer::default()),
+            ..OutputOptions::default()
+        };
+
+        let mut line = Vec::new();
+        write_pair(&mut line, &SimilarityPair::new(1.0, 0, 1), &words, &options, None, "").unwrap();
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "Row 1: ABC\tRow 2: abc\tSimilarity: 100.00%\tRaw: 0.00%\n"
+        );
+    }
+
+    #[test]
+    fn writes_hashes_of_processed_keys() {
+        let words = vec![
+            Word::new("HELLO".to_string(), "hello".to_string()),
+            Word::unprocessed("hello".to_string()),
+        ];
+        let options = OutputOptions {
+            with_hash: true,
+            ..OutputOptions::default()
+        };
+
+        let mut line = Vec::new();
+        write_pair(&mut line, &SimilarityPair::new(1.0, 0, 1), &words, &options, None, "").unwrap();
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "Row 1: HELLO\tRow 2: hello\tSimilarity: 100.00%\tHash A: a430d84680aabd0b\tHash B: a430d84680aabd0b\n"
+        );
+    }
+
+    #[test]
+    fn writes_metric_column() {
+        let words = vec![Word::unprocessed("hello".to_string()), Word::unprocessed("hallo".to_string())];
+        let records = RecordFields::parse("1:1,2:1:jaro-winkler", ',').unwrap();
+        let scorer = Scorer {
+            record_fields: Some(records),
+            ..Scorer::default()
+        };
+        let options = OutputOptions {
+            metric: Some(scorer.metric_name()),
+            ..OutputOptions::default()
+        };
+
+        let mut line = Vec::new();
+        write_pair(&mut line, &SimilarityPair::new(0.8, 0, 1), &words, &options, None, "").unwrap();
+        assert_eq!(
+            String::from_utf8(line).unwrap(),
+            "Row 1: hello\tRow 2: hallo\tSimilarity: 80.00%\tMetric: fields(1:levenshtein,2:jaro-winkler)\n"
+        );
+
+        let mut line = Vec::new();
+        let options = OutputOptions {
+            metric: Some(Scorer::default().metric_name()),
+            ..OutputOptions::default()
+        };
+        write_json_pair(&mut line, &SimilarityPair::new(0.8, 0, 1), &words, &options, None).unwrap();
+        assert!(String::from_utf8(line).unwrap().contains(r#""similarity":0.8,"metric":"levenshtein""#));
+    }
+
+    #[test]
+    fn writes_edit_counts_matching_the_normalization() {
+        let words: Vec<Word> = ["kitten", "sitting", "hello", "help"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let options = OutputOptions {
+            with_edits: true,
+            ..OutputOptions::default()
+        };
+        for (a, b, edits) in [(0, 1, 3), (2, 3, 2), (0, 0, 0)] {
+            let similarity = SimilarityAlgorithm::Levenshtein.similarity(words[a].processed(), words[b].processed());
+            let pair = SimilarityPair::new(similarity, a, b);
+            assert_eq!(edit_count(&pair, &words), edits);
+            let max_len = words[a].original.len().max(words[b].original.len());
+            assert!((similarity - (1.0 - edits as f64 / max_len as f64)).abs() < 1e-12);
+        }
+
+        let mut line = Vec::new();
+        write_pair(&mut line, &SimilarityPair::new(0.5, 2, 3), &words, &options, None, "").unwrap();
+        assert_eq!(String::from_utf8(line).unwrap(), "Row 3: hello\tRow 4: help\tSimilarity: 50.00%\tEdits: 2\n");
+    }
+
+    #[test]
+    fn ranks_pairs_within_their_first_word() {
+        let pairs = [
+            SimilarityPair::new(0.9, 1, 2),
+            SimilarityPair::new(0.8, 0, 1),
+            SimilarityPair::new(0.8, 1, 0),
+            SimilarityPair::new(0.7, 1, 3),
+        ];
+        assert_eq!(pair_ranks(&pairs, 4), [1, 1, 2, 3]);
+
+        let words: Vec<Word> = ["hello", "hallo"].iter().map(|word| Word::unprocessed(word.to_string())).collect();
+        let options = OutputOptions {
+            with_rank: true,
+            ..OutputOptions::default()
+        };
+        let mut line = Vec::new();
+        write_grouped_pair(&mut line, &SimilarityPair::new(0.8, 0, 1), &words, &options, None, "2. ").unwrap();
+        assert_eq!(String::from_utf8(line).unwrap(), "    2. Row 2: hallo\tSimilarity: 80.00%\n");
+
+        let mut line = Vec::new();
+        write_json_pair(&mut line, &SimilarityPair::new(0.8, 0, 1), &words, &options, Some(2)).unwrap();
+        assert!(String::from_utf8(line).unwrap().starts_with(r#"{"rank":2,"row_a":1"#));
+    }
+
+    #[test]
+    fn collects_pairs_within_match_range() {
+        let matrix = vec![
+            vec![1.0, 0.9, 0.8, 0.7],
+            vec![0.9, 1.0, NOT_COMPARED, 0.75],
+            vec![0.8, NOT_COMPARED, 1.0, 0.85],
+            vec![0.7, 0.75, 0.85, 1.0],
+        ];
+        let pairs = collect_pairs(&matrix, &(0.75..=0.85));
+        let similarities: Vec<f64> = pairs.iter().map(|pair| pair.similarity).collect();
+        assert_eq!(similarities, [0.85, 0.8, 0.75]);
+        assert_eq!(collect_pairs(&matrix, &(0.0..=f64::INFINITY)).len(), 5);
+    }
+
+    #[test]
+    fn groups_exact_duplicates_before_fuzzy_pairs() {
+        let words: Vec<Word> = ["Café", "hallo", "cafe", "hello", "CAFE "]
+            .iter()
+            .map(|word| Word::new(word.to_string(), word.to_lowercase()))
+            .collect();
+        let groups = group_exact_duplicates(&words);
+        assert_eq!(groups.representatives, [0, 1, 3]);
+        let exact: Vec<(usize, usize)> = groups.pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(exact, [(0, 2), (0, 4), (2, 4)]);
+
+        // The fuzzy pair of the representatives hallo and hello is mapped back to the words
+        let pairs = groups.merge(vec![SimilarityPair::new(0.8, 1, 2)], &(0.5..=1.0));
+        let partners: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(partners, [(0, 2), (0, 4), (2, 4), (1, 3)]);
+        assert!(groups.merge(Vec::new(), &(0.5..=0.9)).is_empty());
+    }
+
+    #[test]
+    fn writes_adjacency_lists_under_both_words() {
+        let words: Vec<Word> = ["hello", "hallo", "helo"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let pairs = [SimilarityPair::new(0.8, 0, 1), SimilarityPair::new(0.75, 0, 2)];
+        let adjacency = |options: &OutputOptions| {
+            let mut file = Vec::new();
+            let mut output = CappedOutput {
+                writer: &mut file,
+                chunk: Vec::new(),
+                bytes: 0,
+                max_bytes: None,
+                crlf: false,
+            };
+            write_adjacency_lists(&mut output, &pairs, &words, options).unwrap();
+            String::from_utf8(file).unwrap()
+        };
+
+        assert_eq!(
+            adjacency(&OutputOptions::default()),
+            "hello: hallo(0.8000), helo(0.7500)\nhallo: hello(0.8000)\nhelo: hello(0.7500)\n"
+        );
+        let options = OutputOptions {
+            partners_per_word: true,
+            ids: Some(vec!["a".to_string(), "b".to_string(), "c".to_string()]),
+            ..OutputOptions::default()
+        };
+        assert_eq!(adjacency(&options), "a: b(0.8000), c(0.7500)\n");
+
+        let options = OutputOptions {
+            permille: true,
+            ids: Some(vec!["Smith, John".to_string(), "say \"hi\" (x)".to_string(), "c".to_string()]),
+            ..OutputOptions::default()
+        };
+        assert_eq!(
+            adjacency(&options),
+            "\"Smith, John\": \"say \\\"hi\\\" (x)\"(800), c(750)\n\"say \\\"hi\\\" (x)\": \"Smith, John\"(800)\nc: \"Smith, John\"(750)\n"
+        );
+    }
+
+    #[test]
+    fn ends_lines_with_crlf_within_the_size_cap() {
+        let mut file = Vec::new();
+        let mut output = CappedOutput {
+            writer: &mut file,
+            chunk: b"one\ntwo\n".to_vec(),
+            bytes: 0,
+            max_bytes: Some(11),
+            crlf: true,
+        };
+        output.commit(0, 2).unwrap();
+        output.chunk.extend_from_slice(b"\n");
+        assert!(output.commit(2, 3).is_err());
+        assert_eq!(file, b"one\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn parses_length_thresholds() {
+        let thresholds = LengthThresholds::parse("5-10:85, 1-4:95,11-:80", 0.9).unwrap();
+        assert_eq!(thresholds.threshold(3), 0.95);
+        assert_eq!(thresholds.threshold(10), 0.85);
+        assert_eq!(thresholds.threshold(200), 0.8);
+        assert_eq!(thresholds.threshold(0), 0.9);
+        assert_eq!(thresholds.lowest(), 0.8);
+        assert_eq!(LengthThresholds::parse("3:50", 0.9).unwrap().threshold(4), 0.9);
+
+        assert!(LengthThresholds::parse("1-4:95,4-:80", 0.9).is_err());
+        assert!(LengthThresholds::parse("1-:95,8-:80", 0.9).is_err());
+        assert!(LengthThresholds::parse("5-4:95", 0.9).is_err());
+        assert!(LengthThresholds::parse("1-4:195", 0.9).is_err());
+        assert!(LengthThresholds::parse("1-4", 0.9).is_err());
+    }
+
+    #[test]
+    fn keeps_best_match_per_word_with_ties_to_first_partner() {
+        let words: Vec<Word> = ["abcd", "abce", "abcf", "wxyz"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let filter = ComparisonFilter::default();
+        let pairs = calculate_top_pairs(&words, &Scorer::default(), &filter, &(0.5..=1.0), Some(1), None, None);
+        let partners: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(partners, [(0, 1), (1, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn caps_matches_per_word_best_first() {
+        // Word 0 is a hub matching everyone, so its third pair is dropped, which leaves room
+        // for word 3; the last pair is dropped because word 1 is full
+        let pairs = vec![
+            SimilarityPair::new(0.9, 0, 1),
+            SimilarityPair::new(0.8, 0, 2),
+            SimilarityPair::new(0.7, 0, 3),
+            SimilarityPair::new(0.6, 1, 3),
+            SimilarityPair::new(0.5, 2, 3),
+            SimilarityPair::new(0.4, 1, 2),
+        ];
+        let kept = cap_matches_per_word(pairs, 4, 2);
+        let kept: Vec<(usize, usize)> = kept.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(kept, [(0, 1), (0, 2), (1, 3), (2, 3)]);
+    }
+
+    #[test]
+    fn shuffles_ties_reproducibly() {
+        let words: Vec<Word> = ["aaa", "aab", "aac", "aad", "aae", "aaf", "zzz"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &ComparisonFilter::default(), None);
+        let sorted = collect_pairs(&matrix, &(0.5..=1.0));
+        let indices = |pairs: &[SimilarityPair]| -> Vec<(usize, usize)> {
+            pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect()
+        };
+
+        let mut shuffled = sorted.clone();
+        shuffle_ties(&mut shuffled, 7);
+        let mut again = sorted.clone();
+        shuffle_ties(&mut again, 7);
+        let mut other = sorted.clone();
+        shuffle_ties(&mut other, 8);
+        assert_eq!(indices(&shuffled), indices(&again));
+        assert_ne!(indices(&shuffled), indices(&sorted));
+        assert_ne!(indices(&shuffled), indices(&other));
+
+        // The heap keeps the same pairs as shuffling all pairs and taking the first ones
+        let filter = ComparisonFilter {
+            tie_seed: Some(7),
+            ..ComparisonFilter::default()
+        };
+        let limited = calculate_top_pairs(&words, &Scorer::default(), &filter, &(0.5..=1.0), None, Some(4), None);
+        assert_eq!(indices(&limited), indices(&shuffled[..4]));
+    }
+
+    #[test]
+    fn stores_pairs_in_sixteen_bytes() {
+        assert_eq!(std::mem::size_of::<SimilarityPair>(), 16);
+        let pair = SimilarityPair::new(0.5, 3, 7);
+        assert_eq!((pair.index_a(), pair.index_b()), (3, 7));
+    }
+
+    #[test]
+    fn rescores_pairs_below_the_minimum_with_the_fallback() {
+        let words: Vec<Word> = ["martha", "marhta", "hello", "world"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &ComparisonFilter::default(), None);
+        let match_range = 0.9..=f64::INFINITY;
+        assert!(collect_pairs(&matrix, &match_range).is_empty());
+
+        // Jaro-Winkler forgives the transposition that costs Levenshtein two edits
+        let fallback = Fallback {
+            scorer: Scorer::new(SimilarityAlgorithm::JaroWinkler),
+            min_match: 0.9,
+        };
+        let pairs = fallback_pairs(&matrix, &words, &fallback, &match_range);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].index_a(), pairs[0].index_b()), (0, 1));
+        assert!(pairs[0].is_fallback() && pairs[0].similarity > 0.95);
+
+        let moved = pairs[0].with_indices(2, 3);
+        assert_eq!((moved.index_a(), moved.index_b(), moved.is_fallback()), (2, 3, true));
+        assert!(!SimilarityPair::new(0.5, 2, 3).is_fallback());
+    }
+
+    #[test]
+    fn only_compares_pairs_of_sources() {
+        let words: Vec<Word> = ["hello", "hallo", "help", "hello", "world"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let sources = [Word::unprocessed("hello".to_string()), Word::unprocessed("xyz".to_string())];
+        let (flags, unmatched) = match_sources(&words, &sources);
+        assert_eq!(flags, [true, false, false, true, false]);
+        assert_eq!(unmatched, [&sources[1]]);
+
+        let filter = ComparisonFilter {
+            sources: Some(flags.clone()),
+            ..ComparisonFilter::default()
+        };
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &filter, None);
+        let mut pairs = collect_pairs(&matrix, &(0.6..=1.0));
+        orient_to_sources(&mut pairs, &flags);
+        let indices: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(indices, [(0, 3), (0, 1), (3, 1), (0, 2), (3, 2)]);
+
+        // With top_k, only the sources get partners
+        let top = calculate_top_pairs(&words, &Scorer::default(), &filter, &(0.0..=1.0), Some(1), None, None);
+        let indices: Vec<(usize, usize)> = top.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(indices, [(0, 3), (3, 0)]);
+    }
+
+    #[test]
+    fn skips_pairs_without_a_shared_prefix() {
+        let filter = ComparisonFilter {
+            min_prefix: Some(2),
+            ..ComparisonFilter::default()
+        };
+        assert!(filter.passes_word_gates("hello", "help"));
+        assert!(filter.passes_word_gates("héllo", "hé"));
+        assert!(!filter.passes_word_gates("hello", "hallo"));
+        assert!(!filter.passes_word_gates("h", "h"));
+
+        let words: Vec<Word> = ["hello", "hallo", "help"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &filter, None);
+        let pairs = collect_pairs(&matrix, &(0.0..=1.0));
+        let indices: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(indices, [(0, 2)]);
+        assert_eq!((filter.counts.performed(), filter.counts.pruned()), (2, 4));
+    }
+
+    #[test]
+    fn collects_window_pairs_like_the_matrix() {
+        let words: Vec<Word> = ["hello", "hallo", "help", "world", "word", "hello"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let filter = ComparisonFilter {
+            window: Some(2),
+            ..ComparisonFilter::default()
+        };
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &filter, None);
+        let expected = collect_pairs(&matrix, &(0.5..=1.0));
+
+        let filter = ComparisonFilter {
+            window: Some(2),
+            ..ComparisonFilter::default()
+        };
+        let pairs = calculate_window_pairs(&words, &Scorer::default(), &filter, &(0.5..=1.0), None);
+        assert_eq!(pairs, expected);
+        // Of the 15 pairs, the 9 within two rows are scored once
+        assert_eq!((filter.counts.performed(), filter.counts.pruned()), (9, 6));
+    }
+
+    #[test]
+    fn compares_batch_words_with_all_words_only() {
+        let words: Vec<Word> = ["hello", "hallo", "helo", "hellp"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let filter = ComparisonFilter {
+            batch_start: Some(2),
+            ..ComparisonFilter::default()
+        };
+        assert!(!filter.should_compare(0, 1) && !filter.should_compare(1, 0));
+        assert!(filter.should_compare(0, 2) && filter.should_compare(3, 1));
+        assert!(filter.should_compare(2, 3));
+
+        // The canonical words are not compared with each other
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &filter, None);
+        let pairs = collect_pairs(&matrix, &(0.0..=1.0));
+        let mut indices: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        indices.sort();
+        assert_eq!(indices, [(0, 2), (0, 3), (1, 2), (1, 3), (2, 3)]);
+        assert_eq!((filter.counts.performed(), filter.counts.pruned()), (10, 2));
+
+        let options = OutputOptions {
+            batch_start: Some(2),
+            ..OutputOptions::default()
+        };
+        let line = |pair: SimilarityPair| {
+            let mut line = Vec::new();
+            write_pair(&mut line, &pair, &words, &options, None, "").unwrap();
+            String::from_utf8(line).unwrap()
+        };
+        assert_eq!(
+            line(SimilarityPair::new(0.8, 0, 2)),
+            "Row 1: hello\tBatch row 1: helo\tSimilarity: 80.00%\tMatch: batch-canonical\n"
+        );
+        assert_eq!(
+            line(SimilarityPair::new(0.6, 2, 3)),
+            "Batch row 1: helo\tBatch row 2: hellp\tSimilarity: 60.00%\tMatch: batch-internal\n"
+        );
+    }
+
+    #[test]
+    fn stops_rows_once_the_match_budget_is_used_up() {
+        let words: Vec<Word> = ["hello", "hallo", "hullo", "hellos", "world"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let budget = MatchBudget {
+            good_enough: 0.8,
+            matches: 2,
+        };
+        let filter = || ComparisonFilter {
+            match_budget: Some(budget),
+            ..ComparisonFilter::default()
+        };
+
+        // "hello" has its two matches in "hallo" and "hullo", so "hellos" and "world" are skipped
+        let matrix_filter = filter();
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &matrix_filter, None);
+        assert!(matrix[0][3].is_nan() && matrix[0][4].is_nan());
+        assert!(matrix[2][3].is_nan() && matrix[2][4].is_nan());
+        assert!((matrix[3][0] - 5.0 / 6.0).abs() < 1e-12);
+        assert_eq!((matrix_filter.counts.performed(), matrix_filter.counts.pruned()), (14, 6));
+
+        // Without the budget "hello" and "hellos" would be the best pair
+        let top_filter = filter();
+        let pairs = calculate_top_pairs(&words, &Scorer::default(), &top_filter, &(0.8..=1.0), None, None, None);
+        let indices: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(indices, [(0, 1), (0, 2), (1, 2)]);
+        assert_eq!((top_filter.counts.performed(), top_filter.counts.pruned()), (14, 6));
+    }
+
+    #[test]
+    fn stops_window_rows_once_the_match_budget_is_used_up() {
+        let words: Vec<Word> = ["hello", "hallo", "hullo", "hellos", "world"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let filter = ComparisonFilter {
+            window: Some(4),
+            match_budget: Some(MatchBudget {
+                good_enough: 0.8,
+                matches: 1,
+            }),
+            ..ComparisonFilter::default()
+        };
+        let pairs = calculate_window_pairs(&words, &Scorer::default(), &filter, &(0.8..=1.0), None);
+        let indices: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        // "hello" stops at "hallo" and "hallo" at "hullo", so (0, 2) and (0, 3) are not scored
+        assert_eq!(indices, [(0, 1), (1, 2)]);
+        assert_eq!((filter.counts.performed(), filter.counts.pruned()), (5, 5));
+    }
+
+    #[test]
+    fn compares_sorted_neighbors_only() {
+        let words: Vec<Word> = ["hello", "world", "help", "word", "hallo"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let positions = sorted_positions(&words);
+        assert_eq!(positions, [1, 4, 2, 3, 0]);
+
+        let filter = ComparisonFilter {
+            window: Some(1),
+            window_positions: Some(positions),
+            ..ComparisonFilter::default()
+        };
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &filter, None);
+        let pairs = collect_pairs(&matrix, &(0.0..=1.0));
+        let mut indices: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        indices.sort();
+        // Sorted: hallo, hello, help, word, world
+        assert_eq!(indices, [(0, 2), (0, 4), (1, 3), (2, 3)]);
+
+        let windowed = calculate_window_pairs(&words, &Scorer::default(), &filter, &(0.0..=1.0), None);
+        assert_eq!(windowed, pairs);
+    }
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn reports_slowest_rows_first() {
+        let row_times = RowTimes::new(4);
+        row_times.record(0, Duration::from_millis(2));
+        row_times.record(1, Duration::from_millis(5));
+        row_times.record(2, Duration::from_millis(2));
+        assert_eq!(
+            row_times.slowest(3),
+            [(1, Duration::from_millis(5)), (0, Duration::from_millis(2)), (2, Duration::from_millis(2))]
+        );
+        assert_eq!(row_times.slowest(10).len(), 4);
+    }
+
+    #[test]
+    fn detects_numeric_suffix_variants() {
+        assert!(differs_only_in_numeric_suffix("widget", "widget 2"));
+        assert!(differs_only_in_numeric_suffix("a1", "a2"));
+        assert!(differs_only_in_numeric_suffix("a 10", "a2"));
+        assert!(!differs_only_in_numeric_suffix("widget", "widget"));
+        assert!(!differs_only_in_numeric_suffix("widget", "widgets"));
+        assert!(!differs_only_in_numeric_suffix("2 widget", "3 widget"));
+        assert!(!differs_only_in_numeric_suffix("12", "13"));
+    }
+
+    #[test]
+    fn skips_numeric_suffix_variants_but_keeps_duplicates() {
+        let words: Vec<Word> = ["widget", "widget 2", "widget", "gadget1", "gadget2"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let filter = ComparisonFilter {
+            distinct_numeric_suffix: true,
+            ..ComparisonFilter::default()
+        };
+        let matrix = calculate_similarity_matrix(&words, &Scorer::default(), &filter, None);
+        let pairs = collect_pairs(&matrix, &(0.8..=1.0));
+        let indices: Vec<(usize, usize)> = pairs.iter().map(|pair| (pair.index_a(), pair.index_b())).collect();
+        assert_eq!(indices, [(0, 2)]);
+    }
+}
@@ -1,14 +1,55 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
 use std::path::Path;
 use std::process;
+use std::sync::{Arc, Mutex};
 
-use clap::{App, Arg};
-use rayon::prelude::*;
-use strsim::normalized_levenshtein;
+use clap::{App, AppSettings, Arg, ArgMatches, ValueSource};
+use regex::Regex;
 
-use std::time::Instant;
-use indicatif::{ProgressBar, ProgressStyle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use word_similarity::{
+    calculate_similarity_matrix, calculate_top_pairs, calculate_window_pairs, cap_matches_per_word,
+    collect_pairs, count_pairs, fallback_pairs, format_similarity, group_exact_duplicates,
+    match_sources, orient_to_sources, read_ids_file, read_input_file, read_input_file_with_lines,
+    shard_path, shuffle_ties, sorted_positions, word_column, write_output_file, write_output_shards,
+    ComparisonFilter, EmptyPolicy, Fallback, InputEncoding, InputError, InputFormat, InputOptions,
+    LengthThresholds, MatchBudget, OutputFormat, OutputOptions, OutputTruncated, Preprocessing,
+    RowTimes, Scorer, SimilarityAlgorithm, SimilarityPair, Word,
+};
+use word_similarity::cache::SimilarityCache;
+use word_similarity::calibrate::{sample_similarities, suggest_threshold};
+use word_similarity::checksum::file_sha256;
+use word_similarity::csv_matrix::read_csv_matrix;
+use word_similarity::cluster::{canonicalize, write_clusters_file, CanonicalStrategy};
+use word_similarity::crosswalk::write_crosswalk_file;
+use word_similarity::heatmap::{write_heatmap_file, MAX_HEATMAP_WORDS};
+use word_similarity::report::write_report_file;
+use word_similarity::stats::Statistics;
+use word_similarity::metrics::{IdfWeights, SubstitutionCosts};
+use word_similarity::preprocess::{Language, Preset};
+use word_similarity::records::RecordFields;
+use word_similarity::validate::{compare_pairs, naive_pairs, MAX_VALIDATE_WORDS};
+use word_similarity::verify::{read_reported_pairs, verify_sample};
+
+/// Exit code used when --fail-on-match or --fail-on-empty is triggered, or --verify fails.
+/// Errors exit with 1, successful runs with 0.
+const EXIT_CHECK_FAILED: i32 = 2;
+
+/// Exit code used when an input file could be read, but its contents are invalid.
+const EXIT_INVALID_INPUT: i32 = 3;
+
+/// Exit code used when the output file was truncated by --max-output-bytes.
+const EXIT_OUTPUT_TRUNCATED: i32 = 4;
+
+/// The subcommands, with the one used when the first argument is not a subcommand.
+const SUBCOMMANDS: [&str; 3] = ["matrix", "cluster", "from-matrix"];
+const DEFAULT_SUBCOMMAND: &str = "matrix";
 
 /// The entry point of the Rust word similarity application.
 fn main() {
@@ -17,12 +58,786 @@ fn main() {
         .version("0.1.0")
         .author("Roderik von Maltzahn")
         .about("Calculates similarity percentages between word pairs")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(matrix_command())
+        .subcommand(cluster_command())
+        .subcommand(from_matrix_command())
+        .get_matches_from(with_default_subcommand(env::args_os().collect(), |arg| Path::new(arg).exists()));
+
+    match matches.subcommand() {
+        Some(("cluster", matches)) => run_cluster(matches),
+        Some(("from-matrix", matches)) => run_from_matrix(matches),
+        Some((_, matches)) => run_matrix(matches),
+        None => unreachable!("a subcommand is required"),
+    }
+}
+
+/// Insert the default subcommand if the first argument is not a subcommand or a help flag,
+/// so the invocation without a subcommand keeps working.
+///
+/// A first argument naming a subcommand that is also an existing file, by `exists`, is taken
+/// as the input file of the default subcommand, like before there were subcommands.
+fn with_default_subcommand(mut args: Vec<OsString>, exists: impl Fn(&OsStr) -> bool) -> Vec<OsString> {
+    let explicit = match args.get(1) {
+        Some(arg) => match arg.to_str() {
+            Some("-h" | "--help" | "-V" | "--version") => true,
+            Some(name) => (SUBCOMMANDS.contains(&name) || name == "help") && !exists(arg),
+            None => false,
+        },
+        None => true,
+    };
+    if !explicit {
+        args.insert(1, OsString::from(DEFAULT_SUBCOMMAND));
+    }
+    args
+}
+
+/// The arguments of the `matrix` subcommand, which calculates the similarities.
+fn matrix_command() -> App<'static> {
+    App::new("matrix")
+        .about("Calculate the similarities of the word pairs in the input file (the default)")
         .arg(
             Arg::with_name("INPUT")
                 .help("Input file containing a list of words")
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("OUTPUT")
+                .help("Output file for similarity percentages")
+                .required_unless_present_any(["COUNT_ONLY", "INTERACTIVE", "VERIFY", "SUGGEST_THRESHOLD"])
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("MIN_MATCH")
+                .help("Minimum match percentage (default: depends on the algorithm, 80 for levenshtein)")
+                .short('m')
+                .long("min-match")
+                .takes_value(true)
+                .env("WORD_SIMILARITY_MIN_MATCH"),
+        )
+        .arg(
+            Arg::with_name("THRESHOLD_BY_LENGTH")
+                .help("Minimum match percentages per length of the shorter word, like 1-4:95,5-10:85,11-:80; other lengths use --min-match")
+                .long("threshold-by-length")
+                .takes_value(true)
+                .value_name("SPEC")
+                .conflicts_with_all(&["COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("MAX_MATCH")
+                .help("Maximum match percentage, to only keep the pairs between --min-match and this percentage")
+                .long("max-match")
+                .takes_value(true)
+                .value_name("PERCENTAGE")
+                .conflicts_with_all(&["COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("ALGORITHM")
+                .help("Similarity algorithm; repeat or comma-separate to add the scores of further algorithms as extra columns")
+                .short('a')
+                .long("algorithm")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_value_delimiter(true)
+                .possible_values(SimilarityAlgorithm::ALL.iter().map(|a| a.name()))
+                .default_value("levenshtein"),
+        )
+        .arg(
+            Arg::with_name("FALLBACK_METRIC")
+                .help("Score the pairs below --min-match again with this more lenient algorithm and keep the ones reaching --fallback-min-match, tagged as fallback matches")
+                .long("fallback-metric")
+                .takes_value(true)
+                .value_name("ALGORITHM")
+                .possible_values(SimilarityAlgorithm::ALL.iter().map(|a| a.name()))
+                .conflicts_with_all(&["TOP_K", "BEST_ONLY", "LIMIT", "COUNT_ONLY", "INTERACTIVE", "THRESHOLD_BY_LENGTH"]),
+        )
+        .arg(
+            Arg::with_name("FALLBACK_MIN_MATCH")
+                .help("Minimum match percentage of --fallback-metric (default: --min-match if given, else the default of the metric)")
+                .long("fallback-min-match")
+                .takes_value(true)
+                .value_name("PERCENTAGE")
+                .requires("FALLBACK_METRIC"),
+        )
+        .arg(
+            Arg::with_name("SUBSTITUTION_COSTS")
+                .help("File with 'a b cost' lines giving cheaper character substitutions for the levenshtein algorithm")
+                .long("substitution-costs")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("IDF_FILE")
+                .help("File with n-gram document frequencies used to down-weight common bigrams in the cosine algorithm")
+                .long("idf-file")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("INPUT_FORMAT")
+                .help("Format of the input lines: text (a word per line) or json (an array of tokens per line, joined with spaces)")
+                .long("input-format")
+                .takes_value(true)
+                .possible_values(InputFormat::ALL.iter().map(|format| format.name()))
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("TOKEN_REGEX")
+                .help("Use every match of the pattern in a line as a separate word, instead of the whole line")
+                .long("token-regex")
+                .takes_value(true)
+                .value_name("PATTERN")
+                .conflicts_with_all(&["COMPARE_TO_SELF_FILE", "VERIFY", "IDS_FILE"]),
+        )
+        .arg(
+            Arg::with_name("LINES_FROM")
+                .help("Only read the input from this line on, counting from 1; the row labels show the line numbers in the file")
+                .long("lines-from")
+                .takes_value(true)
+                .value_name("LINE")
+                .conflicts_with_all(&["COMPARE_TO_SELF_FILE", "VERIFY", "IDS_FILE"]),
+        )
+        .arg(
+            Arg::with_name("LINES_TO")
+                .help("Only read the input up to and including this line")
+                .long("lines-to")
+                .takes_value(true)
+                .value_name("LINE")
+                .conflicts_with_all(&["COMPARE_TO_SELF_FILE", "VERIFY", "IDS_FILE"]),
+        )
+        .arg(
+            Arg::with_name("COMPARE_FIELD_PAIRS")
+                .help("Read every line as a record of delimited fields and compare the fields listed as FIELD:WEIGHT[:ALGORITHM], numbered from 1 (e.g. 1:2,2:1,3:1:jaro-winkler)")
+                .long("compare-field-pairs")
+                .takes_value(true)
+                .value_name("SPEC")
+                .conflicts_with_all(&["TOKEN_REGEX", "SHOW_ALIGNMENT"]),
+        )
+        .arg(
+            Arg::with_name("FIELD_DELIMITER")
+                .help("The character separating the fields of a record, or \\t for a tab [default: ,]")
+                .long("field-delimiter")
+                .takes_value(true)
+                .value_name("CHAR")
+                .requires("COMPARE_FIELD_PAIRS"),
+        )
+        .arg(
+            Arg::with_name("ALLOW_SINGLE_WORD")
+                .help("Write an empty result with a warning for an input file with a single word instead of failing")
+                .long("allow-single-word"),
+        )
+        .arg(
+            Arg::with_name("NO_PREPROCESS")
+                .help("Compare the words exactly as they are, skipping lowercasing and all other preprocessing")
+                .long("no-preprocess")
+                .conflicts_with_all(&[
+                    "PRESET",
+                    "CASE_SENSITIVE",
+                    "LANG",
+                    "ASCII_FOLD",
+                    "STRIP_ACCENTS",
+                    "STRIP_PUNCTUATION",
+                    "IGNORE_NUMBERS",
+                    "COLLAPSE_WHITESPACE",
+                    "ANAGRAM",
+                    "REVERSE",
+                ]),
+        )
+        .arg(
+            Arg::with_name("PRESET")
+                .help("Start from a named combination of the preprocessing options, extended by the options given")
+                .long("preset")
+                .takes_value(true)
+                .possible_values(Preset::ALL.iter().map(|p| p.name())),
+        )
+        .arg(
+            Arg::with_name("CASE_SENSITIVE")
+                .help("Keep the case of the letters instead of lowercasing the words before comparing")
+                .long("case-sensitive"),
+        )
+        .arg(
+            Arg::with_name("LANG")
+                .help("Lowercase with the casing rules of a language: tr or az (dotted and dotless I), de (ß to ss)")
+                .long("lang")
+                .takes_value(true)
+                .possible_values(Language::ALL.iter().map(|l| l.name()))
+                .conflicts_with("CASE_SENSITIVE"),
+        )
+        .arg(
+            Arg::with_name("ASCII_FOLD")
+                .help("Transliterate Latin letters to ASCII before comparing (e.g. ß to ss, ø to o)")
+                .long("ascii-fold"),
+        )
+        .arg(
+            Arg::with_name("STRIP_ACCENTS")
+                .help("Remove the accents from Latin letters before comparing (e.g. é to e), keeping letters like ß")
+                .long("strip-accents"),
+        )
+        .arg(
+            Arg::with_name("LENGTH_PENALTY")
+                .help("Discount the similarity of short words, which then need to match more exactly (similarity ^ (1 + P / length of the shorter word))")
+                .long("length-penalty")
+                .takes_value(true)
+                .value_name("P"),
+        )
+        .arg(
+            Arg::with_name("CLAMP_IDENTICAL")
+                .help("Score words that are identical after preprocessing exactly 100%, even where an algorithm misses it by a rounding error; --clamp-identical=false turns it off")
+                .long("clamp-identical")
+                .takes_value(true)
+                .value_name("BOOL")
+                .possible_values(["true", "false"])
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("true")
+                .default_value("true"),
+        )
+        .arg(
+            Arg::with_name("MAX_EDITS")
+                .help("Only match pairs within K edits, using a faster banded levenshtein distance")
+                .long("max-edits")
+                .takes_value(true)
+                .value_name("K")
+                .conflicts_with("SUBSTITUTION_COSTS"),
+        )
+        .arg(
+            Arg::with_name("STRIP_PUNCTUATION")
+                .help("Ignore punctuation when comparing words")
+                .long("strip-punctuation"),
+        )
+        .arg(
+            Arg::with_name("IGNORE_NUMBERS")
+                .help("Replace every run of digits with a single placeholder before comparing")
+                .long("ignore-numbers"),
+        )
+        .arg(
+            Arg::with_name("COLLAPSE_WHITESPACE")
+                .help("Collapse runs of whitespace into a single space and trim the ends before comparing")
+                .long("collapse-whitespace"),
+        )
+        .arg(
+            Arg::with_name("ANAGRAM")
+                .help("Sort the characters of the words before comparing, so anagrams and reordered words score 100%")
+                .long("anagram")
+                .conflicts_with("REVERSE"),
+        )
+        .arg(
+            Arg::with_name("REVERSE")
+                .help("Reverse the words before comparing, so prefix-favoring algorithms favor shared suffixes")
+                .long("reverse"),
+        )
+        .arg(
+            Arg::with_name("EMPTY_POLICY")
+                .help("What to do with words that preprocessing leaves empty: drop them with a warning, fail, or compare them")
+                .long("empty-policy")
+                .takes_value(true)
+                .possible_values(EmptyPolicy::ALL.iter().map(|policy| policy.name()))
+                .default_value("drop"),
+        )
+        .arg(
+            Arg::with_name("FORMAT")
+                .help("Output format: tab-separated text, a GraphViz graph of the pairs, JSON Lines, or a line per word listing its matches")
+                .short('f')
+                .long("format")
+                .takes_value(true)
+                .possible_values(OutputFormat::ALL.iter().map(|f| f.name()))
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("PERMILLE")
+                .help("Write similarities as integer permille (0-1000) instead of percentages")
+                .long("permille"),
+        )
+        .arg(
+            Arg::with_name("IDS_FILE")
+                .help("File with one ID per line in the order of the input words, used instead of the row numbers")
+                .long("ids-file")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("COMPARE_TO_SELF_FILE")
+                .help("File with a new batch of words, compared against the input words and against itself, but without comparing the input words among themselves")
+                .long("compare-to-self-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with("IDS_FILE"),
+        )
+        .arg(
+            Arg::with_name("SOURCES_FILE")
+                .help("File with the words to compare against all input words, instead of comparing every pair; the matches of every source are listed with the source first")
+                .long("sources-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with_all(&["COMPARE_TO_SELF_FILE", "EXACT_GROUP_FIRST"]),
+        )
+        .arg(
+            Arg::with_name("ALIGN")
+                .help("Pad the output columns to fixed widths for reading in a terminal")
+                .long("align"),
+        )
+        .arg(
+            Arg::with_name("SHOW_ALIGNMENT")
+                .help("Add the edit operations turning the first word into the second word to every pair")
+                .long("show-alignment"),
+        )
+        .arg(
+            Arg::with_name("COMPARE_NORMALIZED_AND_RAW")
+                .help("Also score the original words before preprocessing and add the result as a Raw column, for audits")
+                .long("compare-normalized-and-raw"),
+        )
+        .arg(
+            Arg::with_name("CRLF")
+                .help("End the lines of the output file with CRLF instead of LF, for Windows tools like Notepad")
+                .long("crlf"),
+        )
+        .arg(
+            Arg::with_name("WITH_HASH")
+                .help("Add a stable hash of the processed key of both words to every pair, to join pairs across runs")
+                .long("with-hash"),
+        )
+        .arg(
+            Arg::with_name("WITH_METRIC")
+                .help("Add the name of the metric behind the similarity to every pair, to tell the output of different metrics apart")
+                .long("with-metric"),
+        )
+        .arg(
+            Arg::with_name("WITH_EDITS")
+                .help("Add the Levenshtein distance of the processed words, the number of edits between them, to every pair")
+                .long("with-edits"),
+        )
+        .arg(
+            Arg::with_name("GROUP_BY_WORD")
+                .help("Write the matches of every word in a block below the word instead of as a flat list")
+                .long("group-by-word")
+                .conflicts_with("CANONICALIZE"),
+        )
+        .arg(
+            Arg::with_name("WITH_RANK")
+                .help("Prefix the matches of every word with their rank, 1 for the best match, with --group-by-word, --top-k or --best-only")
+                .long("with-rank"),
+        )
+        .arg(
+            Arg::with_name("FAIL_ON_MATCH")
+                .help("Exit with code 2 if at least one pair is written")
+                .long("fail-on-match")
+                .conflicts_with("FAIL_ON_EMPTY"),
+        )
+        .arg(
+            Arg::with_name("FAIL_ON_EMPTY")
+                .help("Exit with code 2 if no pairs are written")
+                .long("fail-on-empty"),
+        )
+        .arg(
+            Arg::with_name("STATS")
+                .help("Print the count, min, max, mean, median and standard deviation of the written similarities")
+                .long("stats"),
+        )
+        .arg(
+            Arg::with_name("STATS_OUT")
+                .help("Write the statistics to this file instead of stderr")
+                .long("stats-out")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("STATS"),
+        )
+        .arg(
+            Arg::with_name("COUNT_ONLY")
+                .help("Only print the number of pairs above the minimum match, or above each percentage in a comma-separated list (e.g. --count-only=80,90,95)")
+                .long("count-only")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .use_value_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("INTERACTIVE")
+                .help("Build the matrix once, then try thresholds at a prompt instead of writing an output file")
+                .long("interactive")
+                .conflicts_with("COUNT_ONLY"),
+        )
+        .arg(
+            Arg::with_name("HEATMAP")
+                .help("Also render the full similarity matrix as a heatmap PNG, for inputs of at most 500 words")
+                .long("heatmap")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with_all(&["TOP_K", "BEST_ONLY", "LIMIT", "EXACT_GROUP_FIRST"]),
+        )
+        .arg(
+            Arg::with_name("REPORT_HTML")
+                .help("Also write the pairs as a self-contained HTML page with a sortable table and the differences of the words highlighted, for reviewers")
+                .long("report-html")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("CROSSWALK")
+                .help("Also write a crosswalk with one row per batch word, mapping it to its best input word or to NULL if it has no match")
+                .long("crosswalk")
+                .takes_value(true)
+                .value_name("PATH")
+                .requires("COMPARE_TO_SELF_FILE")
+                .conflicts_with_all(&["TOP_K", "BEST_ONLY", "LIMIT", "MAX_MATCHES_PER_WORD", "COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("TOP_K")
+                .help("Only keep the K best matches of every word")
+                .long("top-k")
+                .takes_value(true)
+                .value_name("K")
+                .conflicts_with_all(&["COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("BEST_ONLY")
+                .help("Only keep the single best match of every word, ties go to the partner that comes first")
+                .long("best-only")
+                .conflicts_with_all(&["TOP_K", "COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("EXACT_GROUP_FIRST")
+                .help("Pair the words that are equal after lowercasing, stripping accents and collapsing whitespace at 100% first, and only compare one word of each group fuzzily")
+                .long("exact-group-first")
+                .conflicts_with_all(&["LIMIT", "COUNT_ONLY", "INTERACTIVE", "COMPARE_TO_SELF_FILE"]),
+        )
+        .arg(
+            Arg::with_name("LIMIT")
+                .help("Only keep the N best pairs overall")
+                .long("limit")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with_all(&["COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("MAX_MATCHES_PER_WORD")
+                .help("Keep at most this many pairs per word, preferring the best pairs, to limit the output of words matching many others")
+                .long("max-matches-per-word")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with_all(&["COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("SHUFFLE_TIES")
+                .help("Order the pairs of equal similarity randomly instead of by row, so the first pairs are a random sample of the ties")
+                .long("shuffle-ties")
+                .conflicts_with_all(&["COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("SEED")
+                .help("The seed for --shuffle-ties, to get the same order in every run (default: a seed from the clock, printed to stderr)")
+                .long("seed")
+                .takes_value(true)
+                .value_name("N")
+                .requires("SHUFFLE_TIES"),
+        )
+        .arg(
+            Arg::with_name("WINDOW")
+                .help("Only compare every word to the next W words, for input that is already sorted (approximate, misses far-apart matches)")
+                .long("window")
+                .takes_value(true)
+                .value_name("W"),
+        )
+        .arg(
+            Arg::with_name("SORTED_NEIGHBORHOOD")
+                .help("Sort the words by their preprocessed text and only compare the words within a sliding window of W sorted words (approximate, misses matches that sort far apart)")
+                .long("sorted-neighborhood")
+                .takes_value(true)
+                .value_name("W")
+                .conflicts_with_all(&["WINDOW", "EXACT_GROUP_FIRST"]),
+        )
+        .arg(
+            Arg::with_name("DISTINCT_NUMERIC_SUFFIX")
+                .help("Leave out the pairs of words that only differ in a trailing number, like \"Widget\" and \"Widget 2\", as distinct variants")
+                .long("distinct-numeric-suffix"),
+        )
+        .arg(
+            Arg::with_name("MIN_PREFIX")
+                .help("Skip the pairs whose processed words do not start with the same K characters, before scoring them")
+                .long("min-prefix")
+                .takes_value(true)
+                .value_name("K"),
+        )
+        .arg(
+            Arg::with_name("MATCH_BUDGET")
+                .help("With --top-k, stop comparing a word once it has M matches at or above --good-enough (approximate, may miss matches)")
+                .long("match-budget")
+                .takes_value(true)
+                .value_name("M")
+                .requires_all(&["TOP_K", "GOOD_ENOUGH"]),
+        )
+        .arg(
+            Arg::with_name("GOOD_ENOUGH")
+                .help("Percentage at which a match counts towards --match-budget")
+                .long("good-enough")
+                .takes_value(true)
+                .value_name("PERCENTAGE")
+                .requires("MATCH_BUDGET"),
+        )
+        .arg(
+            Arg::with_name("CACHE")
+                .help("Look up the similarities in this cache file before computing them, and add the computed ones to it")
+                .long("cache")
+                .takes_value(true)
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("EXPLAIN")
+                .help("Print the effective configuration to stderr before computing the similarities")
+                .long("explain"),
+        )
+        .arg(
+            Arg::with_name("COMPARISON_STATS")
+                .help("Report the number of comparisons performed and pruned, and the comparisons per second")
+                .long("comparison-stats"),
+        )
+        .arg(
+            Arg::with_name("PROFILE")
+                .help("Time every row of the matrix and print the slowest rows with their word (default 10 rows, e.g. --profile=25)")
+                .long("profile")
+                .takes_value(true)
+                .value_name("ROWS")
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("10"),
+        )
+        .arg(
+            Arg::with_name("VALIDATE_AGAINST_NAIVE")
+                .help("Debug: also compute the pairs naively and fail if they differ from the optimized pairs, for inputs of at most 2000 words")
+                .long("validate-against-naive")
+                .hide(true)
+                .conflicts_with_all(&[
+                    "WINDOW",
+                    "SORTED_NEIGHBORHOOD",
+                    "MATCH_BUDGET",
+                    "EXACT_GROUP_FIRST",
+                    "COUNT_ONLY",
+                    "INTERACTIVE",
+                ]),
+        )
+        .arg(
+            Arg::with_name("COLOR")
+                .help("When to use colors in progress bars: auto (if stderr is a terminal), always or never")
+                .long("color")
+                .takes_value(true)
+                .possible_values(["auto", "always", "never"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("CHECKSUM")
+                .help("Print the SHA-256 checksum of the output file to stderr, in the format of sha256sum")
+                .long("checksum"),
+        )
+        .arg(
+            Arg::with_name("MKDIR")
+                .help("Create the output directory if it does not exist")
+                .long("mkdir"),
+        )
+        .arg(
+            Arg::with_name("CANONICALIZE")
+                .help("Cluster the similar words and write one line per cluster with its canonical word and the words it absorbs")
+                .long("canonicalize")
+                .conflicts_with_all(&["COUNT_ONLY", "INTERACTIVE"]),
+        )
+        .arg(
+            Arg::with_name("CANONICAL_STRATEGY")
+                .help("How --canonicalize chooses the canonical word of a cluster")
+                .long("canonical-strategy")
+                .takes_value(true)
+                .possible_values(CanonicalStrategy::ALL.iter().map(|s| s.name()))
+                .requires("CANONICALIZE")
+                .default_value("shortest"),
+        )
+        .arg(
+            Arg::with_name("VERIFY")
+                .help("Recompute a random sample of the pairs in an existing text output file and report discrepancies, instead of writing an output file")
+                .long("verify")
+                .takes_value(true)
+                .value_name("OUTPUT_FILE")
+                .conflicts_with_all(&["OUTPUT", "COUNT_ONLY", "INTERACTIVE", "IDS_FILE", "COMPARE_TO_SELF_FILE"]),
+        )
+        .arg(
+            Arg::with_name("SUGGEST_THRESHOLD")
+                .help("Sample pairs, find the gap between the unrelated pairs and the matches in their similarities and print a suggested --min-match, instead of writing an output file (default 100000 pairs, e.g. --suggest-threshold=500000)")
+                .long("suggest-threshold")
+                .takes_value(true)
+                .value_name("SAMPLE")
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("100000")
+                .conflicts_with_all(&["OUTPUT", "COUNT_ONLY", "INTERACTIVE", "VERIFY"]),
+        )
+        .arg(
+            Arg::with_name("VERIFY_SAMPLE")
+                .help("Number of pairs recomputed by --verify")
+                .long("verify-sample")
+                .takes_value(true)
+                .value_name("N")
+                .requires("VERIFY")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::with_name("OUTPUT_SHARDS")
+                .help("Split the pairs over N files OUTPUT.0 to OUTPUT.N-1 by a hash of the two words, written in parallel")
+                .long("output-shards")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with("CANONICALIZE"),
+        )
+        .arg(
+            Arg::with_name("APPEND")
+                .help("Append to the output file instead of replacing it, for the text and jsonl formats")
+                .long("append")
+                .conflicts_with("CANONICALIZE"),
+        )
+        .arg(
+            Arg::with_name("MAX_OUTPUT_BYTES")
+                .help("Stop writing and fail once the output file would exceed this many bytes")
+                .long("max-output-bytes")
+                .takes_value(true)
+                .value_name("BYTES"),
+        )
+        .arg(
+            Arg::with_name("READ_BUFFER")
+                .help("Size of the input buffer in KB, larger buffers reduce syscalls on slow or remote storage")
+                .long("read-buffer")
+                .takes_value(true)
+                .value_name("KB"),
+        )
+        .arg(
+            Arg::with_name("MMAP")
+                .help("Map the input files into memory instead of reading them through a buffer, for very large files")
+                .long("mmap")
+                .conflicts_with("READ_BUFFER"),
+        )
+        .arg(
+            Arg::with_name("LOSSY")
+                .help("Replace invalid UTF-8 in the input files with U+FFFD instead of rejecting them")
+                .long("lossy"),
+        )
+        .arg(
+            Arg::with_name("INPUT_ENCODING")
+                .help("Character encoding of the input files, decoded into UTF-8 before reading: utf-8, latin1 (ISO-8859-1) or windows-1252")
+                .long("input-encoding")
+                .takes_value(true)
+                .possible_values(InputEncoding::ALL.iter().map(|encoding| encoding.name()))
+                .default_value("utf-8"),
+        )
+        .arg(
+            Arg::with_name("WRITE_BUFFER")
+                .help("Size of the output buffer in KB, larger buffers reduce syscalls on slow or remote storage")
+                .long("write-buffer")
+                .takes_value(true)
+                .value_name("KB"),
+        )
+        .arg(
+            Arg::with_name("PROGRESS_INTERVAL")
+                .help("Minimum milliseconds between progress bar redraws, from 1 to 1000 (default: 15 redraws per second), larger intervals cut the overhead on fast runs or slow terminals")
+                .long("progress-interval")
+                .takes_value(true)
+                .value_name("MS"),
+        )
+        .arg(
+            Arg::with_name("PROGRESS_JSON")
+                .help("Write the progress to stderr as JSON lines like {\"phase\":\"matrix\",\"done\":N,\"total\":M} instead of drawing progress bars, at most once per second or per --progress-interval")
+                .long("progress-json"),
+        )
+}
+
+/// The arguments of the `cluster` subcommand, which clusters the pairs of an existing output.
+fn cluster_command() -> App<'static> {
+    App::new("cluster")
+        .about("Cluster the pairs of an existing text output file and write a canonical word per cluster")
+        .arg(
+            Arg::with_name("INPUT")
+                .help("Input file containing the list of words the pairs were calculated from")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("PAIRS")
+                .help("Text output file of the matrix subcommand")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::with_name("OUTPUT")
+                .help("Output file for the clusters")
+                .required(true)
+                .index(3),
+        )
+        .arg(
+            Arg::with_name("MIN_MATCH")
+                .help("Only join the pairs with at least this similarity percentage (default: all pairs in the file)")
+                .short('m')
+                .long("min-match")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("CANONICAL_STRATEGY")
+                .help("How the canonical word of a cluster is chosen")
+                .long("canonical-strategy")
+                .takes_value(true)
+                .possible_values(CanonicalStrategy::ALL.iter().map(|s| s.name()))
+                .default_value("shortest"),
+        )
+}
+
+/// Cluster the pairs of an existing output file, the `cluster` subcommand.
+fn run_cluster(matches: &ArgMatches) {
+    let input_path = Path::new(matches.value_of("INPUT").unwrap());
+    let (words, lines, _) = match read_input_file_with_lines(input_path, &InputOptions::default()) {
+        Ok(read) => read,
+        Err(e) => {
+            eprintln!("Error: Failed to read the input file: {}", e);
+            process::exit(input_error_code(&e));
+        }
+    };
+
+    let pairs_path = Path::new(matches.value_of("PAIRS").unwrap());
+    let reported = match read_reported_pairs(pairs_path, &words, Some(&lines)) {
+        Ok(pairs) => pairs,
+        Err(e) => {
+            eprintln!("Error: Failed to read the pairs file: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let min_match = match matches.value_of("MIN_MATCH").map(str::parse::<f64>) {
+        Some(Ok(percentage)) => percentage / 100.0,
+        Some(Err(_)) => {
+            let value = matches.value_of("MIN_MATCH").unwrap();
+            eprintln!("Error: Invalid value for min-match: {}", value);
+            process::exit(1);
+        }
+        None => f64::NEG_INFINITY,
+    };
+    let pairs: Vec<SimilarityPair> = reported
+        .iter()
+        .filter(|pair| pair.similarity >= min_match)
+        .map(|pair| SimilarityPair::new(pair.similarity, pair.index_a, pair.index_b))
+        .collect();
+
+    let strategy = matches.value_of("CANONICAL_STRATEGY").unwrap().parse().unwrap();
+    let clusters = canonicalize(&words, &pairs, strategy);
+    let output_path = Path::new(matches.value_of("OUTPUT").unwrap());
+    if let Err(e) = write_clusters_file(output_path, &clusters, &words, &OutputOptions::default()) {
+        eprintln!("Error writing output file: {}", e);
+        process::exit(1);
+    }
+    println!("{} clusters from {} pairs", clusters.len(), pairs.len());
+}
+
+/// The arguments of the `from-matrix` subcommand, which converts a matrix computed elsewhere.
+fn from_matrix_command() -> App<'static> {
+    App::new("from-matrix")
+        .about("Threshold and sort the pairs of a square similarity matrix in a labeled CSV file, computed by another tool")
+        .arg(
+            Arg::with_name("INPUT")
+                .help("CSV file with the labels in the first row and column and the similarities in between")
+                .required(true)
+                .index(1),
+        )
         .arg(
             Arg::with_name("OUTPUT")
                 .help("Output file for similarity percentages")
@@ -37,148 +852,1481 @@ fn main() {
                 .takes_value(true)
                 .default_value("80"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("MAX_MATCH")
+                .help("Maximum match percentage, to only keep the pairs between --min-match and this percentage")
+                .long("max-match")
+                .takes_value(true)
+                .value_name("PERCENTAGE"),
+        )
+        .arg(
+            Arg::with_name("PERCENTAGES")
+                .help("Read the similarities as percentages from 0 to 100 instead of fractions from 0 to 1")
+                .long("percentages"),
+        )
+        .arg(
+            Arg::with_name("FORMAT")
+                .help("Output format: tab-separated text, a GraphViz graph of the pairs, JSON Lines, or a line per word listing its matches")
+                .short('f')
+                .long("format")
+                .takes_value(true)
+                .possible_values(OutputFormat::ALL.iter().map(|f| f.name()))
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("CANONICALIZE")
+                .help("Cluster the similar words and write one line per cluster with its canonical word and the words it absorbs")
+                .long("canonicalize"),
+        )
+        .arg(
+            Arg::with_name("CANONICAL_STRATEGY")
+                .help("How the canonical word of a cluster is chosen")
+                .long("canonical-strategy")
+                .takes_value(true)
+                .possible_values(CanonicalStrategy::ALL.iter().map(|s| s.name()))
+                .default_value("shortest")
+                .requires("CANONICALIZE"),
+        )
+}
 
-    // Get input and output file paths
+/// Threshold and sort the pairs of a matrix computed by another tool, the `from-matrix`
+/// subcommand. The labels of the matrix are the words, and the pairs are written like the
+/// pairs of the `matrix` subcommand.
+fn run_from_matrix(matches: &ArgMatches) {
     let input_path = Path::new(matches.value_of("INPUT").unwrap());
+    let labeled = match read_csv_matrix(input_path, matches.is_present("PERCENTAGES")) {
+        Ok(labeled) => labeled,
+        Err(e) => {
+            eprintln!("Error: Failed to read the matrix file: {}", e);
+            process::exit(input_error_code(&e));
+        }
+    };
+    let words: Vec<Word> = labeled.labels.into_iter().map(Word::unprocessed).collect();
+
+    let min_match = match parse_percentage(matches.value_of("MIN_MATCH").unwrap()) {
+        Some(min_match) => min_match,
+        None => {
+            eprintln!("Error: Invalid value for min-match: {}", matches.value_of("MIN_MATCH").unwrap());
+            process::exit(1);
+        }
+    };
+    let max_match = match matches.value_of("MAX_MATCH").map(parse_percentage) {
+        Some(Some(max_match)) if max_match >= min_match => max_match,
+        Some(Some(_)) => {
+            eprintln!("Error: --max-match must not be below --min-match");
+            process::exit(1);
+        }
+        Some(None) => {
+            eprintln!("Error: Invalid value for max-match: {}", matches.value_of("MAX_MATCH").unwrap());
+            process::exit(1);
+        }
+        None => f64::INFINITY,
+    };
+    let pairs = collect_pairs(&labeled.matrix, &(min_match..=max_match));
+
+    let output_options = OutputOptions {
+        format: matches.value_of("FORMAT").unwrap().parse().unwrap(),
+        ..OutputOptions::default()
+    };
     let output_path = Path::new(matches.value_of("OUTPUT").unwrap());
+    let (result, summary) = if matches.is_present("CANONICALIZE") {
+        if output_options.format != OutputFormat::Text {
+            eprintln!("Error: --canonicalize only writes the text format");
+            process::exit(1);
+        }
+        let strategy = matches.value_of("CANONICAL_STRATEGY").unwrap().parse().unwrap();
+        let clusters = canonicalize(&words, &pairs, strategy);
+        let summary = format!("{} clusters from {} pairs", clusters.len(), pairs.len());
+        (write_clusters_file(output_path, &clusters, &words, &output_options), summary)
+    } else {
+        let summary = format!("{} pairs among {} words", pairs.len(), words.len());
+        (write_output_file(output_path, &pairs, &words, &output_options, None), summary)
+    };
+    if let Err(e) = result {
+        eprintln!("Error writing output file: {}", e);
+        process::exit(1);
+    }
+    println!("{}", summary);
+}
 
-    // Parse minimum match percentage
-    let min_match = matches
-        .value_of("MIN_MATCH")
+/// Calculate the similarities and write the pairs, the `matrix` subcommand.
+fn run_matrix(matches: &ArgMatches) {
+    set_colors(matches.value_of("COLOR").unwrap());
+
+    // Get input file path
+    let input_path = Path::new(matches.value_of("INPUT").unwrap());
+
+    // The first algorithm builds the matrix, the others are only scored for written pairs
+    let algorithms: Vec<SimilarityAlgorithm> = matches
+        .values_of("ALGORITHM")
         .unwrap()
-        .parse::<f64>()
-        .unwrap_or(80.0) / 100.0;
+        .map(|name| name.parse().unwrap())
+        .collect();
+
+    // Parse minimum match percentage, each algorithm scores typos differently
+    let default_match = algorithms[0].default_threshold();
+    let min_match = match matches.value_of("MIN_MATCH") {
+        Some(value) => match parse_percentage(value) {
+            Some(min_match) => min_match,
+            None => {
+                eprintln!("Error: Invalid value for min-match: {}", value);
+                process::exit(1);
+            }
+        },
+        None => default_match,
+    };
+
+    // Only keep the pairs up to the maximum match if given, for reviewing a band of scores
+    let max_match = match matches.value_of("MAX_MATCH") {
+        Some(value) => match parse_percentage(value) {
+            Some(max_match) if max_match >= min_match => max_match,
+            Some(_) => {
+                eprintln!("Error: --max-match must not be below --min-match");
+                process::exit(1);
+            }
+            None => {
+                eprintln!("Error: Invalid value for max-match: {}", value);
+                process::exit(1);
+            }
+        },
+        None => f64::INFINITY,
+    };
+
+    // Every pair has to reach the lowest length threshold, the rest is checked per pair
+    let length_thresholds = matches.value_of("THRESHOLD_BY_LENGTH").map(|spec| {
+        match LengthThresholds::parse(spec, min_match) {
+            Ok(thresholds) => thresholds,
+            Err(e) => {
+                eprintln!("Error: Invalid value for threshold-by-length: {}", e);
+                process::exit(1);
+            }
+        }
+    });
+    let lowest_match = length_thresholds.as_ref().map_or(min_match, LengthThresholds::lowest);
+    let match_range = lowest_match..=max_match;
+
+    let mut scorer = Scorer::new(algorithms[0]);
+    scorer.clamp_identical = matches.value_of("CLAMP_IDENTICAL") == Some("true");
+    if let Some(path) = matches.value_of("SUBSTITUTION_COSTS") {
+        if scorer.algorithm != SimilarityAlgorithm::Levenshtein {
+            eprintln!("Error: --substitution-costs only applies to the levenshtein algorithm");
+            process::exit(1);
+        }
+        match SubstitutionCosts::from_file(Path::new(path)) {
+            Ok(costs) => scorer.substitution_costs = Some(costs),
+            Err(e) => {
+                eprintln!("Error: Failed to read the substitution costs: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    scorer.max_edits = parse_count(matches, "MAX_EDITS");
+    if scorer.max_edits.is_some() && scorer.algorithm != SimilarityAlgorithm::Levenshtein {
+        eprintln!("Error: --max-edits only applies to the levenshtein algorithm");
+        process::exit(1);
+    }
+
+    if let Some(value) = matches.value_of("LENGTH_PENALTY") {
+        match value.parse::<f64>() {
+            Ok(penalty) if penalty.is_finite() && penalty >= 0.0 => scorer.length_penalty = Some(penalty),
+            _ => {
+                eprintln!("Error: Invalid value for length-penalty: {}", value);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = matches.value_of("IDF_FILE") {
+        if scorer.algorithm != SimilarityAlgorithm::Cosine {
+            eprintln!("Error: --idf-file only applies to the cosine algorithm");
+            process::exit(1);
+        }
+        match IdfWeights::from_file(Path::new(path)) {
+            Ok(weights) => scorer.idf_weights = Some(weights),
+            Err(e) => {
+                eprintln!("Error: Failed to read the IDF file: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let record_fields = parse_record_fields(matches);
+    if record_fields.is_some() && algorithms.len() > 1 {
+        eprintln!("Error: --compare-field-pairs only supports a single algorithm, set the algorithm per field instead");
+        process::exit(1);
+    }
+    scorer.record_fields = record_fields.clone();
+    let fallback = parse_fallback(matches, &scorer, min_match);
+
+    // A count of 0 would only give an empty result
+    for name in ["OUTPUT_SHARDS", "MAX_MATCHES_PER_WORD", "TOP_K", "LIMIT"] {
+        if parse_count(matches, name) == Some(0) {
+            eprintln!("Error: Invalid value for {}: 0", name.to_lowercase().replace('_', "-"));
+            process::exit(1);
+        }
+    }
+
+    // The best match of a word is its top 1, where the heap breaks ties by the partner index
+    let top_k = if matches.is_present("BEST_ONLY") {
+        Some(1)
+    } else {
+        parse_count(matches, "TOP_K")
+    };
+    let limit = parse_count(matches, "LIMIT");
+
+    let input_options = InputOptions {
+        format: matches.value_of("INPUT_FORMAT").unwrap().parse().unwrap(),
+        encoding: matches.value_of("INPUT_ENCODING").unwrap().parse().unwrap(),
+        preprocessing: parse_preprocessing(matches),
+        empty_policy: matches.value_of("EMPTY_POLICY").unwrap().parse().unwrap(),
+        allow_single_word: matches.is_present("ALLOW_SINGLE_WORD"),
+        read_buffer_size: parse_buffer_size(matches, "READ_BUFFER"),
+        token_regex: parse_token_regex(matches),
+        progress_interval: parse_progress_interval(matches),
+        hide_progress: matches.is_present("PROGRESS_JSON"),
+        mmap: matches.is_present("MMAP"),
+        lossy: matches.is_present("LOSSY"),
+        record_fields,
+        line_range: parse_line_range(matches),
+    };
+
+    if input_options.lossy && input_options.encoding != InputEncoding::Utf8 {
+        eprintln!("Error: --lossy only applies to UTF-8 input, every byte is a character in {}", input_options.encoding);
+        process::exit(1);
+    }
+
+    let mut output_options = OutputOptions {
+        format: matches.value_of("FORMAT").unwrap().parse().unwrap(),
+        align: matches.is_present("ALIGN"),
+        extra_algorithms: algorithms[1..].to_vec(),
+        ids: None,
+        permille: matches.is_present("PERMILLE"),
+        batch_start: None,
+        write_buffer_size: parse_buffer_size(matches, "WRITE_BUFFER"),
+        group_by_word: matches.is_present("GROUP_BY_WORD"),
+        show_alignment: matches.is_present("SHOW_ALIGNMENT"),
+        max_output_bytes: parse_count(matches, "MAX_OUTPUT_BYTES").map(|bytes| bytes as u64),
+        append: matches.is_present("APPEND"),
+        source_lines: None,
+        raw_scorer: matches.is_present("COMPARE_NORMALIZED_AND_RAW").then(|| scorer.clone()),
+        with_hash: matches.is_present("WITH_HASH"),
+        metric: matches.is_present("WITH_METRIC").then(|| scorer.metric_name()),
+        fallback_metric: fallback.as_ref().map(|fallback| fallback.scorer.metric_name()),
+        with_edits: matches.is_present("WITH_EDITS"),
+        with_rank: matches.is_present("WITH_RANK"),
+        crlf: matches.is_present("CRLF"),
+        partners_per_word: top_k.is_some(),
+    };
+
+    if matches.is_present("CANONICALIZE") && output_options.format != OutputFormat::Text {
+        eprintln!("Error: --canonicalize only writes the text format");
+        process::exit(1);
+    }
+    if output_options.append && output_options.format == OutputFormat::Dot {
+        eprintln!("Error: --append cannot be used with the dot format, which must be a single graph");
+        process::exit(1);
+    }
+    if output_options.group_by_word && output_options.format != OutputFormat::Text {
+        eprintln!("Error: --group-by-word only applies to the text format");
+        process::exit(1);
+    }
+    if output_options.with_rank && !output_options.group_by_word && top_k.is_none() {
+        eprintln!("Error: --with-rank needs --group-by-word, --top-k or --best-only to rank the matches of every word");
+        process::exit(1);
+    }
 
     let start = Instant::now(); // Start the timer
 
     // Read input file and obtain a vector of word pairs
-    let words = read_input_file(&input_path).expect("Error: Failed to read the input file.");
+    let (mut words, mut dropped) = match read_input_file_with_lines(input_path, &input_options) {
+        Ok((words, lines, dropped)) => {
+            if input_options.token_regex.is_some() || input_options.line_range.is_some() || dropped > 0 {
+                output_options.source_lines = Some(lines);
+            }
+            (words, dropped)
+        }
+        Err(e) => {
+            eprintln!("Error: Failed to read the input file: {}", e);
+            process::exit(input_error_code(&e));
+        }
+    };
+
+    if let Some(path) = matches.value_of("VERIFY") {
+        let sample_size = parse_count(matches, "VERIFY_SAMPLE").unwrap();
+        run_verify(Path::new(path), &words, &scorer, sample_size, &output_options);
+    }
+    if let Some(sample_size) = parse_count(matches, "SUGGEST_THRESHOLD") {
+        run_suggest_threshold(&words, &scorer, sample_size);
+    }
+
+    // Append the new batch after the canonical words and skip the canonical pairs
+    let mut filter = ComparisonFilter {
+        match_budget: parse_match_budget(matches),
+        window: parse_count(matches, "WINDOW"),
+        length_thresholds,
+        tie_seed: parse_tie_seed(matches),
+        distinct_numeric_suffix: matches.is_present("DISTINCT_NUMERIC_SUFFIX"),
+        min_prefix: parse_count(matches, "MIN_PREFIX"),
+        fallback,
+        ..ComparisonFilter::default()
+    };
+    if let Some(path) = matches.value_of("CACHE") {
+        match SimilarityCache::open(Path::new(path), &scorer) {
+            Ok(cache) => filter.cache = Some(Arc::new(cache)),
+            Err(e) => {
+                eprintln!("Error: Failed to read the cache file: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = matches.value_of("COMPARE_TO_SELF_FILE") {
+        match read_input_file_with_lines(Path::new(path), &input_options) {
+            Ok((batch, _, batch_dropped)) => {
+                filter.batch_start = Some(words.len());
+                output_options.batch_start = Some(words.len());
+                words.extend(batch);
+                dropped += batch_dropped;
+            }
+            Err(e) => {
+                eprintln!("Error: Failed to read the batch file: {}", e);
+                process::exit(input_error_code(&e));
+            }
+        }
+    }
+    if dropped > 0 {
+        eprintln!("Warning: Dropped {} words that are empty after preprocessing", dropped);
+    }
+    if words.len() == 1 {
+        eprintln!("Warning: The input file contains a single word, so there are no pairs to compare");
+    }
+
+    // Read the external IDs of the words if given
+    if let Some(path) = matches.value_of("IDS_FILE") {
+        if dropped > 0 {
+            eprintln!("Error: --ids-file needs an ID for every word, use --empty-policy keep or error to keep the empty words");
+            process::exit(1);
+        }
+        match read_ids_file(Path::new(path), words.len()) {
+            Ok(ids) => output_options.ids = Some(ids),
+            Err(e) => {
+                eprintln!("Error: Failed to read the IDs file: {}", e);
+                process::exit(input_error_code(&e));
+            }
+        }
+    }
+
+    // Only compare the pairs of the source words if given
+    if let Some(path) = matches.value_of("SOURCES_FILE") {
+        let source_options = InputOptions {
+            allow_single_word: true,
+            hide_progress: true,
+            line_range: None,
+            ..input_options.clone()
+        };
+        let sources = match read_input_file(Path::new(path), &source_options) {
+            Ok(sources) => sources,
+            Err(e) => {
+                eprintln!("Error: Failed to read the sources file: {}", e);
+                process::exit(input_error_code(&e));
+            }
+        };
+        let (flags, unmatched) = match_sources(&words, &sources);
+        if !unmatched.is_empty() {
+            let names: Vec<&str> = unmatched.iter().map(|word| word.original.as_str()).collect();
+            eprintln!("Warning: {} source words are not in the input: {}", unmatched.len(), names.join(", "));
+        }
+        if !flags.contains(&true) {
+            eprintln!("Error: None of the source words are in the input");
+            process::exit(1);
+        }
+        filter.sources = Some(flags);
+    }
+
+    // A window of 0 words would not compare any pair
+    if filter.window == Some(0) {
+        eprintln!("Error: The window must cover at least 1 word");
+        process::exit(1);
+    }
 
+    // The sorted neighborhood is the window over the words in sorted order, after the batch
+    // words are appended so they are sorted in between the canonical words
+    if let Some(size) = parse_count(matches, "SORTED_NEIGHBORHOOD") {
+        if size < 2 {
+            eprintln!("Error: The sorted neighborhood must hold at least 2 words");
+            process::exit(1);
+        }
+        filter.window = Some(size - 1);
+        filter.window_positions = Some(sorted_positions(&words));
+    }
+
+    if matches.is_present("HEATMAP") && words.len() > MAX_HEATMAP_WORDS {
+        eprintln!(
+            "Error: The heatmap is limited to {} words, but the input contains {} words",
+            MAX_HEATMAP_WORDS,
+            words.len()
+        );
+        process::exit(1);
+    }
+
+    if matches.is_present("VALIDATE_AGAINST_NAIVE") && words.len() > MAX_VALIDATE_WORDS {
+        eprintln!(
+            "Error: --validate-against-naive is limited to {} words, but the input contains {} words",
+            MAX_VALIDATE_WORDS,
+            words.len()
+        );
+        process::exit(1);
+    }
+
+    if matches.is_present("EXPLAIN") {
+        explain(matches, &scorer, &filter, &input_options, &output_options, &match_range);
+    }
+
+    // Group the exact duplicates first if requested, and only compare one word of every group
+    let exact_groups = matches.is_present("EXACT_GROUP_FIRST").then(|| group_exact_duplicates(&words));
+    let representatives: Vec<Word>;
+    let compared_words = match &exact_groups {
+        Some(groups) => {
+            representatives = groups.representatives.iter().map(|&index| words[index].clone()).collect();
+            &representatives
+        }
+        None => &words,
+    };
+    let profile_rows = parse_count(matches, "PROFILE");
+    if profile_rows.is_some() {
+        filter.row_times = Some(Arc::new(RowTimes::new(compared_words.len())));
+    }
+
+    // Keep only the best pairs while computing if requested, without building the matrix, and
+    // only compare the words within the window if the matrix is not needed either
+    let banded = filter.window.is_some()
+        && !["HEATMAP", "COUNT_ONLY", "INTERACTIVE"].iter().any(|&name| matches.is_present(name));
+    let compute_start = Instant::now();
+    let pairs = if top_k.is_some() || limit.is_some() {
+        let progress_bar = new_progress_bar(matches, "matrix", compared_words.len() as u64);
+        let pairs = calculate_top_pairs(
+            compared_words,
+            &scorer,
+            &filter,
+            &match_range,
+            top_k,
+            limit,
+            Some(&mut |done, _| progress_bar.set_position(done as u64)),
+        );
+        progress_bar.finish();
+        save_cache(matches, &filter);
+        pairs
+    } else if banded {
+        // Only the pairs within the window are visited, without the quadratic matrix
+        let progress_bar = new_progress_bar(matches, "matrix", compared_words.len() as u64);
+        let mut pairs = calculate_window_pairs(
+            compared_words,
+            &scorer,
+            &filter,
+            &match_range,
+            Some(&mut |done, _| progress_bar.set_position(done as u64)),
+        );
+        progress_bar.finish();
+        save_cache(matches, &filter);
+        pairs.retain(|pair| filter.meets_length_threshold(compared_words, pair));
+        pairs
+    } else {
+        compute_pairs(matches, compared_words, &scorer, &filter, &match_range, &output_options, start)
+    };
+    if matches.is_present("VALIDATE_AGAINST_NAIVE") {
+        let naive = naive_pairs(&words, &scorer, &filter, &match_range, top_k, limit);
+        validate_pairs(&naive, &pairs, &words, &output_options);
+    }
+    let mut pairs = pairs;
+    if let Some(sources) = &filter.sources {
+        orient_to_sources(&mut pairs, sources);
+    }
+    let mut pairs = match &exact_groups {
+        Some(groups) => groups.merge(pairs, &match_range),
+        None => pairs,
+    };
+    if let Some(seed) = filter.tie_seed {
+        shuffle_ties(&mut pairs, seed);
+    }
+    let pairs = match parse_count(matches, "MAX_MATCHES_PER_WORD") {
+        Some(max_matches) => cap_matches_per_word(pairs, words.len(), max_matches),
+        None => pairs,
+    };
+
+    if matches.is_present("COMPARISON_STATS") {
+        let performed = filter.counts.performed();
+        let pruned = filter.counts.pruned();
+        let seconds = compute_start.elapsed().as_secs_f64();
+        let throughput = if seconds > 0.0 { performed as f64 / seconds } else { 0.0 };
+        println!(
+            "Performed {} of {} comparisons (pruned {}), {:.0}/sec, counting {}.",
+            performed,
+            performed + pruned,
+            pruned,
+            throughput,
+            if banded { "every pair once" } else { "both orders of every pair" }
+        );
+        if let Some(cache) = &filter.cache {
+            println!("Found {} of the {} performed comparisons in the cache.", cache.hits(), performed);
+        }
+    }
+    if let (Some(count), Some(row_times)) = (profile_rows, &filter.row_times) {
+        println!("Slowest rows:");
+        for (index, elapsed) in row_times.slowest(count) {
+            // With exact groups, the rows are the representatives of the groups
+            let row = exact_groups.as_ref().map_or(index, |groups| groups.representatives[index]);
+            println!("Row {}: {}\t{:?}", row + 1, words[row].original, elapsed);
+        }
+    }
+
+    write_pairs(matches, &pairs, &words, &output_options, &match_range, start);
+}
+
+/// Build the full similarity matrix and collect the pairs from it.
+///
+/// The count-only and interactive modes work on the matrix and exit here when done.
+fn compute_pairs(
+    matches: &ArgMatches,
+    words: &[Word],
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    match_range: &RangeInclusive<f64>,
+    output_options: &OutputOptions,
+    start: Instant,
+) -> Vec<SimilarityPair> {
     // Calculate similarity matrix
-    let similarity_matrix = calculate_similarity_matrix(&words);
+    let progress_bar = new_progress_bar(matches, "matrix", words.len() as u64);
+    let similarity_matrix = calculate_similarity_matrix(
+        words,
+        scorer,
+        filter,
+        Some(&mut |done, _| progress_bar.set_position(done as u64)),
+    );
+    progress_bar.finish();
+    save_cache(matches, filter);
+
+    if let Some(path) = matches.value_of("HEATMAP") {
+        if let Err(e) = write_heatmap_file(Path::new(path), &similarity_matrix) {
+            eprintln!("Error: Failed to write the heatmap: {}", e);
+            process::exit(1);
+        }
+    }
+
+    // Only report the pair counts if requested
+    if matches.is_present("COUNT_ONLY") {
+        let mut thresholds = Vec::new();
+        for value in matches.values_of("COUNT_ONLY").into_iter().flatten() {
+            match value.trim().parse::<f64>() {
+                Ok(percentage) => thresholds.push(percentage / 100.0),
+                Err(_) => {
+                    eprintln!("Error: Invalid count-only percentage: {}", value);
+                    process::exit(1);
+                }
+            }
+        }
+        if thresholds.is_empty() {
+            thresholds.push(*match_range.start());
+        }
+
+        let counts = count_pairs(&similarity_matrix, &thresholds);
+        for (threshold, count) in thresholds.iter().zip(counts) {
+            println!("Pairs with similarity >= {:.2}%: {}", threshold * 100.0, count);
+        }
+
+        println!("Time elapsed: {:?}", start.elapsed()); // Print out the elapsed time
+        process::exit(0);
+    }
+
+    // Explore thresholds on the in-memory matrix if requested
+    if matches.is_present("INTERACTIVE") {
+        if let Err(e) = run_interactive(&similarity_matrix, words, output_options) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
 
+    let mut pairs = collect_pairs(&similarity_matrix, match_range);
+    pairs.retain(|pair| filter.meets_length_threshold(words, pair));
+    if let Some(fallback) = &filter.fallback {
+        pairs.extend(fallback_pairs(&similarity_matrix, words, fallback, match_range));
+        // Stable, so the pairs of the first algorithm stay ahead at equal similarity
+        pairs.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    }
+    pairs
+}
+
+/// Append the similarities computed in this run to the cache file, if a cache is used.
+fn save_cache(matches: &ArgMatches, filter: &ComparisonFilter) {
+    if let (Some(cache), Some(path)) = (&filter.cache, matches.value_of("CACHE")) {
+        if let Err(e) = cache.save(Path::new(path)) {
+            eprintln!("Error: Failed to write the cache file: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Write the pairs to the output file and report on them.
+fn write_pairs(
+    matches: &ArgMatches,
+    pairs: &[SimilarityPair],
+    words: &[Word],
+    output_options: &OutputOptions,
+    match_range: &RangeInclusive<f64>,
+    start: Instant,
+) {
     // Write similarity matrix to output file
-    if let Err(e) = write_output_file(&output_path, &similarity_matrix, &words, min_match) {
+    let output_path = Path::new(matches.value_of("OUTPUT").unwrap());
+    if let Err(e) = ensure_output_dir(output_path, matches.is_present("MKDIR")) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+    let pair_count = pairs.len();
+
+    let result = if matches.is_present("CANONICALIZE") {
+        let strategy = matches.value_of("CANONICAL_STRATEGY").unwrap().parse().unwrap();
+        let clusters = canonicalize(words, pairs, strategy);
+        write_clusters_file(output_path, &clusters, words, output_options)
+    } else if let Some(shards) = parse_count(matches, "OUTPUT_SHARDS") {
+        let progress_bar = new_progress_bar(matches, "write", shards as u64);
+        let result = write_output_shards(
+            output_path,
+            pairs,
+            words,
+            output_options,
+            shards,
+            Some(&mut |written, _| progress_bar.set_position(written as u64)),
+        );
+        progress_bar.finish();
+        result
+    } else {
+        let progress_bar = new_progress_bar(matches, "write", 0);
+        let result = write_output_file(
+            output_path,
+            pairs,
+            words,
+            output_options,
+            Some(&mut |written, total| {
+                progress_bar.set_length(total as u64);
+                progress_bar.set_position(written as u64);
+            }),
+        );
+        progress_bar.finish();
+        result
+    };
+    if let Err(e) = result {
+        if let Some(truncated) = e.downcast_ref::<OutputTruncated>() {
+            eprintln!("Error: {}, as requested with --max-output-bytes", truncated);
+            process::exit(EXIT_OUTPUT_TRUNCATED);
+        }
         eprintln!("Error writing output file: {}", e);
         process::exit(1);
     }
+    if pair_count == 0 && match_range.end().is_finite() {
+        eprintln!(
+            "No pairs between {:.2}% and {:.2}% found among {} words",
+            match_range.start() * 100.0,
+            match_range.end() * 100.0,
+            words.len()
+        );
+    } else if pair_count == 0 {
+        eprintln!(
+            "No pairs above {:.2}% found among {} words",
+            match_range.start() * 100.0,
+            words.len()
+        );
+    }
+
+    // Report the summary statistics of the written pairs if requested
+    if matches.is_present("STATS") {
+        let report = match Statistics::from_sorted_pairs(pairs) {
+            Some(statistics) => statistics.to_string(),
+            None => "Pairs: 0".to_string(),
+        };
+        match matches.value_of("STATS_OUT") {
+            Some(path) => {
+                if let Err(e) = fs::write(path, format!("{}\n", report)) {
+                    eprintln!("Error writing statistics file: {}", e);
+                    process::exit(1);
+                }
+            }
+            None => eprintln!("{}", report),
+        }
+    }
+
+    if let Some(path) = matches.value_of("REPORT_HTML") {
+        if let Err(e) = write_report_file(Path::new(path), pairs, words, output_options) {
+            eprintln!("Error writing HTML report: {}", e);
+            process::exit(1);
+        }
+    }
+    if let (Some(path), Some(batch_start)) = (matches.value_of("CROSSWALK"), output_options.batch_start) {
+        if let Err(e) = write_crosswalk_file(Path::new(path), pairs, words, batch_start, output_options) {
+            eprintln!("Error writing crosswalk file: {}", e);
+            process::exit(1);
+        }
+    }
+
+    if matches.is_present("CHECKSUM") {
+        print_checksums(output_path, parse_count(matches, "OUTPUT_SHARDS"));
+    }
 
     println!("Time elapsed: {:?}", start.elapsed()); // Print out the elapsed time
+
+    // Fail the run for CI-style checks if requested
+    if (matches.is_present("FAIL_ON_MATCH") && pair_count > 0)
+        || (matches.is_present("FAIL_ON_EMPTY") && pair_count == 0)
+    {
+        process::exit(EXIT_CHECK_FAILED);
+    }
 }
 
-/// Read the input file and return a vector of word pairs.
-fn read_input_file(
-    input_path: &Path,
-) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
-    let file = File::open(input_path)?;
-    let reader = BufReader::new(file);
+/// Parse an optional non-negative integer argument, exiting with an error if it is invalid.
+fn parse_count(matches: &ArgMatches, name: &str) -> Option<usize> {
+    let value = matches.value_of(name)?;
+    match value.parse::<usize>() {
+        Ok(count) => Some(count),
+        Err(_) => {
+            eprintln!("Error: Invalid value for {}: {}", name.to_lowercase().replace('_', "-"), value);
+            process::exit(1);
+        }
+    }
+}
 
-    let mut words = Vec::new();
-    let progress_bar = ProgressBar::new(0);
-    progress_bar.set_style(ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
-        .progress_chars("#>-"));
+/// Compare the optimized pairs with the naive pairs, and exit with `EXIT_CHECK_FAILED` after
+/// listing the divergences if there are any.
+fn validate_pairs(naive: &[SimilarityPair], optimized: &[SimilarityPair], words: &[Word], options: &OutputOptions) {
+    let divergences = compare_pairs(naive, optimized);
+    if divergences.is_empty() {
+        println!("Validated {} pairs against the naive computation.", optimized.len());
+        return;
+    }
 
-    for (index, line) in reader.lines().enumerate() {
-        let line = line?;
+    let similarity = |similarity: Option<f64>| similarity.map_or("missing".to_string(), |s| format_similarity(s, options));
+    eprintln!(
+        "Error: The optimized computation diverges from the naive computation in {} pairs:",
+        divergences.len()
+    );
+    for divergence in &divergences {
+        eprintln!(
+            "{}\t{}\tnaive {}, optimized {}",
+            word_column(divergence.index_a, words, options),
+            word_column(divergence.index_b, words, options),
+            similarity(divergence.naive),
+            similarity(divergence.optimized)
+        );
+    }
+    process::exit(EXIT_CHECK_FAILED);
+}
 
-        // Check for empty lines
-        if line.is_empty() {
-            return Err("Empty lines are not allowed in the input file".into());
+/// Verify a sample of the pairs in an existing output file and exit.
+///
+/// Exits with `EXIT_CHECK_FAILED` if any recomputed similarity does not match.
+fn run_verify(
+    path: &Path,
+    words: &[Word],
+    scorer: &Scorer,
+    sample_size: usize,
+    options: &OutputOptions,
+) -> ! {
+    let pairs = match read_reported_pairs(path, words, options.source_lines.as_deref()) {
+        Ok(pairs) => pairs,
+        Err(e) => {
+            eprintln!("Error: Failed to read the output file to verify: {}", e);
+            process::exit(1);
         }
+    };
 
-        // Preprocess line and store original and processed word in a tuple
-        let processed_line = line.to_lowercase();
-        words.push((processed_line.clone(), line));
+    let report = verify_sample(&pairs, words, scorer, sample_size);
+    for discrepancy in &report.discrepancies {
+        let pair = discrepancy.pair;
+        println!(
+            "Line {}: {}\t{}\treported {}, recomputed {}",
+            pair.line,
+            word_column(pair.index_a, words, options),
+            word_column(pair.index_b, words, options),
+            format_similarity(pair.similarity, options),
+            format_similarity(discrepancy.recomputed, options)
+        );
+    }
+    println!(
+        "Verified {} of {} pairs: {} discrepancies",
+        report.checked,
+        report.total,
+        report.discrepancies.len()
+    );
 
-        progress_bar.set_length((index + 1) as u64);
+    if report.discrepancies.is_empty() {
+        process::exit(0);
     }
+    process::exit(EXIT_CHECK_FAILED);
+}
 
-    progress_bar.finish();
+/// Print a minimum match suggested by the similarities of a sample of the pairs and exit.
+fn run_suggest_threshold(words: &[Word], scorer: &Scorer, sample_size: usize) -> ! {
+    let similarities = sample_similarities(words, scorer, sample_size);
+    match suggest_threshold(&similarities) {
+        Some(suggestion) => {
+            println!("Suggested --min-match: {:.1}", suggestion.threshold * 100.0);
+            println!(
+                "The {} sampled similarities have a valley at {:.1}% between the bulk of the pairs around {:.1}% and a second group around {:.1}%; {} of the sampled pairs ({:.2}%) are at or above it.",
+                suggestion.sampled,
+                suggestion.threshold * 100.0,
+                suggestion.lower_peak * 100.0,
+                suggestion.upper_peak * 100.0,
+                suggestion.above,
+                suggestion.above as f64 / suggestion.sampled as f64 * 100.0
+            );
+        }
+        None => println!(
+            "No natural gap in the {} sampled similarities: they do not separate into unrelated pairs and matches, so there is no threshold to suggest.",
+            similarities.len()
+        ),
+    }
+    process::exit(0);
+}
 
-    // Check for too few or too many words
-    let word_count = words.len();
-    const MIN_WORDS: usize = 2;
-    const MAX_WORDS: usize = 500_000;
-    if word_count < MIN_WORDS || word_count > MAX_WORDS {
-        return Err(format!(
-            "Invalid number of words: {}. The input file must contain between {} and {} words.",
-            word_count, MIN_WORDS, MAX_WORDS
-        )
-        .into());
+/// Combine the preset and the individual preprocessing options; the options only turn toggles on.
+fn parse_preprocessing(matches: &ArgMatches) -> Preprocessing {
+    let preset = matches.value_of("PRESET").map(|name| name.parse::<Preset>().unwrap());
+    let base = preset.map(Preset::preprocessing).unwrap_or_default();
+    Preprocessing {
+        disabled: matches.is_present("NO_PREPROCESS"),
+        case_sensitive: base.case_sensitive || matches.is_present("CASE_SENSITIVE"),
+        language: matches.value_of("LANG").map(|name| name.parse().unwrap()),
+        ascii_fold: base.ascii_fold || matches.is_present("ASCII_FOLD"),
+        strip_accents: base.strip_accents || matches.is_present("STRIP_ACCENTS"),
+        strip_punctuation: base.strip_punctuation || matches.is_present("STRIP_PUNCTUATION"),
+        ignore_numbers: base.ignore_numbers || matches.is_present("IGNORE_NUMBERS"),
+        collapse_whitespace: base.collapse_whitespace || matches.is_present("COLLAPSE_WHITESPACE"),
+        sort_characters: base.sort_characters || matches.is_present("ANAGRAM"),
+        reverse: base.reverse || matches.is_present("REVERSE"),
+    }
+}
+
+/// Compile the --token-regex pattern, exiting with a clear message if it is invalid.
+fn parse_token_regex(matches: &ArgMatches) -> Option<Regex> {
+    let pattern = matches.value_of("TOKEN_REGEX")?;
+    match Regex::new(pattern) {
+        Ok(regex) => Some(regex),
+        Err(e) => {
+            eprintln!("Error: Invalid token regex: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Parse the record fields of --compare-field-pairs and --field-delimiter, exiting if they are
+/// invalid.
+fn parse_record_fields(matches: &ArgMatches) -> Option<RecordFields> {
+    let spec = matches.value_of("COMPARE_FIELD_PAIRS")?;
+    let delimiter = match matches.value_of("FIELD_DELIMITER").unwrap_or(",") {
+        "\\t" => '\t',
+        value => {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(delimiter), None) => delimiter,
+                _ => {
+                    eprintln!("Error: The field delimiter must be a single character: {}", value);
+                    process::exit(1);
+                }
+            }
+        }
+    };
+    match RecordFields::parse(spec, delimiter) {
+        Ok(records) => Some(records),
+        Err(e) => {
+            eprintln!("Error: Invalid value for compare-field-pairs: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Parse the seed of --shuffle-ties, taking one from the clock and reporting it if --seed is
+/// not given, so the run can be repeated.
+fn parse_tie_seed(matches: &ArgMatches) -> Option<u64> {
+    if !matches.is_present("SHUFFLE_TIES") {
+        return None;
     }
+    match matches.value_of("SEED") {
+        Some(value) => match value.parse::<u64>() {
+            Ok(seed) => Some(seed),
+            Err(_) => {
+                eprintln!("Error: Invalid value for seed: {}", value);
+                process::exit(1);
+            }
+        },
+        None => {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_nanos() as u64);
+            eprintln!("Shuffling the ties with --seed {}", seed);
+            Some(seed)
+        }
+    }
+}
+
+/// Parse a percentage from 0 to 100 into a fraction, or `None` if it is not a number in that
+/// range, which rules out NaN and the infinities.
+fn parse_percentage(value: &str) -> Option<f64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|percentage| (0.0..=100.0).contains(percentage))
+        .map(|percentage| percentage / 100.0)
+}
+
+/// Parse the match budget of --match-budget and --good-enough, exiting if it is invalid.
+fn parse_match_budget(matches: &ArgMatches) -> Option<MatchBudget> {
+    let budget = parse_count(matches, "MATCH_BUDGET")?;
+    let value = matches.value_of("GOOD_ENOUGH").unwrap();
+    match parse_percentage(value) {
+        Some(good_enough) if budget > 0 => Some(MatchBudget {
+            good_enough,
+            matches: budget,
+        }),
+        Some(_) => {
+            eprintln!("Error: Invalid value for match-budget: {}", budget);
+            process::exit(1);
+        }
+        None => {
+            eprintln!("Error: Invalid value for good-enough: {}", value);
+            process::exit(1);
+        }
+    }
+}
+
+/// Print the effective configuration of a run to stderr, with all defaults applied.
+fn explain(
+    matches: &ArgMatches,
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    input_options: &InputOptions,
+    output_options: &OutputOptions,
+    match_range: &RangeInclusive<f64>,
+) {
+    let source = |name: &str| match matches.value_source(name) {
+        Some(ValueSource::DefaultValue) => " (default)",
+        Some(ValueSource::EnvVariable) => " (from the environment)",
+        _ => "",
+    };
+    let min_match_source = match matches.value_source("MIN_MATCH") {
+        None => format!(" (default for {})", scorer.algorithm),
+        Some(_) => source("MIN_MATCH").to_string(),
+    };
+    let enabled = |settings: &[(bool, &str)]| -> Vec<String> {
+        settings.iter().filter(|(on, _)| *on).map(|(_, name)| name.to_string()).collect()
+    };
+
+    eprintln!("Configuration:");
+    match &input_options.line_range {
+        Some(range) if *range.end() == usize::MAX => {
+            eprintln!("  Input: {}, from line {}", matches.value_of("INPUT").unwrap(), range.start())
+        }
+        Some(range) => eprintln!(
+            "  Input: {}, lines {} to {}",
+            matches.value_of("INPUT").unwrap(),
+            range.start(),
+            range.end()
+        ),
+        None => eprintln!("  Input: {}", matches.value_of("INPUT").unwrap()),
+    }
+    if input_options.format != InputFormat::Text {
+        eprintln!("  Input format: {}", input_options.format);
+    }
+    if input_options.encoding != InputEncoding::Utf8 {
+        eprintln!("  Input encoding: {}", input_options.encoding);
+    }
+    if let Some(path) = matches.value_of("COMPARE_TO_SELF_FILE") {
+        eprintln!("  Batch: {}", path);
+    }
+    if let (Some(path), Some(sources)) = (matches.value_of("SOURCES_FILE"), &filter.sources) {
+        let count = sources.iter().filter(|&&source| source).count();
+        eprintln!("  Sources: {} ({} words)", path, count);
+    }
+    if let Some(path) = matches.value_of("IDS_FILE") {
+        eprintln!("  IDs: {}", path);
+    }
+    match matches.value_of("OUTPUT") {
+        Some(path) => {
+            let mut format = vec![output_options.format.name().to_string()];
+            format.extend(enabled(&[
+                (output_options.align, "aligned"),
+                (output_options.group_by_word, "grouped by word"),
+                (output_options.permille, "permille"),
+                (output_options.show_alignment, "with alignment"),
+                (output_options.raw_scorer.is_some(), "with raw similarity"),
+                (output_options.with_hash, "with hashes"),
+                (output_options.metric.is_some(), "with metric"),
+                (output_options.with_edits, "with edit counts"),
+                (output_options.with_rank, "with ranks"),
+                (output_options.append, "appended"),
+                (output_options.crlf, "CRLF"),
+                (matches.is_present("CANONICALIZE"), "canonicalized"),
+            ]));
+            if let Some(shards) = parse_count(matches, "OUTPUT_SHARDS") {
+                format.push(format!("{} shards", shards));
+            }
+            if let Some(max_bytes) = output_options.max_output_bytes {
+                format.push(format!("at most {} bytes", max_bytes));
+            }
+            eprintln!("  Output: {} ({})", path, format.join(", "));
+        }
+        None => eprintln!("  Output: none"),
+    }
+    if let Some(path) = matches.value_of("REPORT_HTML") {
+        eprintln!("  HTML report: {}", path);
+    }
+    if let Some(path) = matches.value_of("CROSSWALK") {
+        eprintln!("  Crosswalk: {}", path);
+    }
+
+    eprintln!("  Algorithm: {}{}", scorer.algorithm, source("ALGORITHM"));
+    if !output_options.extra_algorithms.is_empty() {
+        let names: Vec<&str> =
+            output_options.extra_algorithms.iter().map(|algorithm| algorithm.name()).collect();
+        eprintln!("  Extra algorithms: {}", names.join(", "));
+    }
+    if let Some(path) = matches.value_of("SUBSTITUTION_COSTS") {
+        eprintln!("  Substitution costs: {}", path);
+    }
+    if let Some(max_edits) = scorer.max_edits {
+        eprintln!("  Maximum edits: {}", max_edits);
+    }
+    if let Some(path) = matches.value_of("IDF_FILE") {
+        eprintln!("  IDF weights: {}", path);
+    }
+    if let Some(penalty) = scorer.length_penalty {
+        eprintln!("  Length penalty: {}", penalty);
+    }
+    if !scorer.clamp_identical {
+        eprintln!("  Identical words: scored by the algorithm, not clamped to 100%");
+    }
+    if let Some(fallback) = &filter.fallback {
+        eprintln!(
+            "  Fallback: {} at {:.2}%{}",
+            fallback.scorer.algorithm,
+            fallback.min_match * 100.0,
+            match (matches.is_present("FALLBACK_MIN_MATCH"), matches.is_present("MIN_MATCH")) {
+                (true, _) => String::new(),
+                (false, true) => " (from --min-match)".to_string(),
+                (false, false) => format!(" (default for {})", fallback.scorer.algorithm),
+            }
+        );
+    }
+
+    if match_range.end().is_finite() {
+        eprintln!(
+            "  Match range: {:.2}%{} to {:.2}%",
+            match_range.start() * 100.0,
+            min_match_source,
+            match_range.end() * 100.0
+        );
+    } else {
+        eprintln!("  Minimum match: {:.2}%{}", match_range.start() * 100.0, min_match_source);
+    }
+    if let Some(spec) = matches.value_of("THRESHOLD_BY_LENGTH") {
+        eprintln!("  Minimum match by length: {}", spec);
+    }
+    eprintln!("  Preprocessing: {}", input_options.preprocessing);
+    if input_options.empty_policy != EmptyPolicy::Drop {
+        eprintln!("  Empty words: {}", input_options.empty_policy);
+    }
+    if let Some(regex) = &input_options.token_regex {
+        eprintln!("  Token regex: {}", regex);
+    }
+    if let Some(records) = &scorer.record_fields {
+        let fields: Vec<String> = records
+            .fields
+            .iter()
+            .map(|field| {
+                let algorithm = field.algorithm.unwrap_or(scorer.algorithm);
+                format!("field {} x{} ({})", field.field + 1, field.weight, algorithm)
+            })
+            .collect();
+        eprintln!("  Record fields: {} separated by {:?}", fields.join(", "), records.delimiter);
+    }
+
+    let mut selection = Vec::new();
+    if matches.is_present("BEST_ONLY") {
+        selection.push("best match per word".to_string());
+    } else if let Some(top_k) = parse_count(matches, "TOP_K") {
+        selection.push(format!("top {} per word", top_k));
+    }
+    if let Some(limit) = parse_count(matches, "LIMIT") {
+        selection.push(format!("best {} overall", limit));
+    }
+    if let Some(max_matches) = parse_count(matches, "MAX_MATCHES_PER_WORD") {
+        selection.push(format!("at most {} pairs per word", max_matches));
+    }
+    if let Some(seed) = filter.tie_seed {
+        selection.push(format!("ties shuffled with seed {}", seed));
+    }
+    match (filter.window, &filter.window_positions) {
+        (Some(window), Some(_)) => selection.push(format!("sorted neighborhood of {}", window + 1)),
+        (Some(window), None) => selection.push(format!("window of {}", window)),
+        _ => {}
+    }
+    if filter.distinct_numeric_suffix {
+        selection.push("without numeric suffix variants".to_string());
+    }
+    if let Some(length) = filter.min_prefix {
+        selection.push(format!("sharing a prefix of {}", length));
+    }
+    if let Some(budget) = filter.match_budget {
+        let good_enough = budget.good_enough * 100.0;
+        selection.push(format!("{} matches at {:.2}% per word", budget.matches, good_enough));
+    }
+    if matches.is_present("EXACT_GROUP_FIRST") {
+        selection.push("exact groups first".to_string());
+    }
+    if selection.is_empty() {
+        selection.push("all pairs".to_string());
+    }
+    eprintln!("  Pairs: {}", selection.join(", "));
+
+    if let Some(path) = matches.value_of("CACHE") {
+        eprintln!("  Cache: {}", path);
+    }
+    eprintln!("  Threads: {}", rayon::current_num_threads());
+}
+
+/// The exit code for an error reading an input file.
+fn input_error_code(error: &InputError) -> i32 {
+    if error.is_invalid_contents() {
+        EXIT_INVALID_INPUT
+    } else {
+        1
+    }
+}
+
+/// Parse an optional buffer size in KB and return it in bytes, exiting if it is invalid.
+fn parse_buffer_size(matches: &ArgMatches, name: &str) -> Option<usize> {
+    let kilobytes = parse_count(matches, name)?;
+    match kilobytes.checked_mul(1024) {
+        Some(bytes) if bytes > 0 => Some(bytes),
+        _ => {
+            eprintln!("Error: Invalid value for {}: {}", name.to_lowercase().replace('_', "-"), kilobytes);
+            process::exit(1);
+        }
+    }
+}
+
+/// Build the fallback scorer, which keeps the length penalty and the record fields of the
+/// scorer but none of the settings of a particular algorithm.
+fn parse_fallback(matches: &ArgMatches, scorer: &Scorer, min_match: f64) -> Option<Fallback> {
+    let algorithm: SimilarityAlgorithm = matches.value_of("FALLBACK_METRIC")?.parse().unwrap();
+    let min_match = match matches.value_of("FALLBACK_MIN_MATCH").map(parse_percentage) {
+        Some(Some(min_match)) => min_match,
+        Some(None) => {
+            let value = matches.value_of("FALLBACK_MIN_MATCH").unwrap();
+            eprintln!("Error: Invalid value for fallback-min-match: {}", value);
+            process::exit(1);
+        }
+        None if matches.value_source("MIN_MATCH").is_some() => min_match,
+        None => algorithm.default_threshold(),
+    };
+    let scorer = Scorer {
+        algorithm,
+        length_penalty: scorer.length_penalty,
+        record_fields: scorer.record_fields.clone(),
+        clamp_identical: scorer.clamp_identical,
+        ..Scorer::default()
+    };
+    Some(Fallback { scorer, min_match })
+}
+
+/// Parse the 1-based range of input lines to read, exiting if it starts at 0 or is empty.
+fn parse_line_range(matches: &ArgMatches) -> Option<RangeInclusive<usize>> {
+    let from = parse_count(matches, "LINES_FROM");
+    let to = parse_count(matches, "LINES_TO");
+    if from.is_none() && to.is_none() {
+        return None;
+    }
+    let range = from.unwrap_or(1)..=to.unwrap_or(usize::MAX);
+    if *range.start() == 0 || range.is_empty() {
+        eprintln!(
+            "Error: Invalid line range: {} to {}",
+            range.start(),
+            to.map_or("the end".to_string(), |to| to.to_string())
+        );
+        process::exit(1);
+    }
+    Some(range)
+}
+
+/// Parse the progress bar redraw interval, which indicatif takes as a whole number of redraws
+/// per second.
+fn parse_progress_interval(matches: &ArgMatches) -> Option<u64> {
+    let interval = parse_count(matches, "PROGRESS_INTERVAL")?;
+    if !(1..=1000).contains(&interval) {
+        eprintln!("Error: Invalid value for progress-interval: {}. It must be between 1 and 1000.", interval);
+        process::exit(1);
+    }
+    Some(interval as u64)
+}
+
+/// Number of pairs shown for every threshold entered in interactive mode.
+const INTERACTIVE_TOP_PAIRS: usize = 10;
+
+/// Prompt for thresholds and show the match count and top pairs for each, until quit.
+fn run_interactive(
+    matrix: &[Vec<f64>],
+    words: &[Word],
+    options: &OutputOptions,
+) -> io::Result<()> {
+    println!("Enter a minimum match percentage, or 'quit' to exit.");
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()), // End of input
+        };
+        let input = line.trim();
 
-    Ok(words)
+        if input.is_empty() {
+            continue;
+        }
+        if input == "quit" || input == "exit" {
+            return Ok(());
+        }
+
+        let percentage = match input.trim_end_matches('%').parse::<f64>() {
+            Ok(percentage) => percentage,
+            Err(_) => {
+                println!("Not a percentage: {}", input);
+                continue;
+            }
+        };
+
+        let pairs = collect_pairs(matrix, &(percentage / 100.0..=f64::INFINITY));
+        println!("{} pairs with similarity >= {:.2}%", pairs.len(), percentage);
+        for pair in pairs.iter().take(INTERACTIVE_TOP_PAIRS) {
+            println!(
+                "{}\t{}\tSimilarity: {}",
+                word_column(pair.index_a(), words, options),
+                word_column(pair.index_b(), words, options),
+                format_similarity(pair.similarity, options)
+            );
+        }
+    }
+}
+
+/// Enable or disable the colors of the progress bars for the --color choice.
+///
+/// The bars are drawn on stderr but styled like stdout, so the stderr setting decides both.
+fn set_colors(choice: &str) {
+    let enabled = match choice {
+        "always" => true,
+        "never" => false,
+        _ => console::colors_enabled_stderr(),
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
 }
 
-/// Calculate the similarity matrix using the normalized Levenshtein distance.
-fn calculate_similarity_matrix(words: &[(String, String)]) -> Vec<Vec<f64>> {
-    let progress_bar = ProgressBar::new(0);
+/// Create a progress bar in the style used for every phase of a run, redrawn at most once
+/// per `--progress-interval`.
+fn new_progress_bar(matches: &ArgMatches, phase: &'static str, len: u64) -> Progress {
+    if matches.is_present("PROGRESS_JSON") {
+        let interval = parse_progress_interval(matches).unwrap_or(1000);
+        return Progress::Json(JsonProgress {
+            phase,
+            interval: Duration::from_millis(interval),
+            state: Mutex::new(JsonProgressState {
+                done: 0,
+                total: len,
+                last_event: None,
+                last_counts: None,
+            }),
+        });
+    }
+
+    let progress_bar = ProgressBar::new(len);
     progress_bar.set_style(ProgressStyle::default_bar()
         .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
         .progress_chars("#>-"));
+    if let Some(interval) = parse_progress_interval(matches) {
+        progress_bar.set_draw_target(ProgressDrawTarget::stderr_with_hz(1000 / interval));
+    }
+    Progress::Bar(progress_bar)
+}
 
-    let res = words
-        .par_iter()
-        .map(|(processed_word1, _)| {
-            progress_bar.set_length(words.len() as u64);
-            progress_bar.inc(1);
-
-            words
-                .iter()
-                .map(|(processed_word2, _)| {
-                    normalized_levenshtein(&processed_word1, &processed_word2)
-                })
-                .collect::<Vec<f64>>()
-        })
-        .collect::<Vec<Vec<f64>>>();
+/// The progress of a phase of the run, drawn as a bar or written as JSON lines.
+enum Progress {
+    Bar(ProgressBar),
+    Json(JsonProgress),
+}
 
-    progress_bar.finish();
-    res
+/// Progress written to stderr as one JSON object per line, for job runners showing the
+/// progress in their own UI.
+struct JsonProgress {
+    phase: &'static str,
+    /// Minimum time between two events, except for the last one.
+    interval: Duration,
+    state: Mutex<JsonProgressState>,
 }
 
-/// Write the similarity matrix to the output file.
-fn write_output_file(
-    path: &Path,
-    matrix: &[Vec<f64>],
-    words: &[(String, String)],
-    min_match: f64,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let file = File::create(path)?;
-    let mut writer = BufWriter::new(file);
+struct JsonProgressState {
+    done: u64,
+    total: u64,
+    last_event: Option<Instant>,
+    /// The counts of the last event, so finishing does not repeat it.
+    last_counts: Option<(u64, u64)>,
+}
+
+impl Progress {
+    fn set_length(&self, total: u64) {
+        match self {
+            Progress::Bar(progress_bar) => progress_bar.set_length(total),
+            Progress::Json(json) => json.state.lock().unwrap().total = total,
+        }
+    }
 
-    // Collect all pairs in a vector
-    let mut all_pairs: Vec<(f64, usize, &str, usize, &str)> = Vec::new();
+    fn set_position(&self, done: u64) {
+        match self {
+            Progress::Bar(progress_bar) => progress_bar.set_position(done),
+            Progress::Json(json) => {
+                let mut state = json.state.lock().unwrap();
+                state.done = done;
+                if state.last_event.is_none_or(|last| last.elapsed() >= json.interval) {
+                    json.emit(&mut state);
+                }
+            }
+        }
+    }
 
-    for (i, row) in matrix.iter().enumerate() {
-        for (j, value) in row.iter().enumerate() {
-            if i >= j {
-                continue; // Skip if the index of the first word is greater than or equal to the index of the second word
+    /// Finish the phase, making sure the last event has the final counts.
+    fn finish(&self) {
+        match self {
+            Progress::Bar(progress_bar) => progress_bar.finish(),
+            Progress::Json(json) => {
+                let mut state = json.state.lock().unwrap();
+                if state.last_counts != Some((state.done, state.total)) {
+                    json.emit(&mut state);
+                }
             }
-            if *value >= min_match {
-                let (_, original_word1) = &words[i];
-                let (_, original_word2) = &words[j];
-                all_pairs.push((*value, i + 1, original_word1, j + 1, original_word2));
+        }
+    }
+}
+
+impl JsonProgress {
+    fn emit(&self, state: &mut JsonProgressState) {
+        eprintln!(
+            "{{\"phase\":\"{}\",\"done\":{},\"total\":{}}}",
+            self.phase, state.done, state.total
+        );
+        state.last_event = Some(Instant::now());
+        state.last_counts = Some((state.done, state.total));
+    }
+}
+
+/// Print the SHA-256 checksum of the output file, or of every shard, to stderr in the format
+/// of `sha256sum`, so the lines can be checked with `sha256sum -c`.
+fn print_checksums(output_path: &Path, shards: Option<usize>) {
+    let paths = match shards {
+        Some(shards) => (0..shards).map(|shard| shard_path(output_path, shard)).collect(),
+        None => vec![output_path.to_path_buf()],
+    };
+    for path in paths {
+        match file_sha256(&path) {
+            Ok(checksum) => eprintln!("{}  {}", checksum, path.display()),
+            Err(e) => {
+                eprintln!("Error: Failed to read {} for its checksum: {}", path.display(), e);
+                process::exit(1);
             }
         }
     }
+}
+
+/// Make sure the directory the output file goes into exists, creating it if allowed.
+fn ensure_output_dir(path: &Path, create: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => return Ok(()), // Relative file name in the current directory
+    };
+
+    if parent.is_dir() {
+        return Ok(());
+    }
+
+    if create {
+        fs::create_dir_all(parent).map_err(|e| {
+            format!("Failed to create output directory {}: {}", parent.display(), e)
+        })?;
+        return Ok(());
+    }
+
+    Err(format!(
+        "Output directory {} does not exist. Use --mkdir to create it.",
+        parent.display()
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix_matches(args: &[&str]) -> clap::Result<ArgMatches> {
+        let argv = ["matrix", "in.txt", "out.txt"].iter().chain(args);
+        matrix_command().try_get_matches_from(argv)
+    }
+
+    #[test]
+    fn inserts_the_default_subcommand() {
+        let rewrite = |args: &[&str], files: &[&str]| {
+            let args = args.iter().map(OsString::from).collect();
+            let rewritten = with_default_subcommand(args, |arg| files.iter().any(|file| arg == *file));
+            rewritten.into_iter().map(|arg| arg.into_string().unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(rewrite(&["ws", "in.txt", "out.txt"], &[]), ["ws", "matrix", "in.txt", "out.txt"]);
+        assert_eq!(rewrite(&["ws", "-m", "90", "in.txt", "out.txt"], &[]), ["ws", "matrix", "-m", "90", "in.txt", "out.txt"]);
+        assert_eq!(rewrite(&["ws", "cluster", "out.txt", "clusters.txt"], &[]), ["ws", "cluster", "out.txt", "clusters.txt"]);
+        assert_eq!(rewrite(&["ws", "from-matrix", "m.csv", "out.txt"], &[]), ["ws", "from-matrix", "m.csv", "out.txt"]);
+        for flag in ["help", "-h", "--help", "-V", "--version"] {
+            assert_eq!(rewrite(&["ws", flag], &[]), ["ws", flag]);
+        }
+        assert_eq!(rewrite(&["ws"], &[]), ["ws"]);
+        assert!(rewrite(&[], &[]).is_empty());
 
-    // Sort pairs in descending order of similarity
-    all_pairs.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        // An input file with the name of a subcommand keeps working without a subcommand
+        assert_eq!(rewrite(&["ws", "cluster", "out.txt"], &["cluster"]), ["ws", "matrix", "cluster", "out.txt"]);
+        assert_eq!(rewrite(&["ws", "help", "out.txt"], &["help"]), ["ws", "matrix", "help", "out.txt"]);
+    }
 
-    // Write sorted pairs to the output file
-    for pair in all_pairs {
-        writeln!(
-            writer,
-            "Row {}: {}\tRow {}: {}\tSimilarity: {:.2}%",
-            pair.1,
-            pair.2,
-            pair.3,
-            pair.4,
-            pair.0 * 100.0
-        )?;
+    #[test]
+    fn parses_percentages_in_range() {
+        assert_eq!(parse_percentage("80"), Some(0.8));
+        assert_eq!(parse_percentage("0"), Some(0.0));
+        assert_eq!(parse_percentage("100"), Some(1.0));
+        for value in ["150", "-1", "NaN", "inf", "-inf", "9o", ""] {
+            assert_eq!(parse_percentage(value), None, "{}", value);
+        }
     }
 
-    Ok(())
+    #[test]
+    fn options_extend_the_preset() {
+        let preprocessing = |args: &[&str]| parse_preprocessing(&matrix_matches(args).unwrap()).to_string();
+        assert_eq!(preprocessing(&[]), "lowercase");
+        assert_eq!(preprocessing(&["--preset", "code-matching"]), "collapse-whitespace");
+        assert_eq!(
+            preprocessing(&["--preset", "code-matching", "--strip-punctuation"]),
+            "strip-punctuation, collapse-whitespace"
+        );
+        // Options only turn steps on, so the preset keeps its own steps
+        assert_eq!(
+            preprocessing(&["--preset", "name-matching", "--case-sensitive", "--reverse"]),
+            "strip-accents, strip-punctuation, collapse-whitespace, reverse"
+        );
+        assert_eq!(preprocessing(&["--no-preprocess"]), "none");
+
+        assert!(matrix_matches(&["--preset", "fuzzy"]).is_err());
+        assert!(matrix_matches(&["--preset", "code-matching", "--no-preprocess"]).is_err());
+    }
 }
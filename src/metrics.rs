@@ -0,0 +1,786 @@
+//! The string similarity algorithms that can be used to compare words.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::records::RecordFields;
+use crate::{fnv1a, fnv1a_extend};
+use strsim::{jaro, jaro_winkler, normalized_damerau_levenshtein, normalized_levenshtein, sorensen_dice};
+
+/// A normalized string similarity algorithm, scoring pairs between 0.0 and 1.0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SimilarityAlgorithm {
+    #[default]
+    Levenshtein,
+    DamerauLevenshtein,
+    Jaro,
+    JaroWinkler,
+    SorensenDice,
+    /// Cosine similarity of the character bigram counts, see [`ngram_cosine`].
+    Cosine,
+}
+
+impl SimilarityAlgorithm {
+    /// All algorithms, in the order they are listed in the help text.
+    pub const ALL: [SimilarityAlgorithm; 6] = [
+        SimilarityAlgorithm::Levenshtein,
+        SimilarityAlgorithm::DamerauLevenshtein,
+        SimilarityAlgorithm::Jaro,
+        SimilarityAlgorithm::JaroWinkler,
+        SimilarityAlgorithm::SorensenDice,
+        SimilarityAlgorithm::Cosine,
+    ];
+
+    /// The name used for the algorithm on the command line and in the output.
+    pub fn name(self) -> &'static str {
+        match self {
+            SimilarityAlgorithm::Levenshtein => "levenshtein",
+            SimilarityAlgorithm::DamerauLevenshtein => "damerau-levenshtein",
+            SimilarityAlgorithm::Jaro => "jaro",
+            SimilarityAlgorithm::JaroWinkler => "jaro-winkler",
+            SimilarityAlgorithm::SorensenDice => "sorensen-dice",
+            SimilarityAlgorithm::Cosine => "cosine",
+        }
+    }
+
+    /// The minimum match used when none is given, as a fraction.
+    ///
+    /// The algorithms score the same pairs on different scales: Jaro-Winkler runs high, since
+    /// every word shares some characters with every other word within reach, while the bigram
+    /// algorithms run low, since a single edit breaks up to two bigrams. The defaults are set so
+    /// a typo of a single edit in a word of about eight letters matches with every algorithm.
+    pub fn default_threshold(self) -> f64 {
+        match self {
+            SimilarityAlgorithm::Levenshtein | SimilarityAlgorithm::DamerauLevenshtein => 0.8,
+            SimilarityAlgorithm::Jaro => 0.85,
+            SimilarityAlgorithm::JaroWinkler => 0.9,
+            SimilarityAlgorithm::SorensenDice | SimilarityAlgorithm::Cosine => 0.7,
+        }
+    }
+
+    /// Calculate the similarity between two words.
+    pub fn similarity(self, a: &str, b: &str) -> f64 {
+        match self {
+            SimilarityAlgorithm::Levenshtein => normalized_levenshtein(a, b),
+            SimilarityAlgorithm::DamerauLevenshtein => normalized_damerau_levenshtein(a, b),
+            SimilarityAlgorithm::Jaro => jaro(a, b),
+            SimilarityAlgorithm::JaroWinkler => jaro_winkler(a, b),
+            SimilarityAlgorithm::SorensenDice => sorensen_dice(a, b),
+            SimilarityAlgorithm::Cosine => ngram_cosine(a, b, None),
+        }
+    }
+}
+
+impl fmt::Display for SimilarityAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SimilarityAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SimilarityAlgorithm::ALL
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.name() == s)
+            .ok_or_else(|| format!("Unknown similarity algorithm: {}", s))
+    }
+}
+
+/// Costs for substituting one character with another in the weighted Levenshtein distance.
+///
+/// Costs are symmetric and between 0.0 and 1.0. Pairs that are not in the table cost 1.0,
+/// like in the regular Levenshtein distance.
+#[derive(Clone, Debug, Default)]
+pub struct SubstitutionCosts {
+    costs: HashMap<(char, char), f64>,
+}
+
+impl SubstitutionCosts {
+    /// Read a cost table from a file, see [`SubstitutionCosts::parse`] for the format.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)?;
+        SubstitutionCosts::parse(&contents)
+    }
+
+    /// A hash of the costs in the table, which only changes when the costs do.
+    pub fn fingerprint(&self) -> u64 {
+        let mut entries: Vec<(&(char, char), &f64)> = self.costs.iter().collect();
+        entries.sort_by_key(|(pair, _)| **pair);
+        entries.iter().fold(fnv1a(b"substitution costs"), |hash, ((a, b), cost)| {
+            let hash = fnv1a_extend(hash, &u32::from(*a).to_le_bytes());
+            let hash = fnv1a_extend(hash, &u32::from(*b).to_le_bytes());
+            fnv1a_extend(hash, &cost.to_bits().to_le_bytes())
+        })
+    }
+
+    /// Parse a cost table with one `a b cost` entry per line, e.g. `0 o 0.2`.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut costs = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let entry = match fields.as_slice() {
+                [a, b, cost] => single_char(a).zip(single_char(b)).zip(cost.parse::<f64>().ok()),
+                _ => None,
+            };
+            let ((a, b), cost) = entry.ok_or_else(|| {
+                format!(
+                    "Invalid substitution cost on line {}: expected 'a b cost', got '{}'",
+                    index + 1,
+                    line
+                )
+            })?;
+            if !(0.0..=1.0).contains(&cost) {
+                return Err(format!(
+                    "Invalid substitution cost on line {}: {} is not between 0 and 1",
+                    index + 1,
+                    cost
+                )
+                .into());
+            }
+
+            costs.insert((a, b), cost);
+            costs.insert((b, a), cost);
+        }
+
+        Ok(SubstitutionCosts { costs })
+    }
+
+    /// The cost of substituting `a` with `b`.
+    pub fn cost(&self, a: char, b: char) -> f64 {
+        if a == b {
+            0.0
+        } else {
+            self.costs.get(&(a, b)).copied().unwrap_or(1.0)
+        }
+    }
+}
+
+/// Return the character if the string consists of exactly one character.
+fn single_char(s: &str) -> Option<char> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+/// Calculate the Levenshtein distance with substitution costs taken from the table.
+///
+/// Insertions and deletions always cost 1.0.
+pub fn weighted_levenshtein(a: &str, b: &str, costs: &SubstitutionCosts) -> f64 {
+    let b_chars: Vec<char> = b.chars().collect();
+
+    // Single-row DP over the characters of b
+    let mut row: Vec<f64> = (0..=b_chars.len()).map(|j| j as f64).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = (i + 1) as f64;
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution = diagonal + costs.cost(a_char, *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1.0).min(row[j + 1] + 1.0);
+        }
+    }
+
+    row[b_chars.len()]
+}
+
+/// Calculate the weighted Levenshtein distance normalized to a similarity between 0.0 and 1.0.
+pub fn normalized_weighted_levenshtein(a: &str, b: &str, costs: &SubstitutionCosts) -> f64 {
+    let max_len = a.chars().count().max(b.chars().count());
+    if max_len == 0 {
+        return 1.0;
+    }
+    1.0 - weighted_levenshtein(a, b, costs) / max_len as f64
+}
+
+/// Calculate the Levenshtein distance if it is at most `max_edits`, or `None` otherwise.
+///
+/// Only the diagonal band of width `2 * max_edits + 1` of the DP table is filled, since an
+/// alignment with at most `max_edits` edits never leaves it. This takes O(k * n) instead of
+/// O(m * n) time and stops as soon as a whole row of the band exceeds `max_edits`.
+pub fn banded_levenshtein(a: &str, b: &str, max_edits: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max_edits {
+        return None;
+    }
+
+    // Cells outside the band are "too far"
+    let too_far = max_edits + 1;
+    let mut previous: Vec<usize> = (0..=b.len())
+        .map(|j| if j <= max_edits { j } else { too_far })
+        .collect();
+    let mut current = vec![too_far; b.len() + 1];
+
+    for i in 1..=a.len() {
+        let low = i.saturating_sub(max_edits).max(1);
+        let high = (i + max_edits).min(b.len());
+
+        current[0] = if i <= max_edits { i } else { too_far };
+        if low > 1 {
+            current[low - 1] = too_far;
+        }
+
+        let mut row_min = current[0];
+        for j in low..=high {
+            let substitution = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let value = substitution
+                .min(previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(too_far);
+            current[j] = value;
+            row_min = row_min.min(value);
+        }
+        if high < b.len() {
+            current[high + 1] = too_far;
+        }
+
+        // The minimum of a row never decreases, so this pair can no longer get within range
+        if row_min > max_edits {
+            return None;
+        }
+
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    Some(previous[b.len()]).filter(|distance| *distance <= max_edits)
+}
+
+/// Inverse document frequencies of n-grams, used to down-weight common n-grams in the cosine.
+///
+/// The weight of an n-gram is `ln((1 + documents) / (1 + frequency)) + 1`, so n-grams that
+/// are not in the table get the highest weight and every weight stays positive.
+#[derive(Clone, Debug, Default)]
+pub struct IdfWeights {
+    documents: f64,
+    frequencies: HashMap<String, f64>,
+}
+
+impl IdfWeights {
+    /// Read a frequency table from a file, see [`IdfWeights::parse`] for the format.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)?;
+        IdfWeights::parse(&contents)
+    }
+
+    /// A hash of the document count and frequencies in the table, which only changes when
+    /// they do.
+    pub fn fingerprint(&self) -> u64 {
+        let mut entries: Vec<(&String, &f64)> = self.frequencies.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        let hash = fnv1a_extend(fnv1a(b"idf weights"), &self.documents.to_bits().to_le_bytes());
+        entries.iter().fold(hash, |hash, (gram, frequency)| {
+            // 0xff never occurs in UTF-8, so it ends the n-gram unambiguously
+            let hash = fnv1a_extend(hash, gram.as_bytes());
+            let hash = fnv1a_extend(hash, &[0xff]);
+            fnv1a_extend(hash, &frequency.to_bits().to_le_bytes())
+        })
+    }
+
+    /// Parse a frequency table. The first line holds the number of documents, followed by
+    /// one `ngram<TAB>document frequency` entry per line, e.g. `er\t5120`.
+    ///
+    /// Empty lines and lines starting with `#` are ignored. The n-grams are not trimmed, so
+    /// they can contain spaces.
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut documents = None;
+        let mut frequencies = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if documents.is_none() {
+                let count = line.trim().parse::<u64>().map_err(|_| {
+                    format!(
+                        "Invalid document count on line {}: expected a number, got '{}'",
+                        index + 1,
+                        line
+                    )
+                })?;
+                documents = Some(count as f64);
+                continue;
+            }
+
+            let entry = line
+                .split_once('\t')
+                .and_then(|(gram, frequency)| Some((gram, frequency.trim().parse::<u64>().ok()?)));
+            let (gram, frequency) = entry.ok_or_else(|| {
+                format!(
+                    "Invalid document frequency on line {}: expected 'ngram<TAB>frequency', got '{}'",
+                    index + 1,
+                    line
+                )
+            })?;
+            frequencies.insert(gram.to_string(), frequency as f64);
+        }
+
+        let documents = documents.ok_or("The IDF file does not contain a document count")?;
+        Ok(IdfWeights {
+            documents,
+            frequencies,
+        })
+    }
+
+    /// The weight of an n-gram.
+    pub fn weight(&self, gram: &str) -> f64 {
+        let frequency = self.frequencies.get(gram).copied().unwrap_or(0.0);
+        ((1.0 + self.documents) / (1.0 + frequency)).ln() + 1.0
+    }
+}
+
+/// Count the character bigrams of a word. A word of one character is its own single n-gram.
+fn bigram_counts(word: &str) -> BTreeMap<String, f64> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut counts = BTreeMap::new();
+    if chars.len() == 1 {
+        counts.insert(word.to_string(), 1.0);
+    }
+    for window in chars.windows(2) {
+        *counts.entry(window.iter().collect()).or_insert(0.0) += 1.0;
+    }
+    counts
+}
+
+/// Calculate the cosine similarity of the character bigram vectors of two words.
+///
+/// With IDF weights, every bigram count is multiplied by the weight of the bigram first, so
+/// shared rare bigrams count more than shared common ones. The bigrams are summed in sorted
+/// order, so the result does not depend on hashing.
+pub fn ngram_cosine(a: &str, b: &str, weights: Option<&IdfWeights>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let weigh = |counts: BTreeMap<String, f64>| -> BTreeMap<String, f64> {
+        match weights {
+            Some(weights) => counts
+                .into_iter()
+                .map(|(gram, count)| {
+                    let weight = weights.weight(&gram);
+                    (gram, count * weight)
+                })
+                .collect(),
+            None => counts,
+        }
+    };
+    let a = weigh(bigram_counts(a));
+    let b = weigh(bigram_counts(b));
+
+    let dot: f64 = a
+        .iter()
+        .filter_map(|(gram, value)| b.get(gram).map(|other| value * other))
+        .sum();
+    let norm = |vector: &BTreeMap<String, f64>| vector.values().map(|v| v * v).sum::<f64>().sqrt();
+    let norms = norm(&a) * norm(&b);
+    if norms == 0.0 {
+        return 0.0;
+    }
+    // Rounding can push identical vectors slightly above 1.0
+    (dot / norms).min(1.0)
+}
+
+/// One step of the transformation of a word into another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditOperation {
+    Match(char),
+    Substitute(char, char),
+    Insert(char),
+    Delete(char),
+}
+
+impl fmt::Display for EditOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditOperation::Match(c) => write!(f, "{}", c),
+            EditOperation::Substitute(a, b) => write!(f, "{}→{}", a, b),
+            EditOperation::Insert(c) => write!(f, "+{}", c),
+            EditOperation::Delete(c) => write!(f, "-{}", c),
+        }
+    }
+}
+
+/// Calculate a cheapest sequence of edit operations turning `a` into `b`.
+///
+/// The full Levenshtein DP table is kept and traced back from the end, preferring matches and
+/// substitutions over deletions over insertions, so the number of non-matching operations is
+/// the Levenshtein distance.
+pub fn levenshtein_alignment(a: &str, b: &str) -> Vec<EditOperation> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let width = b.len() + 1;
+
+    let mut table = vec![0usize; (a.len() + 1) * width];
+    for i in 0..=a.len() {
+        for j in 0..=b.len() {
+            table[i * width + j] = if i == 0 {
+                j
+            } else if j == 0 {
+                i
+            } else {
+                let substitution =
+                    table[(i - 1) * width + j - 1] + usize::from(a[i - 1] != b[j - 1]);
+                substitution
+                    .min(table[(i - 1) * width + j] + 1)
+                    .min(table[i * width + j - 1] + 1)
+            };
+        }
+    }
+
+    let mut operations = Vec::with_capacity(a.len().max(b.len()));
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > 0 || j > 0 {
+        let current = table[i * width + j];
+        let diagonal = |i: usize, j: usize| {
+            table[(i - 1) * width + j - 1] + usize::from(a[i - 1] != b[j - 1])
+        };
+        if i > 0 && j > 0 && current == diagonal(i, j) {
+            operations.push(if a[i - 1] == b[j - 1] {
+                EditOperation::Match(a[i - 1])
+            } else {
+                EditOperation::Substitute(a[i - 1], b[j - 1])
+            });
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && current == table[(i - 1) * width + j] + 1 {
+            operations.push(EditOperation::Delete(a[i - 1]));
+            i -= 1;
+        } else {
+            operations.push(EditOperation::Insert(b[j - 1]));
+            j -= 1;
+        }
+    }
+    operations.reverse();
+    operations
+}
+
+/// The similarity algorithm used to build the matrix, together with its settings.
+#[derive(Clone, Debug, Default)]
+pub struct Scorer {
+    pub algorithm: SimilarityAlgorithm,
+    /// Substitution costs replacing the uniform costs of the Levenshtein algorithm.
+    pub substitution_costs: Option<SubstitutionCosts>,
+    /// Maximum number of edits for the Levenshtein algorithm; pairs needing more score 0.0.
+    pub max_edits: Option<usize>,
+    /// N-gram weights for the cosine algorithm.
+    pub idf_weights: Option<IdfWeights>,
+    /// Discount the similarity of short words, see `apply_length_penalty`.
+    pub length_penalty: Option<f64>,
+    /// Compare the words as records of delimited fields, with these weights per field.
+    pub record_fields: Option<RecordFields>,
+    /// Score words with byte-equal keys exactly 1.0, since some settings miss it by a rounding
+    /// error, such as the cosine of most words or the weighted sum of record fields.
+    pub clamp_identical: bool,
+}
+
+impl Scorer {
+    /// Create a scorer that uses the algorithm without further settings.
+    pub fn new(algorithm: SimilarityAlgorithm) -> Self {
+        Scorer {
+            algorithm,
+            ..Scorer::default()
+        }
+    }
+
+    /// The name of the metric behind the similarities, like `levenshtein`, or the algorithm of
+    /// every weighted field with record fields, like `fields(1:jaro-winkler,2:levenshtein)`.
+    pub fn metric_name(&self) -> String {
+        match &self.record_fields {
+            Some(records) => {
+                let fields: Vec<String> = records
+                    .fields
+                    .iter()
+                    .map(|field| format!("{}:{}", field.field + 1, field.algorithm.unwrap_or(self.algorithm)))
+                    .collect();
+                format!("fields({})", fields.join(","))
+            }
+            None => self.algorithm.name().to_string(),
+        }
+    }
+
+    /// Calculate the similarity between two words.
+    ///
+    /// With record fields, the fields are compared with their own algorithm if they have one,
+    /// and the length penalty applies to every field on its own.
+    pub fn similarity(&self, a: &str, b: &str) -> f64 {
+        if self.clamp_identical && a == b {
+            return 1.0;
+        }
+        match &self.record_fields {
+            Some(records) => records.similarity(a, b, |algorithm, a, b| {
+                self.penalized_similarity(algorithm.unwrap_or(self.algorithm), a, b)
+            }),
+            None => self.penalized_similarity(self.algorithm, a, b),
+        }
+    }
+
+    fn penalized_similarity(&self, algorithm: SimilarityAlgorithm, a: &str, b: &str) -> f64 {
+        let similarity = self.algorithm_similarity(algorithm, a, b);
+        match self.length_penalty {
+            Some(penalty) => {
+                let shorter = a.chars().count().min(b.chars().count());
+                apply_length_penalty(similarity, shorter, penalty)
+            }
+            None => similarity,
+        }
+    }
+
+    /// Calculate the similarity between two words with the algorithm and the settings that
+    /// apply to it.
+    fn algorithm_similarity(&self, algorithm: SimilarityAlgorithm, a: &str, b: &str) -> f64 {
+        if algorithm == SimilarityAlgorithm::Levenshtein {
+            if let Some(costs) = &self.substitution_costs {
+                return normalized_weighted_levenshtein(a, b, costs);
+            }
+            if let Some(max_edits) = self.max_edits {
+                let max_len = a.chars().count().max(b.chars().count());
+                return match banded_levenshtein(a, b, max_edits) {
+                    Some(_) if max_len == 0 => 1.0,
+                    Some(distance) => 1.0 - distance as f64 / max_len as f64,
+                    None => 0.0,
+                };
+            }
+        }
+        if algorithm == SimilarityAlgorithm::Cosine {
+            if let Some(weights) = &self.idf_weights {
+                return ngram_cosine(a, b, Some(weights));
+            }
+        }
+        algorithm.similarity(a, b)
+    }
+}
+
+/// Discount a similarity by the length of the shorter word of the pair.
+///
+/// The similarity `s` becomes `s ^ (1 + penalty / shorter)`, so a pair of 4-letter words at
+/// 75% drops to 65% with a penalty of 2, while a pair of 20-letter words at 75% stays at 73%.
+/// Perfect matches stay at 100% and a penalty of 0 changes nothing. The shorter word counts
+/// as at least one character.
+pub fn apply_length_penalty(similarity: f64, shorter: usize, penalty: f64) -> f64 {
+    similarity.powf(1.0 + penalty / shorter.max(1) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ocr_costs() -> SubstitutionCosts {
+        SubstitutionCosts::parse("# OCR confusions\n0 o 0.2\n1 l 0.3\n\n5 s 0.25\n").unwrap()
+    }
+
+    #[test]
+    fn weighted_levenshtein_without_costs_matches_levenshtein() {
+        let costs = SubstitutionCosts::default();
+        for (a, b) in [("kitten", "sitting"), ("", "abc"), ("flaw", "lawn"), ("héllo", "hello")] {
+            assert_eq!(
+                weighted_levenshtein(a, b, &costs),
+                strsim::levenshtein(a, b) as f64
+            );
+            let normalized = normalized_weighted_levenshtein(a, b, &costs);
+            assert!((normalized - normalized_levenshtein(a, b)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn weighted_levenshtein_uses_cheaper_substitutions() {
+        let costs = ocr_costs();
+        assert!((weighted_levenshtein("b0ok", "book", &costs) - 0.2).abs() < 1e-12);
+        assert!((weighted_levenshtein("he1lo", "hello", &costs) - 0.3).abs() < 1e-12);
+        assert!((weighted_levenshtein("5h0p", "shop", &costs) - 0.45).abs() < 1e-12);
+
+        // Pairs that are not in the table still cost a full edit
+        assert_eq!(weighted_levenshtein("bxok", "book", &costs), 1.0);
+    }
+
+    #[test]
+    fn weighted_levenshtein_is_symmetric() {
+        let costs = ocr_costs();
+        assert_eq!(
+            weighted_levenshtein("b0o1", "bool", &costs),
+            weighted_levenshtein("bool", "b0o1", &costs)
+        );
+    }
+
+    #[test]
+    fn normalized_weighted_levenshtein_handles_empty_words() {
+        let costs = ocr_costs();
+        assert_eq!(normalized_weighted_levenshtein("", "", &costs), 1.0);
+        assert_eq!(normalized_weighted_levenshtein("", "abc", &costs), 0.0);
+    }
+
+    #[test]
+    fn banded_levenshtein_matches_full_levenshtein_within_the_band() {
+        let words = [
+            "", "a", "ab", "ba", "abc", "kitten", "sitting", "sitten", "flaw", "lawn",
+            "hello", "hallo", "helo", "yellow", "worldwide", "worldwido", "héllo", "été",
+            "abcdefgh", "hgfedcba", "aaaaaaaa", "aaaa",
+        ];
+        for a in words {
+            for b in words {
+                let distance = strsim::levenshtein(a, b);
+                for max_edits in 0..10 {
+                    let expected = Some(distance).filter(|d| *d <= max_edits);
+                    assert_eq!(
+                        banded_levenshtein(a, b, max_edits),
+                        expected,
+                        "{:?} vs {:?} with at most {} edits",
+                        a,
+                        b,
+                        max_edits
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scorer_with_max_edits_scores_far_pairs_zero() {
+        let scorer = Scorer {
+            max_edits: Some(1),
+            ..Scorer::default()
+        };
+        assert_eq!(scorer.similarity("hello", "hallo"), normalized_levenshtein("hello", "hallo"));
+        assert_eq!(scorer.similarity("hello", "yellow"), 0.0);
+        assert_eq!(scorer.similarity("", ""), 1.0);
+    }
+
+    #[test]
+    fn default_thresholds_match_typical_typos() {
+        for (a, b) in [("separate", "seperate"), ("calendar", "calender"), ("database", "datbase"), ("address", "adress")] {
+            for algorithm in SimilarityAlgorithm::ALL {
+                let similarity = algorithm.similarity(a, b);
+                assert!(similarity >= algorithm.default_threshold(), "{} {} {}", algorithm, a, b);
+            }
+        }
+        // Unrelated words of the same length stay below every default
+        for algorithm in SimilarityAlgorithm::ALL {
+            assert!(algorithm.similarity("database", "painting") < algorithm.default_threshold(), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn substitution_costs_reject_invalid_lines() {
+        assert!(SubstitutionCosts::parse("0 o").is_err());
+        assert!(SubstitutionCosts::parse("00 o 0.2").is_err());
+        assert!(SubstitutionCosts::parse("0 o cheap").is_err());
+        assert!(SubstitutionCosts::parse("0 o 1.5").is_err());
+    }
+
+    #[test]
+    fn ngram_cosine_scores_shared_bigrams() {
+        assert_eq!(ngram_cosine("hello", "hello", None), 1.0);
+        assert_eq!(ngram_cosine("", "", None), 1.0);
+        assert_eq!(ngram_cosine("abc", "xyz", None), 0.0);
+        assert_eq!(ngram_cosine("a", "a", None), 1.0);
+        // "night" and "nacht" only share "ht" out of four bigrams each
+        assert!((ngram_cosine("night", "nacht", None) - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn idf_weights_down_weight_common_bigrams() {
+        let weights = IdfWeights::parse("# corpus\n1000\nin\t900\nng\t950\n").unwrap();
+        assert!(weights.weight("in") < weights.weight("zq"));
+
+        // Sharing only the common suffix counts less than sharing a rare prefix
+        let plain = ngram_cosine("walking", "talking", None);
+        let weighted = ngram_cosine("walking", "talking", Some(&weights));
+        assert!(weighted < plain);
+        assert!((ngram_cosine("walking", "walking", Some(&weights)) - 1.0).abs() < 1e-12);
+
+        let scorer = Scorer {
+            algorithm: SimilarityAlgorithm::Cosine,
+            idf_weights: Some(weights),
+            ..Scorer::default()
+        };
+        assert_eq!(scorer.similarity("walking", "talking"), weighted);
+    }
+
+    #[test]
+    fn clamps_identical_words_to_exactly_one() {
+        let mut scorer = Scorer::new(SimilarityAlgorithm::Cosine);
+        // The norm of the two bigrams of "abc" is the square root of 2, whose square is not
+        // exactly 2, which shows as 99.99...% on the diagonal
+        assert!(scorer.similarity("abc", "abc") < 1.0);
+
+        scorer.clamp_identical = true;
+        assert_eq!(scorer.similarity("abc", "abc"), 1.0);
+        assert_eq!(scorer.similarity("abc", "abd"), ngram_cosine("abc", "abd", None));
+        // Only byte-equal keys are clamped, not different words with the same bigrams
+        assert!(scorer.similarity("aba", "bab") < 1.0);
+    }
+
+    #[test]
+    fn idf_weights_reject_invalid_lines() {
+        assert!(IdfWeights::parse("").is_err());
+        assert!(IdfWeights::parse("many\n").is_err());
+        assert!(IdfWeights::parse("10\nin 5\n").is_err());
+        assert!(IdfWeights::parse("10\nin\tfive\n").is_err());
+    }
+
+    #[test]
+    fn alignment_costs_the_levenshtein_distance() {
+        let pairs = [("kitten", "sitting"), ("", "abc"), ("flaw", "lawn"), ("héllo", "hello"), ("", "")];
+        for (a, b) in pairs {
+            let operations = levenshtein_alignment(a, b);
+            let edits = operations
+                .iter()
+                .filter(|operation| !matches!(operation, EditOperation::Match(_)))
+                .count();
+            assert_eq!(edits, strsim::levenshtein(a, b), "{:?} vs {:?}", a, b);
+        }
+
+        let rendered: Vec<String> = levenshtein_alignment("hello", "hallo!")
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(rendered, ["h", "e→a", "l", "l", "o", "+!"]);
+        assert_eq!(
+            levenshtein_alignment("helo", "hel"),
+            [
+                EditOperation::Match('h'),
+                EditOperation::Match('e'),
+                EditOperation::Match('l'),
+                EditOperation::Delete('o'),
+            ]
+        );
+    }
+
+    #[test]
+    fn length_penalty_discounts_short_words_more() {
+        let short = apply_length_penalty(0.75, 4, 2.0);
+        let long = apply_length_penalty(0.75, 20, 2.0);
+        assert!((short - 0.75f64.powf(1.5)).abs() < 1e-12);
+        assert!(short < long && long < 0.75);
+        assert_eq!(apply_length_penalty(1.0, 1, 2.0), 1.0);
+        assert_eq!(apply_length_penalty(0.75, 4, 0.0), 0.75);
+
+        // One edit in a short pair versus several edits in a long pair at the same raw score
+        let scorer = Scorer {
+            length_penalty: Some(2.0),
+            ..Scorer::default()
+        };
+        let long = ("internationalization", "intarnatiunarisatiun");
+        let raw_short = normalized_levenshtein("cart", "card");
+        let raw_long = normalized_levenshtein(long.0, long.1);
+        assert_eq!(raw_short, 0.75);
+        assert_eq!(raw_long, raw_short);
+        let penalized_short = scorer.similarity("cart", "card");
+        let penalized_long = scorer.similarity(long.0, long.1);
+        assert!(penalized_short < penalized_long && penalized_long < raw_long);
+    }
+}
@@ -0,0 +1,75 @@
+//! Read-only memory maps of input files, for reading very large files without copying them.
+
+use std::fs::File;
+use std::io;
+
+use memmap2::Mmap;
+
+/// Map the whole file into memory, read-only.
+///
+/// The file must not be changed while it is mapped: a write by another process can show up
+/// in the mapped bytes, or a truncation can make reading them fail with a bus error.
+pub fn map_file(file: &File) -> io::Result<Mmap> {
+    // SAFETY: the map is only read, and the caller keeps the file unchanged while it is
+    // mapped, as documented for --mmap
+    let map = unsafe { Mmap::map(file)? };
+    // The advice only affects read-ahead, so a failure is ignored
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+    Ok(map)
+}
+
+/// Iterate over the lines of a byte slice like `BufRead::lines`: without the `\n` or `\r\n`
+/// at the end of every line, and without an empty line after a final newline.
+pub fn byte_lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = bytes;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        match rest.iter().position(|&byte| byte == b'\n') {
+            Some(end) => {
+                let line = &rest[..end];
+                rest = &rest[end + 1..];
+                Some(line.strip_suffix(b"\r").unwrap_or(line))
+            }
+            None => Some(std::mem::take(&mut rest)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+
+    #[test]
+    fn splits_lines_like_bufread() {
+        for text in [
+            &b""[..],
+            b"\n",
+            b"a",
+            b"a\n",
+            b"a\r\nb",
+            b"a\n\nb\r\n",
+            b"a\r",
+            b"\r\n\r\n",
+        ] {
+            let expected: Vec<Vec<u8>> =
+                text.lines().map(|line| line.unwrap().into_bytes()).collect();
+            let actual: Vec<Vec<u8>> = byte_lines(text).map(<[u8]>::to_vec).collect();
+            assert_eq!(actual, expected, "lines of {:?}", text);
+        }
+    }
+
+    #[test]
+    fn maps_files() {
+        let path = std::env::temp_dir().join(format!("word_similarity_mmap_{}.txt", std::process::id()));
+        for contents in [&b"hello\nworld"[..], b""] {
+            std::fs::write(&path, contents).unwrap();
+            let map = map_file(&File::open(&path).unwrap()).unwrap();
+            assert_eq!(&*map, contents);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,550 @@
+//! Preprocessing of the words into the keys that are actually compared.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Toggles for the transformations applied to a word before it is compared.
+///
+/// The original word is always kept for display; only the comparison key is changed.
+#[derive(Clone, Debug, Default)]
+pub struct Preprocessing {
+    /// Skip all preprocessing, including lowercasing, and compare the words as they are.
+    ///
+    /// Unlike an otherwise empty configuration this also avoids allocating a separate key.
+    pub disabled: bool,
+    /// Keep the case of the letters instead of lowercasing them.
+    pub case_sensitive: bool,
+    /// The language whose casing rules are used for lowercasing, instead of the Unicode
+    /// default rules.
+    pub language: Option<Language>,
+    /// Transliterate Latin letters and typographic punctuation to ASCII.
+    pub ascii_fold: bool,
+    /// Remove the diacritics from Latin letters, leaving letters like `ß` unchanged.
+    pub strip_accents: bool,
+    /// Remove punctuation characters.
+    pub strip_punctuation: bool,
+    /// Replace every run of digits with a single `#`, so numbers do not affect the score.
+    pub ignore_numbers: bool,
+    /// Collapse runs of whitespace into a single space and trim the ends.
+    pub collapse_whitespace: bool,
+    /// Sort the characters, so anagrams and reordered words get the same key.
+    pub sort_characters: bool,
+    /// Reverse the characters, so prefix-favoring algorithms like Jaro-Winkler favor suffixes.
+    pub reverse: bool,
+}
+
+impl Preprocessing {
+    /// Turn a word into its comparison key.
+    pub fn apply(&self, word: &str) -> String {
+        if self.disabled {
+            return word.to_string();
+        }
+
+        let mut key = if self.case_sensitive {
+            word.to_string()
+        } else {
+            lowercase(word, self.language)
+        };
+
+        if self.ascii_fold {
+            key = ascii_fold(&key);
+        }
+
+        if self.strip_accents {
+            key = strip_accents(&key);
+        }
+
+        if self.strip_punctuation {
+            key.retain(|c| !is_punctuation(c));
+        }
+
+        if self.ignore_numbers {
+            key = mask_numbers(&key);
+        }
+
+        if self.collapse_whitespace {
+            key = key.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+
+        if self.sort_characters {
+            let mut characters: Vec<char> = key.chars().collect();
+            characters.sort_unstable();
+            key = characters.into_iter().collect();
+        }
+
+        if self.reverse {
+            key = key.chars().rev().collect();
+        }
+
+        key
+    }
+}
+
+/// Lists the steps that are applied, in the order they are applied, or `none`.
+impl fmt::Display for Preprocessing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.disabled {
+            return f.write_str("none");
+        }
+
+        let mut steps = Vec::new();
+        match (self.case_sensitive, self.language) {
+            (true, _) => {}
+            (false, Some(language)) => steps.push(format!("lowercase ({})", language)),
+            (false, None) => steps.push("lowercase".to_string()),
+        }
+        let toggles = [
+            (self.ascii_fold, "ascii-fold"),
+            (self.strip_accents, "strip-accents"),
+            (self.strip_punctuation, "strip-punctuation"),
+            (self.ignore_numbers, "ignore-numbers"),
+            (self.collapse_whitespace, "collapse-whitespace"),
+            (self.sort_characters, "anagram"),
+            (self.reverse, "reverse"),
+        ];
+        steps.extend(toggles.iter().filter(|(on, _)| *on).map(|(_, name)| name.to_string()));
+
+        if steps.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&steps.join(", "))
+        }
+    }
+}
+
+/// A language with casing rules that differ from the Unicode default lowercasing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Language {
+    /// Turkish: `I` lowercases to dotless `ı` and `İ` to `i`.
+    Turkish,
+    /// Azerbaijani, with the same dotted and dotless I as Turkish.
+    Azerbaijani,
+    /// German: `ß` and `ẞ` fold to `ss`, so `Straße` matches `STRASSE`.
+    German,
+}
+
+impl Language {
+    /// All languages, in the order they are listed in the help text.
+    pub const ALL: [Language; 3] = [Language::Turkish, Language::Azerbaijani, Language::German];
+
+    /// The ISO 639-1 code used for the language on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::Turkish => "tr",
+            Language::Azerbaijani => "az",
+            Language::German => "de",
+        }
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Language::ALL
+            .iter()
+            .copied()
+            .find(|language| language.name() == s)
+            .ok_or_else(|| format!("Unknown language: {}", s))
+    }
+}
+
+/// Lowercase a word with the casing rules of the language, or the Unicode default rules.
+fn lowercase(word: &str, language: Option<Language>) -> String {
+    match language {
+        Some(Language::Turkish | Language::Azerbaijani) => {
+            // Map the two capital I's first, the default rules then keep ı and i
+            word.replace('I', "ı").replace('İ', "i").to_lowercase()
+        }
+        Some(Language::German) => word.to_lowercase().replace('ß', "ss"),
+        None => word.to_lowercase(),
+    }
+}
+
+/// A named combination of the preprocessing toggles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Names of people, places and organizations: lowercase, strip accents, strip
+    /// punctuation and collapse whitespace, so `Zoë  O'Brien` matches `zoe obrien`.
+    NameMatching,
+    /// Identifiers and code: keep case, accents and punctuation, since `get_id` and
+    /// `getID` are different, and only collapse whitespace.
+    CodeMatching,
+}
+
+impl Preset {
+    /// All presets, in the order they are listed in the help text.
+    pub const ALL: [Preset; 2] = [Preset::NameMatching, Preset::CodeMatching];
+
+    /// The name used for the preset on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::NameMatching => "name-matching",
+            Preset::CodeMatching => "code-matching",
+        }
+    }
+
+    /// The preprocessing toggles the preset expands to.
+    pub fn preprocessing(self) -> Preprocessing {
+        match self {
+            Preset::NameMatching => Preprocessing {
+                strip_accents: true,
+                strip_punctuation: true,
+                collapse_whitespace: true,
+                ..Preprocessing::default()
+            },
+            Preset::CodeMatching => Preprocessing {
+                case_sensitive: true,
+                collapse_whitespace: true,
+                ..Preprocessing::default()
+            },
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Preset::ALL
+            .iter()
+            .copied()
+            .find(|preset| preset.name() == s)
+            .ok_or_else(|| format!("Unknown preprocessing preset: {}", s))
+    }
+}
+
+/// The placeholder that replaces a run of digits with --ignore-numbers.
+const NUMBER_PLACEHOLDER: char = '#';
+
+/// Replace every run of numeric characters with a single placeholder.
+fn mask_numbers(s: &str) -> String {
+    let mut masked = String::with_capacity(s.len());
+    let mut in_number = false;
+    for c in s.chars() {
+        if c.is_numeric() {
+            if !in_number {
+                masked.push(NUMBER_PLACEHOLDER);
+            }
+            in_number = true;
+        } else {
+            masked.push(c);
+            in_number = false;
+        }
+    }
+    masked
+}
+
+/// Check for ASCII punctuation and the common Unicode punctuation marks.
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation()
+        || matches!(
+            c,
+            '\u{00A1}' // ¡
+                | '\u{00A7}' // §
+                | '\u{00AB}' // «
+                | '\u{00B6}' // ¶
+                | '\u{00B7}' // ·
+                | '\u{00BB}' // »
+                | '\u{00BF}' // ¿
+                | '\u{2010}'..='\u{2027}' // Dashes, quotation marks, bullets and ellipsis
+                | '\u{2030}'..='\u{205E}' // Per mille, primes, angle quotes and more
+                | '\u{3001}'..='\u{3003}' // CJK comma and full stops
+                | '\u{3008}'..='\u{3011}' // CJK brackets
+        )
+}
+
+/// Transliterate a string to ASCII, leaving characters without a known ASCII form unchanged.
+fn ascii_fold(s: &str) -> String {
+    let mut folded = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii() {
+            folded.push(c);
+        } else if let Some(base) = base_letter(c) {
+            folded.push(base);
+        } else if let Some(replacement) = ascii_replacement(c) {
+            folded.push_str(replacement);
+        } else {
+            folded.push(c);
+        }
+    }
+    folded
+}
+
+/// Remove the diacritics from Latin letters, and combining diacritical marks.
+fn strip_accents(s: &str) -> String {
+    s.chars()
+        .filter(|c| !('\u{0300}'..='\u{036F}').contains(c))
+        .map(|c| base_letter(c).unwrap_or(c))
+        .collect()
+}
+
+/// The ASCII base letter of a Latin letter with diacritics.
+///
+/// These are the letters whose canonical decomposition is an ASCII letter followed by
+/// combining marks, from the Latin-1 Supplement, Latin Extended-A/B and Latin Extended
+/// Additional blocks.
+fn base_letter(c: char) -> Option<char> {
+    match c {
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Ā' | 'Ă' | 'Ą' | 'Ǎ' | 'Ǟ' | 'Ǡ' | 'Ǻ' | 'Ȁ'
+            | 'Ȃ' | 'Ȧ' | 'Ḁ' | 'Ạ' | 'Ả' | 'Ấ' | 'Ầ' | 'Ẩ' | 'Ẫ' | 'Ậ' | 'Ắ' | 'Ằ' | 'Ẳ'
+            | 'Ẵ' | 'Ặ' => Some('A'),
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' | 'ǎ' | 'ǟ' | 'ǡ' | 'ǻ' | 'ȁ'
+            | 'ȃ' | 'ȧ' | 'ḁ' | 'ạ' | 'ả' | 'ấ' | 'ầ' | 'ẩ' | 'ẫ' | 'ậ' | 'ắ' | 'ằ' | 'ẳ'
+            | 'ẵ' | 'ặ' => Some('a'),
+        'Ḃ' | 'Ḅ' | 'Ḇ' => Some('B'),
+        'ḃ' | 'ḅ' | 'ḇ' => Some('b'),
+        'Ç' | 'Ć' | 'Ĉ' | 'Ċ' | 'Č' | 'Ḉ' => Some('C'),
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' | 'ḉ' => Some('c'),
+        'Ď' | 'Ḋ' | 'Ḍ' | 'Ḏ' | 'Ḑ' | 'Ḓ' => Some('D'),
+        'ď' | 'ḋ' | 'ḍ' | 'ḏ' | 'ḑ' | 'ḓ' => Some('d'),
+        'È' | 'É' | 'Ê' | 'Ë' | 'Ē' | 'Ĕ' | 'Ė' | 'Ę' | 'Ě' | 'Ȅ' | 'Ȇ' | 'Ȩ' | 'Ḕ' | 'Ḗ'
+            | 'Ḙ' | 'Ḛ' | 'Ḝ' | 'Ẹ' | 'Ẻ' | 'Ẽ' | 'Ế' | 'Ề' | 'Ể' | 'Ễ' | 'Ệ' => Some('E'),
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' | 'ȅ' | 'ȇ' | 'ȩ' | 'ḕ' | 'ḗ'
+            | 'ḙ' | 'ḛ' | 'ḝ' | 'ẹ' | 'ẻ' | 'ẽ' | 'ế' | 'ề' | 'ể' | 'ễ' | 'ệ' => Some('e'),
+        'Ḟ' => Some('F'),
+        'ḟ' => Some('f'),
+        'Ĝ' | 'Ğ' | 'Ġ' | 'Ģ' | 'Ǧ' | 'Ǵ' | 'Ḡ' => Some('G'),
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' | 'ǧ' | 'ǵ' | 'ḡ' => Some('g'),
+        'Ĥ' | 'Ȟ' | 'Ḣ' | 'Ḥ' | 'Ḧ' | 'Ḩ' | 'Ḫ' => Some('H'),
+        'ĥ' | 'ȟ' | 'ḣ' | 'ḥ' | 'ḧ' | 'ḩ' | 'ḫ' | 'ẖ' => Some('h'),
+        'Ì' | 'Í' | 'Î' | 'Ï' | 'Ĩ' | 'Ī' | 'Ĭ' | 'Į' | 'İ' | 'Ǐ' | 'Ȉ' | 'Ȋ' | 'Ḭ' | 'Ḯ'
+            | 'Ỉ' | 'Ị' => Some('I'),
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ǐ' | 'ȉ' | 'ȋ' | 'ḭ' | 'ḯ' | 'ỉ'
+            | 'ị' => Some('i'),
+        'Ĵ' => Some('J'),
+        'ĵ' | 'ǰ' => Some('j'),
+        'Ķ' | 'Ǩ' | 'Ḱ' | 'Ḳ' | 'Ḵ' => Some('K'),
+        'ķ' | 'ǩ' | 'ḱ' | 'ḳ' | 'ḵ' => Some('k'),
+        'Ĺ' | 'Ļ' | 'Ľ' | 'Ḷ' | 'Ḹ' | 'Ḻ' | 'Ḽ' => Some('L'),
+        'ĺ' | 'ļ' | 'ľ' | 'ḷ' | 'ḹ' | 'ḻ' | 'ḽ' => Some('l'),
+        'Ḿ' | 'Ṁ' | 'Ṃ' => Some('M'),
+        'ḿ' | 'ṁ' | 'ṃ' => Some('m'),
+        'Ñ' | 'Ń' | 'Ņ' | 'Ň' | 'Ǹ' | 'Ṅ' | 'Ṇ' | 'Ṉ' | 'Ṋ' => Some('N'),
+        'ñ' | 'ń' | 'ņ' | 'ň' | 'ǹ' | 'ṅ' | 'ṇ' | 'ṉ' | 'ṋ' => Some('n'),
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ō' | 'Ŏ' | 'Ő' | 'Ơ' | 'Ǒ' | 'Ǫ' | 'Ǭ' | 'Ȍ' | 'Ȏ'
+            | 'Ȫ' | 'Ȭ' | 'Ȯ' | 'Ȱ' | 'Ṍ' | 'Ṏ' | 'Ṑ' | 'Ṓ' | 'Ọ' | 'Ỏ' | 'Ố' | 'Ồ' | 'Ổ'
+            | 'Ỗ' | 'Ộ' | 'Ớ' | 'Ờ' | 'Ở' | 'Ỡ' | 'Ợ' => Some('O'),
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ō' | 'ŏ' | 'ő' | 'ơ' | 'ǒ' | 'ǫ' | 'ǭ' | 'ȍ' | 'ȏ'
+            | 'ȫ' | 'ȭ' | 'ȯ' | 'ȱ' | 'ṍ' | 'ṏ' | 'ṑ' | 'ṓ' | 'ọ' | 'ỏ' | 'ố' | 'ồ' | 'ổ'
+            | 'ỗ' | 'ộ' | 'ớ' | 'ờ' | 'ở' | 'ỡ' | 'ợ' => Some('o'),
+        'Ṕ' | 'Ṗ' => Some('P'),
+        'ṕ' | 'ṗ' => Some('p'),
+        'Ŕ' | 'Ŗ' | 'Ř' | 'Ȑ' | 'Ȓ' | 'Ṙ' | 'Ṛ' | 'Ṝ' | 'Ṟ' => Some('R'),
+        'ŕ' | 'ŗ' | 'ř' | 'ȑ' | 'ȓ' | 'ṙ' | 'ṛ' | 'ṝ' | 'ṟ' => Some('r'),
+        'Ś' | 'Ŝ' | 'Ş' | 'Š' | 'Ș' | 'Ṡ' | 'Ṣ' | 'Ṥ' | 'Ṧ' | 'Ṩ' => Some('S'),
+        'ś' | 'ŝ' | 'ş' | 'š' | 'ș' | 'ṡ' | 'ṣ' | 'ṥ' | 'ṧ' | 'ṩ' => Some('s'),
+        'Ţ' | 'Ť' | 'Ț' | 'Ṫ' | 'Ṭ' | 'Ṯ' | 'Ṱ' => Some('T'),
+        'ţ' | 'ť' | 'ț' | 'ṫ' | 'ṭ' | 'ṯ' | 'ṱ' | 'ẗ' => Some('t'),
+        'Ù' | 'Ú' | 'Û' | 'Ü' | 'Ũ' | 'Ū' | 'Ŭ' | 'Ů' | 'Ű' | 'Ų' | 'Ư' | 'Ǔ' | 'Ǖ' | 'Ǘ'
+            | 'Ǚ' | 'Ǜ' | 'Ȕ' | 'Ȗ' | 'Ṳ' | 'Ṵ' | 'Ṷ' | 'Ṹ' | 'Ṻ' | 'Ụ' | 'Ủ' | 'Ứ' | 'Ừ'
+            | 'Ử' | 'Ữ' | 'Ự' => Some('U'),
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' | 'ư' | 'ǔ' | 'ǖ' | 'ǘ'
+            | 'ǚ' | 'ǜ' | 'ȕ' | 'ȗ' | 'ṳ' | 'ṵ' | 'ṷ' | 'ṹ' | 'ṻ' | 'ụ' | 'ủ' | 'ứ' | 'ừ'
+            | 'ử' | 'ữ' | 'ự' => Some('u'),
+        'Ṽ' | 'Ṿ' => Some('V'),
+        'ṽ' | 'ṿ' => Some('v'),
+        'Ŵ' | 'Ẁ' | 'Ẃ' | 'Ẅ' | 'Ẇ' | 'Ẉ' => Some('W'),
+        'ŵ' | 'ẁ' | 'ẃ' | 'ẅ' | 'ẇ' | 'ẉ' | 'ẘ' => Some('w'),
+        'Ẋ' | 'Ẍ' => Some('X'),
+        'ẋ' | 'ẍ' => Some('x'),
+        'Ý' | 'Ŷ' | 'Ÿ' | 'Ȳ' | 'Ẏ' | 'Ỳ' | 'Ỵ' | 'Ỷ' | 'Ỹ' => Some('Y'),
+        'ý' | 'ÿ' | 'ŷ' | 'ȳ' | 'ẏ' | 'ẙ' | 'ỳ' | 'ỵ' | 'ỷ' | 'ỹ' => Some('y'),
+        'Ź' | 'Ż' | 'Ž' | 'Ẑ' | 'Ẓ' | 'Ẕ' => Some('Z'),
+        'ź' | 'ż' | 'ž' | 'ẑ' | 'ẓ' | 'ẕ' => Some('z'),
+        _ => None,
+    }
+}
+
+/// The ASCII transliteration of letters and punctuation that do not decompose into ASCII.
+fn ascii_replacement(c: char) -> Option<&'static str> {
+    match c {
+        'Æ' | 'Ǣ' | 'Ǽ' => Some("AE"),
+        'æ' | 'ǣ' | 'ǽ' => Some("ae"),
+        'Ð' | 'Đ' | 'Ɖ' => Some("D"),
+        'ð' | 'đ' | 'ɖ' => Some("d"),
+        'Ø' | 'Ǿ' => Some("O"),
+        'ø' | 'ǿ' => Some("o"),
+        'Þ' => Some("TH"),
+        'þ' => Some("th"),
+        'ß' => Some("ss"),
+        'ẞ' => Some("SS"),
+        'Ħ' => Some("H"),
+        'ħ' => Some("h"),
+        'ı' => Some("i"),
+        'Ĳ' => Some("IJ"),
+        'ĳ' => Some("ij"),
+        'ĸ' => Some("k"),
+        'Ŀ' | 'Ł' => Some("L"),
+        'ŀ' | 'ł' => Some("l"),
+        'ŉ' => Some("'n"),
+        'Ŋ' => Some("N"),
+        'ŋ' => Some("n"),
+        'Œ' => Some("OE"),
+        'œ' => Some("oe"),
+        'Ŧ' => Some("T"),
+        'ŧ' => Some("t"),
+        'ſ' => Some("s"),
+        'ƒ' => Some("f"),
+        '\u{00A0}' => Some(" "),
+        '‘' | '’' | '‚' | '‛' | '′' => Some("'"),
+        '“' | '”' | '„' | '‟' | '″' => Some("\""),
+        '«' => Some("<<"),
+        '»' => Some(">>"),
+        '‐' | '‑' | '‒' | '–' | '—' | '―' | '−' => Some("-"),
+        '…' => Some("..."),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimilarityAlgorithm;
+
+    #[test]
+    fn sorted_characters_match_anagrams_only() {
+        let anagram = Preprocessing {
+            sort_characters: true,
+            ..Preprocessing::default()
+        };
+        let similarity = |a: &str, b: &str| {
+            SimilarityAlgorithm::Levenshtein.similarity(&anagram.apply(a), &anagram.apply(b))
+        };
+
+        assert_eq!(anagram.apply("Listen"), "eilnst");
+        assert_eq!(similarity("listen", "Silent"), 1.0);
+        assert_eq!(similarity("new york", "york new"), 1.0);
+
+        // Words with the same letters in other amounts are scored like their sorted forms
+        assert_eq!(similarity("aab", "abb"), SimilarityAlgorithm::Levenshtein.similarity("aab", "abb"));
+        assert!(similarity("aab", "abb") < 1.0);
+        assert!(similarity("listen", "listens") < 1.0);
+    }
+
+    #[test]
+    fn collapses_whitespace_in_the_key_only() {
+        let collapse = Preprocessing {
+            collapse_whitespace: true,
+            ..Preprocessing::default()
+        };
+        assert_eq!(collapse.apply("  New \t  York "), "new york");
+        assert_eq!(collapse.apply(" \t "), "");
+
+        let options = crate::InputOptions {
+            preprocessing: collapse,
+            ..crate::InputOptions::default()
+        };
+        let (words, _, _) = crate::read_input("  New \t  York \nBoston\n".as_bytes(), &options).unwrap();
+        assert_eq!(words[0].original, "  New \t  York ");
+        assert_eq!(words[0].processed(), "new york");
+    }
+
+    #[test]
+    fn reverses_characters_to_favor_shared_suffixes() {
+        let reverse = Preprocessing {
+            reverse: true,
+            ..Preprocessing::default()
+        };
+        assert_eq!(reverse.apply("Héllo"), "olléh");
+        assert_eq!(reverse.apply("日本語"), "語本日");
+
+        // Jaro-Winkler adds a bonus for a shared prefix, which reversing turns into a shared suffix
+        let jaro_winkler = |a: &str, b: &str| SimilarityAlgorithm::JaroWinkler.similarity(a, b);
+        let forward = jaro_winkler("station", "nation");
+        let reversed = jaro_winkler(&reverse.apply("station"), &reverse.apply("nation"));
+        assert!(reversed > forward, "{} <= {}", reversed, forward);
+        assert!(jaro_winkler(&reverse.apply("nation"), &reverse.apply("natural")) < reversed);
+    }
+
+    #[test]
+    fn presets_expand_to_their_toggles() {
+        let name_matching = Preset::NameMatching.preprocessing();
+        assert_eq!(name_matching.to_string(), "lowercase, strip-accents, strip-punctuation, collapse-whitespace");
+        assert_eq!(name_matching.apply("Zoë  O'Brien"), "zoe obrien");
+
+        let code_matching = Preset::CodeMatching.preprocessing();
+        assert_eq!(code_matching.to_string(), "collapse-whitespace");
+        assert_eq!(code_matching.apply("get_ID  (x)"), "get_ID (x)");
+
+        for preset in Preset::ALL {
+            assert_eq!(preset.name().parse::<Preset>(), Ok(preset));
+        }
+        assert!("Name-Matching".parse::<Preset>().is_err());
+        assert!("fuzzy".parse::<Preset>().is_err());
+    }
+
+    #[test]
+    fn folds_to_ascii() {
+        for (word, folded) in [
+            ("Straße", "Strasse"),
+            ("Øresund", "Oresund"),
+            ("øl", "ol"),
+            ("Łódź", "Lodz"),
+            ("Æsir", "AEsir"),
+            ("encyclopædia", "encyclopaedia"),
+            ("Œuvre", "OEuvre"),
+            ("cœur", "coeur"),
+            ("Þór", "THor"),
+            ("\u{2018}quoted\u{2019}", "'quoted'"),
+            ("\u{201C}quoted\u{201D}", "\"quoted\""),
+            ("«quoted»", "<<quoted>>"),
+            ("1990–2000", "1990-2000"),
+            ("wait…", "wait..."),
+            ("日本", "日本"),
+        ] {
+            assert_eq!(ascii_fold(word), folded, "{}", word);
+        }
+
+        let fold = Preprocessing {
+            ascii_fold: true,
+            ..Preprocessing::default()
+        };
+        assert_eq!(fold.apply("ŁUKASZ"), "lukasz");
+    }
+
+    #[test]
+    fn lowercases_with_the_rules_of_the_language() {
+        for (language, word, lowercased) in [
+            (Some(Language::Turkish), "İSTANBUL", "istanbul"),
+            (Some(Language::Turkish), "I", "ı"),
+            (Some(Language::Turkish), "DİYARBAKIR", "diyarbakır"),
+            (Some(Language::Azerbaijani), "İLHAM", "ilham"),
+            (Some(Language::German), "Straße", "strasse"),
+            (Some(Language::German), "STRASSE", "strasse"),
+            (None, "I", "i"),
+            (None, "Straße", "straße"),
+        ] {
+            assert_eq!(lowercase(word, language), lowercased, "{:?} {}", language, word);
+        }
+    }
+
+    #[test]
+    fn masks_every_run_of_digits_once() {
+        assert_eq!(mask_numbers("Widget 2019"), "Widget #");
+        assert_eq!(mask_numbers("a1b22c333"), "a#b#c#");
+        assert_eq!(mask_numbers("no digits"), "no digits");
+
+        // Punctuation is stripped first, so the placeholder survives and the digits around
+        // a separator become one run
+        let numbers = Preprocessing {
+            strip_punctuation: true,
+            ignore_numbers: true,
+            ..Preprocessing::default()
+        };
+        assert_eq!(numbers.apply("Order #1,000.50"), "order #");
+        assert_eq!(numbers.apply("v1.2"), numbers.apply("v3.4"));
+    }
+}
@@ -0,0 +1,163 @@
+//! Records of delimited fields, compared field by field and combined by weight.
+
+use crate::{Preprocessing, SimilarityAlgorithm};
+
+/// A field of the records that contributes to their similarity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldWeight {
+    /// Index of the field in the record, counting from 0.
+    pub field: usize,
+    /// Weight of the field relative to the other fields.
+    pub weight: f64,
+    /// Algorithm for this field, or the algorithm of the scorer if not set.
+    pub algorithm: Option<SimilarityAlgorithm>,
+}
+
+/// How records are split into fields and how the similarities of the fields are combined.
+///
+/// The similarity of two records is the weighted mean of the similarities of their fields.
+/// A field that is empty in either record is left out, together with its weight, so a missing
+/// city neither helps nor hurts a match. Two records without any field to compare score 0.0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordFields {
+    pub delimiter: char,
+    pub fields: Vec<FieldWeight>,
+}
+
+impl RecordFields {
+    /// Parse a comma-separated list of `FIELD:WEIGHT` or `FIELD:WEIGHT:ALGORITHM` entries, with
+    /// the fields numbered from 1, like `1:2,2:1,3:1:jaro-winkler`.
+    pub fn parse(spec: &str, delimiter: char) -> Result<Self, String> {
+        let mut fields: Vec<FieldWeight> = Vec::new();
+        for entry in spec.split(',').map(str::trim) {
+            let parts: Vec<&str> = entry.split(':').map(str::trim).collect();
+            let (field, weight, algorithm) = match parts.as_slice() {
+                [field, weight] => (field, weight, None),
+                [field, weight, algorithm] => (field, weight, Some(algorithm.parse()?)),
+                _ => return Err(format!("Expected FIELD:WEIGHT[:ALGORITHM], got '{}'", entry)),
+            };
+            let field = match field.parse::<usize>() {
+                Ok(field) if field >= 1 => field - 1,
+                _ => return Err(format!("Invalid field number in '{}'", entry)),
+            };
+            let weight = match weight.parse::<f64>() {
+                Ok(weight) if weight.is_finite() && weight > 0.0 => weight,
+                _ => return Err(format!("Invalid weight in '{}'", entry)),
+            };
+            if fields.iter().any(|existing| existing.field == field) {
+                return Err(format!("Field {} is listed more than once", field + 1));
+            }
+            fields.push(FieldWeight { field, weight, algorithm });
+        }
+        Ok(RecordFields { delimiter, fields })
+    }
+
+    /// The number of fields a record needs to have every weighted field.
+    pub fn required_fields(&self) -> usize {
+        self.fields.iter().map(|field| field.field + 1).max().unwrap_or(0)
+    }
+
+    /// Split a record into its fields, without the whitespace around them.
+    pub fn split<'a>(&self, record: &'a str) -> Vec<&'a str> {
+        record.split(self.delimiter).map(str::trim).collect()
+    }
+
+    /// Preprocess every field on its own, so the delimiters survive steps like stripping the
+    /// punctuation.
+    pub fn preprocess(&self, record: &str, preprocessing: &Preprocessing) -> String {
+        let fields: Vec<String> =
+            self.split(record).into_iter().map(|field| preprocessing.apply(field)).collect();
+        fields.join(&self.delimiter.to_string())
+    }
+
+    /// Combine the similarities of the weighted fields of two records, given a function that
+    /// compares two fields with an algorithm override.
+    ///
+    /// Fields missing from a record count as empty.
+    pub fn similarity<F>(&self, a: &str, b: &str, field_similarity: F) -> f64
+    where
+        F: Fn(Option<SimilarityAlgorithm>, &str, &str) -> f64,
+    {
+        let (a, b) = (self.split(a), self.split(b));
+
+        let mut total = 0.0;
+        let mut total_weight = 0.0;
+        for weighted in &self.fields {
+            let a = a.get(weighted.field).copied().unwrap_or("");
+            let b = b.get(weighted.field).copied().unwrap_or("");
+            if a.is_empty() || b.is_empty() {
+                continue;
+            }
+            total += weighted.weight * field_similarity(weighted.algorithm, a, b);
+            total_weight += weighted.weight;
+        }
+        if total_weight == 0.0 {
+            0.0
+        } else {
+            total / total_weight
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scorer;
+
+    #[test]
+    fn parses_field_weights() {
+        let records = RecordFields::parse("1:2, 3:1:jaro-winkler", ';').unwrap();
+        assert_eq!(records.required_fields(), 3);
+        assert_eq!(
+            records.fields[1],
+            FieldWeight {
+                field: 2,
+                weight: 1.0,
+                algorithm: Some(SimilarityAlgorithm::JaroWinkler)
+            }
+        );
+
+        assert!(RecordFields::parse("0:1", ',').is_err());
+        assert!(RecordFields::parse("1:-1", ',').is_err());
+        assert!(RecordFields::parse("1:1,1:2", ',').is_err());
+        assert!(RecordFields::parse("1:1:unknown", ',').is_err());
+        assert!(RecordFields::parse("1", ',').is_err());
+    }
+
+    #[test]
+    fn combines_field_similarities_by_weight() {
+        let records = RecordFields::parse("1:3,2:1", ',').unwrap();
+        let scorer = Scorer {
+            record_fields: Some(records),
+            ..Scorer::default()
+        };
+
+        // Names at 80% and identical cities give (3 * 0.8 + 1 * 1.0) / 4
+        let similarity = scorer.similarity("hello, paris", "hallo ,paris");
+        assert!((similarity - 0.85).abs() < 1e-12);
+
+        // An empty or missing field is left out together with its weight
+        assert!((scorer.similarity("hello,", "hallo,paris") - 0.8).abs() < 1e-12);
+        assert!((scorer.similarity("hello", "hallo,paris") - 0.8).abs() < 1e-12);
+        assert_eq!(scorer.similarity(",paris", "hallo,"), 0.0);
+    }
+
+    #[test]
+    fn uses_field_algorithm_and_keeps_delimiters() {
+        let records = RecordFields::parse("1:1:jaro-winkler", ',').unwrap();
+        let scorer = Scorer {
+            record_fields: Some(records.clone()),
+            ..Scorer::default()
+        };
+        assert_eq!(
+            scorer.similarity("martha,x", "marhta,y"),
+            SimilarityAlgorithm::JaroWinkler.similarity("martha", "marhta")
+        );
+
+        let preprocessing = Preprocessing {
+            strip_punctuation: true,
+            ..Preprocessing::default()
+        };
+        assert_eq!(records.preprocess("Main St., Paris!", &preprocessing), "main st,paris");
+    }
+}
@@ -0,0 +1,203 @@
+//! A self-contained HTML page of the pairs, for reviewers who do not read the text output.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::metrics::{levenshtein_alignment, EditOperation};
+use crate::{format_similarity, row_label, OutputOptions, SimilarityPair, Word};
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: left; }
+th { cursor: pointer; user-select: none; background: #f4f4f4; position: sticky; top: 0; }
+th[data-order=asc]::after { content: \" \\25b2\"; }
+th[data-order=desc]::after { content: \" \\25bc\"; }
+td.number { text-align: right; font-variant-numeric: tabular-nums; }
+tbody tr:hover { background: #f9f6e8; }
+mark { background: #ffd76e; border-radius: 2px; }
+";
+
+/// Sorts the table by the clicked column, by the `data-value` of the cells if they have one
+/// and by their text otherwise, and reverses the order on the next click.
+const SCRIPT: &str = "
+var headers = document.querySelectorAll(\"th\");
+headers.forEach(function (header, column) {
+  header.addEventListener(\"click\", function () {
+    var body = document.querySelector(\"tbody\");
+    var ascending = header.dataset.order !== \"asc\";
+    headers.forEach(function (other) { delete other.dataset.order; });
+    header.dataset.order = ascending ? \"asc\" : \"desc\";
+    var value = function (row) {
+      var cell = row.cells[column];
+      return cell.dataset.value !== undefined ? Number(cell.dataset.value) : cell.textContent;
+    };
+    var rows = Array.from(body.rows);
+    rows.sort(function (a, b) {
+      var x = value(a), y = value(b);
+      var order = typeof x === \"number\" ? x - y : x.localeCompare(y);
+      return ascending ? order : -order;
+    });
+    rows.forEach(function (row) { body.appendChild(row); });
+  });
+});
+";
+
+/// Render the pairs as an HTML page with a sortable table, in the order of the pairs.
+///
+/// The characters in which the two words differ are highlighted. They are found by aligning
+/// the original words rather than the processed ones, so the page also shows the differences
+/// in case or accents that preprocessing ignored.
+pub fn render_report(pairs: &[SimilarityPair], words: &[Word], options: &OutputOptions) -> String {
+    let mut rows = String::new();
+    for (position, pair) in pairs.iter().enumerate() {
+        let (a, b) = highlight_differences(&words[pair.index_a()].original, &words[pair.index_b()].original);
+        // The number is sorted by its value, so 10 comes after 2
+        let number = position + 1;
+        rows.push_str(&format!(
+            "<tr><td class=\"number\" data-value=\"{}\">{}</td>\
+             <td data-value=\"{}\">{}</td><td>{}</td>\
+             <td data-value=\"{}\">{}</td><td>{}</td>\
+             <td class=\"number\" data-value=\"{}\">{}</td></tr>\n",
+            number,
+            number,
+            pair.index_a(),
+            escape_html(&row_label(pair.index_a(), options)),
+            a,
+            pair.index_b(),
+            escape_html(&row_label(pair.index_b(), options)),
+            b,
+            pair.similarity,
+            escape_html(&format_similarity(pair.similarity, options)),
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<title>Word similarity report</title>
+<style>{}</style>
+</head>
+<body>
+<h1>Word similarity report</h1>
+<p>{} pairs among {} words, best first. Click a column header to sort by it.</p>
+<table>
+<thead><tr><th>#</th><th>Row</th><th>Word</th><th>Row</th><th>Word</th><th>Similarity</th></tr></thead>
+<tbody>
+{}</tbody>
+</table>
+<script>{}</script>
+</body>
+</html>
+",
+        STYLE,
+        pairs.len(),
+        words.len(),
+        rows,
+        SCRIPT
+    )
+}
+
+/// Write the HTML report of the pairs to a file, see `render_report`.
+pub fn write_report_file(
+    path: &Path,
+    pairs: &[SimilarityPair],
+    words: &[Word],
+    options: &OutputOptions,
+) -> io::Result<()> {
+    fs::write(path, render_report(pairs, words, options))
+}
+
+/// Escape the words as HTML, marking the characters that the Levenshtein alignment of the
+/// words does not match: the deleted and substituted ones in `a`, the inserted and
+/// substituted ones in `b`.
+fn highlight_differences(a: &str, b: &str) -> (String, String) {
+    let mut marked_a = Vec::new();
+    let mut marked_b = Vec::new();
+    for operation in levenshtein_alignment(a, b) {
+        match operation {
+            EditOperation::Match(c) => {
+                marked_a.push((c, false));
+                marked_b.push((c, false));
+            }
+            EditOperation::Substitute(x, y) => {
+                marked_a.push((x, true));
+                marked_b.push((y, true));
+            }
+            EditOperation::Delete(x) => marked_a.push((x, true)),
+            EditOperation::Insert(y) => marked_b.push((y, true)),
+        }
+    }
+    (marked_html(&marked_a), marked_html(&marked_b))
+}
+
+/// Escape the characters as HTML, with every run of marked characters in a single `<mark>`.
+fn marked_html(chars: &[(char, bool)]) -> String {
+    let mut html = String::new();
+    let mut in_mark = false;
+    for &(c, marked) in chars {
+        if marked != in_mark {
+            html.push_str(if marked { "<mark>" } else { "</mark>" });
+            in_mark = marked;
+        }
+        html.push_str(&escape_html(c.encode_utf8(&mut [0; 4])));
+    }
+    if in_mark {
+        html.push_str("</mark>");
+    }
+    html
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_the_differing_characters() {
+        assert_eq!(
+            highlight_differences("hello", "hallo!"),
+            ("h<mark>e</mark>llo".to_string(), "h<mark>a</mark>llo<mark>!</mark>".to_string())
+        );
+        assert_eq!(
+            highlight_differences("a<b", "a<bc"),
+            ("a&lt;b".to_string(), "a&lt;b<mark>c</mark>".to_string())
+        );
+    }
+
+    #[test]
+    fn renders_a_row_per_pair() {
+        let words: Vec<Word> = ["Café", "cafe", "<b>"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect();
+        let pairs = [SimilarityPair::new(0.75, 0, 1), SimilarityPair::new(0.0, 1, 2)];
+        let html = render_report(&pairs, &words, &OutputOptions::default());
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<p>2 pairs among 3 words"));
+        assert!(html.contains(
+            "<tr><td class=\"number\" data-value=\"1\">1</td><td data-value=\"0\">Row 1</td><td><mark>C</mark>af<mark>é</mark></td>"
+        ));
+        assert!(html.contains("<td class=\"number\" data-value=\"0.75\">75.00%</td></tr>"));
+        assert!(html.contains("&lt;b&gt;"));
+        assert!(!html.contains("<b>"));
+    }
+}
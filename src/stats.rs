@@ -0,0 +1,107 @@
+//! Summary statistics over the similarities of the written pairs.
+
+use std::fmt;
+
+use crate::SimilarityPair;
+
+/// Summary statistics of the similarities of a set of pairs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Statistics {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    /// The population standard deviation.
+    pub std_dev: f64,
+}
+
+impl Statistics {
+    /// Compute the statistics of pairs sorted in descending order of similarity, as returned
+    /// by `collect_pairs`. Returns `None` if there are no pairs.
+    pub fn from_sorted_pairs(pairs: &[SimilarityPair]) -> Option<Statistics> {
+        let count = pairs.len();
+        if count == 0 {
+            return None;
+        }
+
+        let mean = pairs.iter().map(|pair| pair.similarity).sum::<f64>() / count as f64;
+        let variance = pairs
+            .iter()
+            .map(|pair| (pair.similarity - mean).powi(2))
+            .sum::<f64>()
+            / count as f64;
+
+        // The pairs are already sorted, so the median is in the middle
+        let median = if count % 2 == 1 {
+            pairs[count / 2].similarity
+        } else {
+            (pairs[count / 2 - 1].similarity + pairs[count / 2].similarity) / 2.0
+        };
+
+        Some(Statistics {
+            count,
+            min: pairs[count - 1].similarity,
+            max: pairs[0].similarity,
+            mean,
+            median,
+            std_dev: variance.sqrt(),
+        })
+    }
+}
+
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Pairs: {}", self.count)?;
+        writeln!(f, "Min similarity: {:.2}%", self.min * 100.0)?;
+        writeln!(f, "Max similarity: {:.2}%", self.max * 100.0)?;
+        writeln!(f, "Mean similarity: {:.2}%", self.mean * 100.0)?;
+        writeln!(f, "Median similarity: {:.2}%", self.median * 100.0)?;
+        write!(f, "Standard deviation: {:.2}%", self.std_dev * 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statistics(similarities: &[f64]) -> Statistics {
+        let pairs: Vec<SimilarityPair> = similarities
+            .iter()
+            .enumerate()
+            .map(|(index, &similarity)| SimilarityPair::new(similarity, index, index + 1))
+            .collect();
+        Statistics::from_sorted_pairs(&pairs).unwrap()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn takes_the_middle_pair_of_an_odd_count() {
+        let stats = statistics(&[0.9, 0.8, 0.4]);
+        assert_eq!((stats.count, stats.min, stats.max, stats.median), (3, 0.4, 0.9, 0.8));
+        assert_close(stats.mean, 0.7);
+        // The population deviation divides by 3, not 2
+        assert_close(stats.std_dev, (0.14f64 / 3.0).sqrt());
+    }
+
+    #[test]
+    fn averages_the_middle_pairs_of_an_even_count() {
+        let stats = statistics(&[1.0, 0.8, 0.6, 0.2]);
+        assert_eq!((stats.count, stats.min, stats.max), (4, 0.2, 1.0));
+        assert_close(stats.median, 0.7);
+        assert_close(stats.mean, 0.65);
+        assert_close(stats.std_dev, 0.0875f64.sqrt());
+    }
+
+    #[test]
+    fn describes_a_single_pair() {
+        let stats = statistics(&[0.75]);
+        assert_eq!((stats.count, stats.min, stats.max, stats.median), (1, 0.75, 0.75, 0.75));
+        assert_eq!((stats.mean, stats.std_dev), (0.75, 0.0));
+        assert!(stats.to_string().starts_with("Pairs: 1\nMin similarity: 75.00%\n"));
+        assert_eq!(Statistics::from_sorted_pairs(&[]), None);
+    }
+}
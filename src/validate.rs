@@ -0,0 +1,230 @@
+//! A self-test of the optimized computation of the pairs against a naive computation.
+//!
+//! The naive computation scores every pair on its own, in a plain loop, without the cache,
+//! the heaps of `calculate_top_pairs` or the banded Levenshtein distance of `max_edits`, and
+//! then selects the pairs by sorting all of them. Both have to give exactly the same pairs.
+
+use std::cmp::Reverse;
+use std::ops::RangeInclusive;
+
+use crate::metrics::apply_length_penalty;
+use crate::{ComparisonFilter, RankedPair, Scorer, SimilarityAlgorithm, SimilarityPair, Word};
+
+/// The largest input that is validated, since the naive computation is slow.
+pub const MAX_VALIDATE_WORDS: usize = 2000;
+
+/// A pair that only one of the computations found, or found with another similarity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Divergence {
+    pub index_a: usize,
+    pub index_b: usize,
+    pub naive: Option<f64>,
+    pub optimized: Option<f64>,
+}
+
+/// Compute the pairs naively, with the same selection as `calculate_top_pairs` if `top_k`
+/// or `limit` is given and as `collect_pairs` otherwise.
+///
+/// The approximate options of the filter, the window and the match budget, are not supported,
+/// since they are meant to give other pairs than the naive computation.
+pub fn naive_pairs(
+    words: &[Word],
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    match_range: &RangeInclusive<f64>,
+    top_k: Option<usize>,
+    limit: Option<usize>,
+) -> Vec<SimilarityPair> {
+    let mut selected = Vec::new();
+    for index_a in 0..words.len() {
+        if top_k.is_some() && !filter.selects_partners(index_a) {
+            continue;
+        }
+        let mut row = Vec::new();
+        for index_b in 0..words.len() {
+            let partner = match top_k {
+                Some(_) => index_b != index_a,
+                None => index_b > index_a,
+            };
+            if partner {
+                row.extend(naive_pair(words, scorer, filter, match_range, index_a, index_b));
+            }
+        }
+        if let Some(k) = top_k {
+            row = best(row, k, filter.tie_seed);
+        }
+        selected.extend(row);
+    }
+
+    match (top_k, limit) {
+        (None, None) => {
+            selected.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+            selected
+        }
+        _ => best(selected, limit.unwrap_or(usize::MAX), filter.tie_seed),
+    }
+}
+
+/// Score a pair naively and return it if it is selected, with the fallback if the algorithm
+/// misses the match range.
+fn naive_pair(
+    words: &[Word],
+    scorer: &Scorer,
+    filter: &ComparisonFilter,
+    match_range: &RangeInclusive<f64>,
+    index_a: usize,
+    index_b: usize,
+) -> Option<SimilarityPair> {
+    let (a, b) = (words[index_a].processed(), words[index_b].processed());
+    if !filter.should_compare(index_a, index_b) || !filter.passes_word_gates(a, b) {
+        return None;
+    }
+
+    let similarity = naive_similarity(scorer, a, b);
+    let pair = SimilarityPair::new(similarity, index_a, index_b);
+    if match_range.contains(&similarity) {
+        return filter.meets_length_threshold(words, &pair).then_some(pair);
+    }
+    let fallback = filter.fallback.as_ref().filter(|_| similarity < *match_range.start())?;
+    let similarity = fallback.scorer.similarity(a, b);
+    (fallback.min_match..=*match_range.end())
+        .contains(&similarity)
+        .then(|| SimilarityPair::fallback(similarity, index_a, index_b))
+}
+
+/// The similarity of the scorer with the full Levenshtein distance instead of the band of
+/// `max_edits`. Record fields are scored by the scorer itself.
+fn naive_similarity(scorer: &Scorer, a: &str, b: &str) -> f64 {
+    let max_edits = match scorer.max_edits {
+        Some(max_edits)
+            if scorer.algorithm == SimilarityAlgorithm::Levenshtein
+                && scorer.substitution_costs.is_none()
+                && scorer.record_fields.is_none() =>
+        {
+            max_edits
+        }
+        _ => return scorer.similarity(a, b),
+    };
+
+    let distance = strsim::levenshtein(a, b);
+    let max_len = a.chars().count().max(b.chars().count());
+    let similarity = if distance > max_edits {
+        0.0
+    } else if max_len == 0 {
+        1.0
+    } else {
+        1.0 - distance as f64 / max_len as f64
+    };
+    match scorer.length_penalty {
+        Some(penalty) => {
+            let shorter = a.chars().count().min(b.chars().count());
+            apply_length_penalty(similarity, shorter, penalty)
+        }
+        None => similarity,
+    }
+}
+
+/// The best `count` pairs, best first, ranked like the heaps of `calculate_top_pairs`.
+fn best(pairs: Vec<SimilarityPair>, count: usize, tie_seed: Option<u64>) -> Vec<SimilarityPair> {
+    let mut ranked: Vec<RankedPair> = pairs.into_iter().map(|pair| RankedPair::new(pair, tie_seed)).collect();
+    ranked.sort_by_key(|&ranked| Reverse(ranked));
+    ranked.into_iter().take(count).map(|ranked| ranked.pair).collect()
+}
+
+/// Compare the pairs of both computations, regardless of their order.
+pub fn compare_pairs(naive: &[SimilarityPair], optimized: &[SimilarityPair]) -> Vec<Divergence> {
+    let sorted = |pairs: &[SimilarityPair]| {
+        let mut pairs = pairs.to_vec();
+        pairs.sort_by(|a, b| {
+            (a.index_a(), a.index_b())
+                .cmp(&(b.index_a(), b.index_b()))
+                .then(a.similarity.total_cmp(&b.similarity))
+        });
+        pairs
+    };
+    let (naive, optimized) = (sorted(naive), sorted(optimized));
+
+    let mut divergences = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < naive.len() || j < optimized.len() {
+        let key = |pair: &SimilarityPair| (pair.index_a(), pair.index_b());
+        let (naive_pair, optimized_pair) = (naive.get(i), optimized.get(j));
+        let order = match (naive_pair, optimized_pair) {
+            (Some(a), Some(b)) => key(a).cmp(&key(b)),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            _ => std::cmp::Ordering::Greater,
+        };
+        let (naive_pair, optimized_pair) = match order {
+            std::cmp::Ordering::Less => (naive_pair, None),
+            std::cmp::Ordering::Greater => (None, optimized_pair),
+            std::cmp::Ordering::Equal => (naive_pair, optimized_pair),
+        };
+        i += usize::from(naive_pair.is_some());
+        j += usize::from(optimized_pair.is_some());
+        if naive_pair != optimized_pair {
+            let pair = naive_pair.or(optimized_pair).unwrap();
+            divergences.push(Divergence {
+                index_a: pair.index_a(),
+                index_b: pair.index_b(),
+                naive: naive_pair.map(|pair| pair.similarity),
+                optimized: optimized_pair.map(|pair| pair.similarity),
+            });
+        }
+    }
+    divergences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{calculate_similarity_matrix, calculate_top_pairs, collect_pairs};
+
+    fn words() -> Vec<Word> {
+        ["hello", "hallo", "help", "hello", "world", "word", "held"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn optimized_pairs_match_naive_pairs() {
+        let words = words();
+        let match_range = 0.5..=f64::INFINITY;
+        let scorer = Scorer {
+            max_edits: Some(2),
+            ..Scorer::default()
+        };
+        for tie_seed in [None, Some(7)] {
+            let filter = ComparisonFilter {
+                tie_seed,
+                ..ComparisonFilter::default()
+            };
+            let matrix = calculate_similarity_matrix(&words, &scorer, &filter, None);
+            let optimized = collect_pairs(&matrix, &match_range);
+            let naive = naive_pairs(&words, &scorer, &filter, &match_range, None, None);
+            assert_eq!(compare_pairs(&naive, &optimized), []);
+
+            for (top_k, limit) in [(Some(1), None), (Some(2), Some(3)), (None, Some(4))] {
+                let optimized = calculate_top_pairs(&words, &scorer, &filter, &match_range, top_k, limit, None);
+                let naive = naive_pairs(&words, &scorer, &filter, &match_range, top_k, limit);
+                assert_eq!(naive, optimized);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_missing_extra_and_changed_pairs() {
+        let naive = [SimilarityPair::new(0.9, 0, 1), SimilarityPair::new(0.8, 0, 2)];
+        let optimized = [SimilarityPair::new(0.8, 0, 2), SimilarityPair::new(0.7, 1, 2)];
+        let divergences = compare_pairs(&naive, &optimized);
+        let summary: Vec<_> = divergences
+            .iter()
+            .map(|divergence| (divergence.index_a, divergence.index_b, divergence.naive, divergence.optimized))
+            .collect();
+        assert_eq!(summary, [(0, 1, Some(0.9), None), (1, 2, None, Some(0.7))]);
+
+        let changed = compare_pairs(&naive[..1], &[SimilarityPair::new(0.85, 0, 1)]);
+        assert_eq!(changed[0].naive, Some(0.9));
+        assert_eq!(changed[0].optimized, Some(0.85));
+    }
+}
@@ -0,0 +1,285 @@
+//! Re-verification of the similarities reported in an existing output file.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Scorer, Word};
+
+/// A pair as reported on one line of a text output file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReportedPair {
+    /// Number of the line in the output file, starting at 1.
+    pub line: usize,
+    pub index_a: usize,
+    pub index_b: usize,
+    pub similarity: f64,
+    /// Largest difference from the recomputed similarity explained by the output precision.
+    pub tolerance: f64,
+}
+
+/// A reported pair whose similarity does not match the recomputed one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Discrepancy {
+    pub pair: ReportedPair,
+    pub recomputed: f64,
+}
+
+/// The outcome of verifying a sample of the pairs in an output file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Number of pairs in the output file.
+    pub total: usize,
+    /// Number of pairs that were recomputed.
+    pub checked: usize,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// Parse the pairs of a text output file written without external IDs.
+///
+/// The line numbers of the words in the input file are needed for row labels with a line,
+/// or `None` if every word is on the line of its row.
+pub fn read_reported_pairs(
+    path: &Path,
+    words: &[Word],
+    lines: Option<&[usize]>,
+) -> Result<Vec<ReportedPair>, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let mut pairs = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let pair = parse_output_line(&line, index + 1, words, lines)
+            .map_err(|e| format!("Line {}: {}", index + 1, e))?;
+        pairs.push(pair);
+    }
+    Ok(pairs)
+}
+
+/// Parse one line of the text output, in either the tab-separated or the aligned form.
+///
+/// The word columns are matched against the input words by their row number, so words
+/// containing spaces or tabs are parsed correctly. The `lines` are those of
+/// `read_reported_pairs`.
+pub fn parse_output_line(
+    line: &str,
+    line_number: usize,
+    words: &[Word],
+    lines: Option<&[usize]>,
+) -> Result<ReportedPair, String> {
+    let (index_a, rest) = parse_word_column(line, words, lines)?;
+    let (index_b, rest) = parse_word_column(rest.trim_start(), words, lines)?;
+    let rest = rest
+        .trim_start()
+        .strip_prefix("Similarity:")
+        .ok_or("Missing similarity column")?;
+    let value = rest.split_whitespace().next().ok_or("Missing similarity value")?;
+
+    let (similarity, tolerance) = if let Some(percentage) = value.strip_suffix('%') {
+        (parse_number(percentage)? / 100.0, 0.00005)
+    } else if let Some(permille) = value.strip_suffix('‰') {
+        (parse_number(permille)? / 1000.0, 0.0005)
+    } else {
+        return Err(format!("Invalid similarity: {}", value));
+    };
+
+    Ok(ReportedPair {
+        line: line_number,
+        index_a,
+        index_b,
+        similarity,
+        tolerance,
+    })
+}
+
+/// Parse a `Row N: word` or `Row N (line M): word` column at the start of the text, returning
+/// the word index and the rest.
+///
+/// With a line, the word must be on that line of the input file. The rows of the output shift
+/// when words were dropped or only a range of lines was read, so if the word of the row is on
+/// another line, the word on the line is used instead.
+fn parse_word_column<'a>(
+    text: &'a str,
+    words: &[Word],
+    lines: Option<&[usize]>,
+) -> Result<(usize, &'a str), String> {
+    let rest = text
+        .strip_prefix("Row ")
+        .ok_or("Expected a 'Row N' label, files written with --ids-file cannot be verified")?;
+    let (label, rest) = rest.split_once(": ").ok_or("Missing word after the row label")?;
+    let (number, line) = match label.split_once(" (line ") {
+        Some((number, line)) => match line.strip_suffix(')').map(str::parse::<usize>) {
+            Some(Ok(line)) => (number, Some(line)),
+            _ => return Err(format!("Invalid line number: {}", line)),
+        },
+        None => (label, None),
+    };
+    let row = match number.parse::<usize>() {
+        Ok(row) if row >= 1 => row,
+        _ => return Err(format!("Invalid row number: {}", number)),
+    };
+    let line_of = |index: usize| lines.map_or(index + 1, |lines| lines[index]);
+    let index = match line {
+        None if row <= words.len() => row - 1,
+        None => return Err(format!("Invalid row number: {}", number)),
+        Some(line) if row <= words.len() && line_of(row - 1) == line => row - 1,
+        Some(line) => {
+            // The lines only increase, and a line holds several words with a token regex
+            let on_line = match lines {
+                Some(lines) => lines.partition_point(|&l| l < line)..lines.partition_point(|&l| l <= line),
+                None => line.saturating_sub(1).min(words.len())..line.min(words.len()),
+            };
+            on_line
+                .into_iter()
+                .find(|&index| rest.starts_with(words[index].original.as_str()))
+                .ok_or_else(|| format!("No word on line {} of the input file matches row {}", line, number))?
+        }
+    };
+    let original = &words[index].original;
+    let rest = rest
+        .strip_prefix(original.as_str())
+        .ok_or_else(|| format!("Row {} is not '{}' in the input file", number, original))?;
+    Ok((index, rest))
+}
+
+fn parse_number(text: &str) -> Result<f64, String> {
+    text.parse::<f64>().map_err(|_| format!("Invalid similarity: {}", text))
+}
+
+/// Recompute the similarities of a random sample of the reported pairs.
+///
+/// A pair is a discrepancy if its reported similarity differs from the recomputed one by
+/// more than the rounding of the output format explains.
+pub fn verify_sample(
+    pairs: &[ReportedPair],
+    words: &[Word],
+    scorer: &Scorer,
+    sample_size: usize,
+) -> VerifyReport {
+    let mut indices: Vec<usize> = (0..pairs.len()).collect();
+    let sample_size = sample_size.min(indices.len());
+
+    // Partial Fisher-Yates shuffle, the first sample_size indices are the sample
+    let mut random = SplitMix64::from_time();
+    for i in 0..sample_size {
+        let j = i + (random.next() % (indices.len() - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(sample_size);
+    indices.sort_unstable();
+
+    let discrepancies = indices
+        .into_iter()
+        .filter_map(|index| {
+            let pair = pairs[index];
+            let recomputed = scorer.similarity(
+                words[pair.index_a].processed(),
+                words[pair.index_b].processed(),
+            );
+            // Allow for the float error of parsing the rounded value back
+            let matches = (pair.similarity - recomputed).abs() <= pair.tolerance + 1e-9;
+            (!matches).then_some(Discrepancy { pair, recomputed })
+        })
+        .collect();
+
+    VerifyReport {
+        total: pairs.len(),
+        checked: sample_size,
+        discrepancies,
+    }
+}
+
+/// A small SplitMix64 generator, good enough to pick samples.
+pub(crate) struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub(crate) fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or(0);
+        SplitMix64(nanos)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words() -> Vec<Word> {
+        ["hello", "hallo", "new york"]
+            .iter()
+            .map(|word| Word::unprocessed(word.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_tab_separated_line() {
+        let pair = parse_output_line("Row 1: hello\tRow 2: hallo\tSimilarity: 80.00%", 1, &words(), None).unwrap();
+        assert_eq!((pair.index_a, pair.index_b), (0, 1));
+        assert!((pair.similarity - 0.8).abs() < 1e-12);
+    }
+
+    #[test]
+    fn parses_aligned_line_with_spaces_and_extra_scores() {
+        let line = "Row 3: new york  Row 1: hello  Similarity:    750‰  jaro: 600‰";
+        let pair = parse_output_line(line, 4, &words(), None).unwrap();
+        assert_eq!((pair.line, pair.index_a, pair.index_b), (4, 2, 0));
+        assert!((pair.similarity - 0.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rejects_mismatched_words_and_labels() {
+        let words = words();
+        assert!(parse_output_line("Row 1: hallo\tRow 2: hallo\tSimilarity: 80.00%", 1, &words, None).is_err());
+        assert!(parse_output_line("Row 9: hello\tRow 2: hallo\tSimilarity: 80.00%", 1, &words, None).is_err());
+        assert!(parse_output_line("a: hello\tb: hallo\tSimilarity: 80.00%", 1, &words, None).is_err());
+    }
+
+    #[test]
+    fn parses_row_labels_with_lines() {
+        // Line 2 of the input was dropped, so the rows after it are one line ahead
+        let words = words();
+        let lines = [1, 3, 4];
+        let line = "Row 1 (line 1): hello\tRow 2 (line 3): hallo\tSimilarity: 80.00%";
+        let pair = parse_output_line(line, 1, &words, Some(&lines)).unwrap();
+        assert_eq!((pair.index_a, pair.index_b), (0, 1));
+
+        // Read again without dropping, the word is found on its line instead of in its row
+        let all = ["hello", "!!!", "hallo", "new york"].map(|word| Word::unprocessed(word.to_string()));
+        let pair = parse_output_line(line, 1, &all, None).unwrap();
+        assert_eq!((pair.index_a, pair.index_b), (0, 2));
+
+        assert!(parse_output_line("Row 1 (line 2): hello\tRow 2: hallo\tSimilarity: 80.00%", 1, &words, Some(&lines)).is_err());
+        assert!(parse_output_line("Row 1 (line x): hello\tRow 2: hallo\tSimilarity: 80.00%", 1, &words, None).is_err());
+    }
+
+    #[test]
+    fn finds_discrepancies() {
+        let words = words();
+        let lines = [
+            "Row 1: hello\tRow 2: hallo\tSimilarity: 80.00%",
+            "Row 1: hello\tRow 3: new york\tSimilarity: 99.00%",
+        ];
+        let pairs: Vec<_> = lines
+            .iter()
+            .enumerate()
+            .map(|(index, line)| parse_output_line(line, index + 1, &words, None).unwrap())
+            .collect();
+        let report = verify_sample(&pairs, &words, &Scorer::default(), 10);
+        assert_eq!((report.total, report.checked), (2, 2));
+        assert_eq!(report.discrepancies.len(), 1);
+        assert_eq!(report.discrepancies[0].pair.line, 2);
+    }
+}